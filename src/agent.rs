@@ -2,188 +2,835 @@
 // Agent Trait and Platform-Specific Implementations
 // ============================================================================
 
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde_json::Value;
+
 use crate::models::*;
-use crate::osquery::query_to_struct;
+use crate::osquery::{
+    parse_rows, table_from_query, OsqueryCapabilities, OsqueryError, OsqueryiBackend, QueryBackend,
+};
 
 /// Trait common to all supported operating systems
-pub trait Agent {
+pub trait Agent: Send + Sync {
     /// Gather system info using OSquery
-    fn collect_system_info(&self) -> SystemInfo;
+    fn collect_system_info(&self) -> SystemInfo {
+        self.collect_with(&CollectOptions::default())
+    }
+
+    /// Gather system info using OSquery with explicit collection options
+    fn collect_with(&self, options: &CollectOptions) -> SystemInfo;
+
+    /// osquery version and tables, probed once; `None` while the probe keeps failing
+    fn capabilities(&self) -> Option<OsqueryCapabilities> {
+        None
+    }
+
+    /// Runs a query that is not part of the collection plan, e.g. one scheduled
+    /// by a remote server, through the same backend
+    fn query(&self, sql: &str) -> Result<Vec<Value>, OsqueryError>;
+}
+
+/// Knobs for a single collection
+#[derive(Debug, Clone)]
+pub struct CollectOptions {
+    /// Maximum number of queries running at once; 1 runs them one after another
+    pub workers: usize,
+    /// Hand every query to the backend at once (one `osqueryi` session for the
+    /// whole snapshot). Takes precedence over `workers`; section timings then
+    /// cover the whole batch.
+    pub batch: bool,
+    /// Sections to collect; `None` collects all of them. Left-out sections
+    /// are reported as `SectionState::Disabled`.
+    pub sections: Option<BTreeSet<Section>>,
+}
+
+impl CollectOptions {
+    pub fn collects(&self, section: Section) -> bool {
+        self.sections.as_ref().is_none_or(|sections| sections.contains(&section))
+    }
+}
+
+impl Default for CollectOptions {
+    fn default() -> Self {
+        CollectOptions {
+            workers: 1,
+            batch: false,
+            sections: None,
+        }
+    }
+}
+
+/// One section of `SystemInfo` and the queries whose rows fill it
+struct SectionPlan {
+    section: Section,
+    queries: &'static [&'static str],
+}
+
+/// Query to run instead of the plan query `replaces` when osquery lacks its table.
+/// Keyed by query, not table, so a table feeding several sections is only
+/// replaced where the substitute's rows fit the section.
+struct Substitute {
+    replaces: &'static str,
+    query: &'static str,
+}
+
+/// Runs an agent's queries, remembering tables osquery reported as missing so
+/// they are not queried (and logged as errors) again every cycle
+struct Collector {
+    backend: Box<dyn QueryBackend>,
+    plan: &'static [SectionPlan],
+    substitutes: &'static [Substitute],
+    unsupported: Mutex<HashSet<String>>,
+    // Filled by the first successful probe
+    capabilities: Mutex<Option<OsqueryCapabilities>>,
+    substituted: Mutex<HashMap<&'static str, &'static str>>,
+}
+
+impl Collector {
+    fn new(
+        backend: Box<dyn QueryBackend>,
+        plan: &'static [SectionPlan],
+        substitutes: &'static [Substitute],
+    ) -> Self {
+        Collector {
+            backend,
+            plan,
+            substitutes,
+            unsupported: Mutex::new(HashSet::new()),
+            capabilities: Mutex::new(None),
+            substituted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Probes osquery on first use and caches the result. Plan tables that are
+    /// missing are substituted or marked unsupported up front, with one log line.
+    /// A failed probe is retried on the next call; until then every table is queried.
+    fn capabilities(&self) -> Option<OsqueryCapabilities> {
+        let mut cached = self.capabilities.lock().unwrap();
+        if cached.is_none() {
+            match OsqueryCapabilities::probe(self.backend.as_ref()) {
+                Ok(capabilities) => {
+                    self.apply_capabilities(&capabilities);
+                    *cached = Some(capabilities);
+                }
+                Err(e) => log::warn!("Could not probe osquery tables, querying all of them: {}", e),
+            }
+        }
+        cached.clone()
+    }
+
+    fn apply_capabilities(&self, capabilities: &OsqueryCapabilities) {
+        let mut skipped = Vec::new();
+        let mut replaced = Vec::new();
+        let mut unsupported = self.unsupported.lock().unwrap();
+        let mut substituted = self.substituted.lock().unwrap();
+        for query in self.plan.iter().flat_map(|entry| entry.queries.iter().copied()) {
+            let Some(table) = table_from_query(query) else { continue };
+            if capabilities.has_table(&table) {
+                continue;
+            }
+            let substitute = self.substitutes.iter().find(|s| {
+                s.replaces == query && table_from_query(s.query).is_some_and(|t| capabilities.has_table(&t))
+            });
+            match substitute {
+                Some(substitute) => {
+                    replaced.push(format!(
+                        "{} -> {}",
+                        table,
+                        table_from_query(substitute.query).unwrap_or_default()
+                    ));
+                    substituted.insert(query, substitute.query);
+                }
+                None => {
+                    skipped.push(table.clone());
+                    unsupported.insert(table);
+                }
+            }
+        }
+
+        if !skipped.is_empty() || !replaced.is_empty() {
+            let mut parts = Vec::new();
+            if !skipped.is_empty() {
+                parts.push(format!("skipping {}", skipped.join(", ")));
+            }
+            if !replaced.is_empty() {
+                parts.push(format!("substituting {}", replaced.join(", ")));
+            }
+            log::info!(
+                "osquery {} lacks {} table(s): {}",
+                capabilities.version.as_deref().unwrap_or("(unknown version)"),
+                skipped.len() + replaced.len(),
+                parts.join("; ")
+            );
+        }
+    }
+
+    /// Runs every section of the plan and records a status for each. Queries may
+    /// run concurrently, but sections are assembled in plan order so the result
+    /// is the same as a sequential run.
+    fn collect(&self, options: &CollectOptions) -> SystemInfo {
+        let plan: Vec<&SectionPlan> = self.plan.iter().filter(|entry| options.collects(entry.section)).collect();
+        self.capabilities();
+        let queries: Vec<&str> = {
+            let substituted = self.substituted.lock().unwrap();
+            plan.iter()
+                .flat_map(|entry| entry.queries.iter())
+                .map(|query| substituted.get(query).copied().unwrap_or(query))
+                .collect()
+        };
+        let runs = if options.batch {
+            self.run_batch(&queries)
+        } else {
+            self.run_all(&queries, options.workers)
+        };
+        let mut runs = queries.iter().zip(runs);
+
+        let mut info = SystemInfo::default();
+        for section in Section::ALL.into_iter().filter(|section| !options.collects(*section)) {
+            let status = SectionStatus {
+                state: SectionState::Disabled,
+                ..Default::default()
+            };
+            info.collection_status.insert(section.name().to_string(), status);
+        }
+        for entry in plan {
+            let (queries, runs): (Vec<&str>, Vec<QueryRun>) =
+                runs.by_ref().take(entry.queries.len()).unzip();
+            // Wall-clock span of the section's queries, which may have overlapped
+            let duration = match (
+                runs.iter().map(|r| r.started).min(),
+                runs.iter().map(|r| r.finished).max(),
+            ) {
+                (Some(started), Some(finished)) => finished - started,
+                _ => Default::default(),
+            };
+            let outcomes = queries
+                .into_iter()
+                .zip(runs)
+                .map(|(query, run)| (query, run.result))
+                .collect();
+            let mut status = fill_section(&mut info, entry.section, outcomes);
+            status.duration_ms = duration.as_millis() as u64;
+            info.collection_status.insert(entry.section.name().to_string(), status);
+        }
+        info
+    }
+
+    /// Runs `queries` on up to `workers` threads, returning results in query order
+    fn run_all(&self, queries: &[&str], workers: usize) -> Vec<QueryRun> {
+        let workers = workers.clamp(1, queries.len().max(1));
+        if workers == 1 {
+            return queries.iter().map(|query| self.run(query)).collect();
+        }
+
+        let next = AtomicUsize::new(0);
+        let slots: Vec<Mutex<Option<QueryRun>>> = queries.iter().map(|_| Mutex::new(None)).collect();
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= queries.len() {
+                        break;
+                    }
+                    let run = self.run(queries[i]);
+                    *slots[i].lock().unwrap() = Some(run);
+                });
+            }
+        });
+        slots
+            .into_iter()
+            .map(|slot| slot.into_inner().unwrap().expect("every query slot is filled"))
+            .collect()
+    }
+
+    /// Runs `queries` as a single backend batch, skipping known-unsupported tables
+    fn run_batch(&self, queries: &[&str]) -> Vec<QueryRun> {
+        let started = Instant::now();
+        let mut results: Vec<Option<Result<Vec<Value>, OsqueryError>>> =
+            queries.iter().map(|query| self.known_unsupported(query).map(Err)).collect();
+
+        let pending: Vec<&str> = queries
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.is_none())
+            .map(|(query, _)| *query)
+            .collect();
+        let mut batch = self.backend.execute_batch(&pending).into_iter();
+        for (query, slot) in queries.iter().zip(results.iter_mut()) {
+            if slot.is_none() {
+                let result = batch.next().unwrap_or_else(|| {
+                    Err(OsqueryError::Transport {
+                        query: query.to_string(),
+                        message: "backend returned fewer batch results than queries".to_string(),
+                    })
+                });
+                self.note_result(query, &result);
+                *slot = Some(result);
+            }
+        }
+
+        let finished = Instant::now();
+        results
+            .into_iter()
+            .map(|result| QueryRun {
+                result: result.expect("every batch slot is filled"),
+                started,
+                finished,
+            })
+            .collect()
+    }
+
+    fn run(&self, query: &str) -> QueryRun {
+        let started = Instant::now();
+        let result = self.execute(query);
+        QueryRun {
+            result,
+            started,
+            finished: Instant::now(),
+        }
+    }
+
+    fn execute(&self, query: &str) -> Result<Vec<Value>, OsqueryError> {
+        if let Some(error) = self.known_unsupported(query) {
+            return Err(error);
+        }
+
+        let result = self.backend.execute(query);
+        self.note_result(query, &result);
+        result
+    }
+
+    /// An `UnsupportedTable` error if the query reads a table already reported missing
+    fn known_unsupported(&self, query: &str) -> Option<OsqueryError> {
+        let table = table_from_query(query)?;
+        if !self.unsupported.lock().unwrap().contains(&table) {
+            return None;
+        }
+        Some(OsqueryError::UnsupportedTable {
+            query: query.to_string(),
+            table,
+            stderr: String::new(),
+        })
+    }
+
+    /// Logs a failed query and remembers tables that don't exist on this host
+    fn note_result(&self, query: &str, result: &Result<Vec<Value>, OsqueryError>) {
+        if let Err(e) = result {
+            log::warn!("Error executing query '{}': {}", query, e);
+            if let Some(table) = e.unsupported_table() {
+                log::info!("Table '{}' is not available on this host; skipping it from now on", table);
+                self.unsupported.lock().unwrap().insert(table.to_string());
+            }
+        }
+    }
+}
+
+struct QueryRun {
+    result: Result<Vec<Value>, OsqueryError>,
+    started: Instant,
+    finished: Instant,
+}
+
+/// Deserializes the rows of one section into `info` and works out its status
+fn fill_section(
+    info: &mut SystemInfo,
+    section: Section,
+    outcomes: Vec<(&str, Result<Vec<Value>, OsqueryError>)>,
+) -> SectionStatus {
+    let mut status = SectionStatus::default();
+    let mut errors = Vec::new();
+    let mut outputs = Vec::new();
+    for (query, result) in outcomes {
+        match result {
+            Ok(rows) => outputs.push((query, rows)),
+            Err(OsqueryError::UnsupportedTable { table, .. }) => status.unsupported_tables.push(table),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    let answered = outputs.len() + errors.len();
+
+    let skipped = &mut status.rows_skipped;
+    status.rows = match section {
+        Section::OsVersion => {
+            info.os_version = parse_all(outputs, skipped).into_iter().next();
+            info.os_version.is_some() as usize
+        }
+        Section::SystemInfo => {
+            info.system_info = parse_all(outputs, skipped).into_iter().next();
+            info.system_info.is_some() as usize
+        }
+        Section::Processes => {
+            info.processes = parse_all(outputs, skipped);
+            info.processes.len()
+        }
+        Section::NetworkConnections => {
+            info.network_connections = parse_all(outputs, skipped);
+            info.network_connections.len()
+        }
+        Section::ListeningPorts => {
+            info.listening_ports = parse_all(outputs, skipped);
+            info.listening_ports.len()
+        }
+        Section::Users => {
+            info.users = parse_all(outputs, skipped);
+            info.users.len()
+        }
+        Section::Services => {
+            info.services = parse_services(outputs, skipped);
+            info.services.len()
+        }
+        Section::ScheduledTasks => {
+            info.scheduled_tasks = parse_scheduled_tasks(outputs, skipped);
+            info.scheduled_tasks.len()
+        }
+        Section::InstalledPackages => {
+            let distro = info.os_version.as_ref().and_then(|os| os.platform.clone());
+            info.installed_packages = parse_packages(outputs, distro.as_deref(), skipped);
+            info.installed_packages.len()
+        }
+        Section::InterfaceAddresses => {
+            info.interface_addresses = parse_all(outputs, skipped);
+            info.interface_addresses.len()
+        }
+    };
+
+    status.state = if !errors.is_empty() {
+        status.error = Some(errors.join("; "));
+        SectionState::Failed
+    } else if answered == 0 {
+        SectionState::Unsupported
+    } else if status.rows == 0 && status.rows_skipped > 0 {
+        status.error = Some(format!(
+            "all {} rows failed to deserialize",
+            status.rows_skipped
+        ));
+        SectionState::Failed
+    } else if status.rows == 0 {
+        SectionState::Empty
+    } else {
+        SectionState::Ok
+    };
+    status
 }
 
+fn parse_all<T>(outputs: Vec<(&str, Vec<Value>)>, skipped: &mut usize) -> Vec<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let mut parsed = Vec::new();
+    for (query, rows) in outputs {
+        let (rows, dropped) = parse_rows(query, rows);
+        parsed.extend(rows);
+        *skipped += dropped;
+    }
+    parsed
+}
+
+fn parse_as<T, U>(outputs: Vec<(&str, Vec<Value>)>, skipped: &mut usize, wrap: fn(T) -> U) -> Vec<U>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    parse_all(outputs, skipped).into_iter().map(wrap).collect()
+}
+
+/// Parses service rows with the native model of the table they came from
+fn parse_services(outputs: Vec<(&str, Vec<Value>)>, skipped: &mut usize) -> Vec<ServiceInfo> {
+    let mut services = Vec::new();
+    for (query, rows) in outputs {
+        let output = vec![(query, rows)];
+        let details: Vec<ServiceDetails> = match table_from_query(query).as_deref() {
+            Some("systemd_units") => parse_as(output, skipped, ServiceDetails::Systemd),
+            Some("launchd") => parse_as(output, skipped, ServiceDetails::Launchd),
+            Some("startup_items") => parse_as(output, skipped, ServiceDetails::StartupItem),
+            _ => parse_as(output, skipped, ServiceDetails::Windows),
+        };
+        services.extend(details.into_iter().map(ServiceInfo::from));
+    }
+    services
+}
+
+/// Parses package rows with the native model of the package manager they came from
+fn parse_packages(
+    outputs: Vec<(&str, Vec<Value>)>,
+    distro: Option<&str>,
+    skipped: &mut usize,
+) -> Vec<PackageInfo> {
+    let mut packages = Vec::new();
+    for (query, rows) in outputs {
+        let output = vec![(query, rows)];
+        let details: Vec<PackageDetails> = match table_from_query(query).as_deref() {
+            Some("rpm_packages") => parse_as(output, skipped, PackageDetails::Rpm),
+            Some("deb_packages") => parse_as(output, skipped, PackageDetails::Deb),
+            Some("portage_packages") => parse_as(output, skipped, PackageDetails::Portage),
+            Some("pkg_packages") => parse_as(output, skipped, PackageDetails::Pkg),
+            Some("homebrew_packages") => parse_as(output, skipped, PackageDetails::Homebrew),
+            Some("macports_packages") => parse_as(output, skipped, PackageDetails::Macports),
+            _ => parse_as(output, skipped, PackageDetails::Programs),
+        };
+        packages.extend(details.into_iter().map(|details| PackageInfo::new(details, distro)));
+    }
+    packages
+}
+
+/// Parses scheduled-job rows with the native model of the table they came from
+fn parse_scheduled_tasks(outputs: Vec<(&str, Vec<Value>)>, skipped: &mut usize) -> Vec<ScheduledTask> {
+    let mut tasks = Vec::new();
+    for (query, rows) in outputs {
+        let output = vec![(query, rows)];
+        let details: Vec<ScheduledTaskDetails> = match table_from_query(query).as_deref() {
+            Some("crontab") => parse_as(output, skipped, ScheduledTaskDetails::Cron),
+            Some("systemd_units") => parse_as(output, skipped, ScheduledTaskDetails::SystemdTimer),
+            Some("launchd") => parse_launchd_schedules(output, skipped)
+                .into_iter()
+                .map(ScheduledTaskDetails::Launchd)
+                .collect(),
+            _ => parse_as(output, skipped, ScheduledTaskDetails::Windows),
+        };
+        tasks.extend(details.into_iter().map(ScheduledTask::from));
+    }
+    tasks
+}
+
+/// One row of `LAUNCHD_SCHEDULES_QUERY`: a job plus at most one
+/// `StartCalendarInterval` entry
+#[derive(Deserialize)]
+struct LaunchdScheduleRow {
+    #[serde(flatten)]
+    job: LaunchdSchedule,
+    #[serde(default)]
+    calendar_key: Option<String>,
+    #[serde(default)]
+    calendar_value: Option<String>,
+}
+
+/// Folds the per-entry join rows back into one schedule per job
+fn parse_launchd_schedules(outputs: Vec<(&str, Vec<Value>)>, skipped: &mut usize) -> Vec<LaunchdSchedule> {
+    let mut jobs: Vec<LaunchdSchedule> = Vec::new();
+    for row in parse_all::<LaunchdScheduleRow>(outputs, skipped) {
+        let index = match jobs.iter().position(|job| job.path == row.job.path) {
+            Some(index) => index,
+            None => {
+                jobs.push(row.job);
+                jobs.len() - 1
+            }
+        };
+        if let (Some(key), Some(value)) = (row.calendar_key, row.calendar_value) {
+            if !key.is_empty() {
+                jobs[index].start_calendar_interval.insert(key, value);
+            }
+        }
+    }
+    jobs
+}
+
+// All three implementations are compiled on every host so recorded fixtures
+// for any platform can be replayed through them; only `get_agent()` picks
+// by `target_os`.
+
 /// Windows implementation
-#[cfg(target_os = "windows")]
-pub struct WindowsAgent {}
+pub struct WindowsAgent {
+    collector: Collector,
+}
 
-#[cfg(target_os = "windows")]
-impl Agent for WindowsAgent {
-    fn collect_system_info(&self) -> SystemInfo {
-        SystemInfo {
-            os_version: query_to_struct::<OsVersion>("SELECT * FROM os_version;")
-                .ok()
-                .and_then(|v| v.into_iter().next()),
-            
-            system_info: query_to_struct::<SystemDetails>("SELECT * FROM system_info;")
-                .ok()
-                .and_then(|v| v.into_iter().next()),
-            
-            processes: query_to_struct::<ProcessInfo>("SELECT * FROM processes;")
-                .unwrap_or_default(),
-            
-            network_connections: query_to_struct::<NetworkConnection>(
-                "SELECT * FROM process_open_sockets;"
-            ).unwrap_or_default(),
-            
-            listening_ports: query_to_struct::<ListeningPort>(
-                "SELECT * FROM listening_ports;"
-            ).unwrap_or_default(),
-            
-            users: query_to_struct::<UserInfo>(
-                "SELECT * FROM users;"
-            ).unwrap_or_default(),
-            
-            services: query_to_struct::<ServiceInfo>(
-                "SELECT * FROM services;"
-            ).unwrap_or_default(),
-            
-            scheduled_tasks: query_to_struct::<ScheduledTask>(
-                "SELECT * FROM scheduled_tasks;"
-            ).unwrap_or_default(),
-            
-            installed_packages: query_to_struct::<PackageInfo>(
-                "SELECT * FROM programs;"
-            ).unwrap_or_default(),
-            
-            interface_addresses: query_to_struct::<InterfaceAddress>(
-                "SELECT * FROM interface_addresses;"
-            ).unwrap_or_default(),
+impl WindowsAgent {
+    pub fn new(backend: Box<dyn QueryBackend>) -> Self {
+        WindowsAgent {
+            collector: Collector::new(backend, WINDOWS_PLAN, &[]),
         }
     }
 }
 
+const WINDOWS_PLAN: &[SectionPlan] = &[
+    SectionPlan { section: Section::OsVersion, queries: &["SELECT * FROM os_version;"] },
+    SectionPlan { section: Section::SystemInfo, queries: &["SELECT * FROM system_info;"] },
+    SectionPlan { section: Section::Processes, queries: &["SELECT * FROM processes;"] },
+    SectionPlan { section: Section::NetworkConnections, queries: &["SELECT * FROM process_open_sockets;"] },
+    SectionPlan { section: Section::ListeningPorts, queries: &["SELECT * FROM listening_ports;"] },
+    SectionPlan { section: Section::Users, queries: &["SELECT * FROM users;"] },
+    SectionPlan { section: Section::Services, queries: &["SELECT * FROM services;"] },
+    SectionPlan { section: Section::ScheduledTasks, queries: &["SELECT * FROM scheduled_tasks;"] },
+    SectionPlan { section: Section::InstalledPackages, queries: &["SELECT * FROM programs;"] },
+    SectionPlan { section: Section::InterfaceAddresses, queries: &["SELECT * FROM interface_addresses;"] },
+];
+
+impl Agent for WindowsAgent {
+    fn collect_with(&self, options: &CollectOptions) -> SystemInfo {
+        self.collector.collect(options)
+    }
+
+    fn capabilities(&self) -> Option<OsqueryCapabilities> {
+        self.collector.capabilities()
+    }
+
+    fn query(&self, sql: &str) -> Result<Vec<Value>, OsqueryError> {
+        self.collector.execute(sql)
+    }
+}
+
 /// Linux implementation
-#[cfg(target_os = "linux")]
-pub struct LinuxAgent {}
+pub struct LinuxAgent {
+    collector: Collector,
+}
 
-#[cfg(target_os = "linux")]
-impl Agent for LinuxAgent {
-    fn collect_system_info(&self) -> SystemInfo {
-        SystemInfo {
-            os_version: query_to_struct::<OsVersion>("SELECT * FROM os_version;")
-                .ok()
-                .and_then(|v| v.into_iter().next()),
-            
-            system_info: query_to_struct::<SystemDetails>("SELECT * FROM system_info;")
-                .ok()
-                .and_then(|v| v.into_iter().next()),
-            
-            processes: query_to_struct::<ProcessInfo>("SELECT * FROM processes;")
-                .unwrap_or_default(),
-            
-            network_connections: query_to_struct::<NetworkConnection>(
-                "SELECT * FROM process_open_sockets;"
-            ).unwrap_or_default(),
-            
-            listening_ports: query_to_struct::<ListeningPort>(
-                "SELECT * FROM listening_ports;"
-            ).unwrap_or_default(),
-            
-            users: query_to_struct::<UserInfo>(
-                "SELECT * FROM users;"
-            ).unwrap_or_default(),
-            
-            services: query_to_struct::<ServiceInfo>(
-                "SELECT * FROM systemd_units;"
-            ).unwrap_or_default(),
-            
-            scheduled_tasks: query_to_struct::<ScheduledTask>(
-                "SELECT * FROM crontab;"
-            ).unwrap_or_default(),
-            
-            installed_packages: {
-                // Try different package managers, collect all results
-                let mut packages = Vec::new();
-                packages.extend(query_to_struct::<PackageInfo>("SELECT * FROM rpm_packages;").unwrap_or_default());
-                packages.extend(query_to_struct::<PackageInfo>("SELECT * FROM deb_packages;").unwrap_or_default());
-                packages.extend(query_to_struct::<PackageInfo>("SELECT * FROM portage_packages;").unwrap_or_default());
-                packages.extend(query_to_struct::<PackageInfo>("SELECT * FROM pkg_packages;").unwrap_or_default());
-                packages
-            },
-            
-            interface_addresses: query_to_struct::<InterfaceAddress>(
-                "SELECT * FROM interface_addresses;"
-            ).unwrap_or_default(),
+impl LinuxAgent {
+    pub fn new(backend: Box<dyn QueryBackend>) -> Self {
+        LinuxAgent {
+            collector: Collector::new(backend, LINUX_PLAN, LINUX_SUBSTITUTES),
         }
     }
 }
 
+const LINUX_SERVICES_QUERY: &str = "SELECT * FROM systemd_units WHERE id LIKE '%.service';";
+
+const LINUX_PLAN: &[SectionPlan] = &[
+    SectionPlan { section: Section::OsVersion, queries: &["SELECT * FROM os_version;"] },
+    SectionPlan { section: Section::SystemInfo, queries: &["SELECT * FROM system_info;"] },
+    SectionPlan { section: Section::Processes, queries: &["SELECT * FROM processes;"] },
+    SectionPlan { section: Section::NetworkConnections, queries: &["SELECT * FROM process_open_sockets;"] },
+    SectionPlan { section: Section::ListeningPorts, queries: &["SELECT * FROM listening_ports;"] },
+    SectionPlan { section: Section::Users, queries: &["SELECT * FROM users;"] },
+    SectionPlan { section: Section::Services, queries: &[LINUX_SERVICES_QUERY] },
+    SectionPlan {
+        section: Section::ScheduledTasks,
+        queries: &["SELECT * FROM crontab;", "SELECT * FROM systemd_units WHERE id LIKE '%.timer';"],
+    },
+    // Try different package managers, collect all results
+    SectionPlan {
+        section: Section::InstalledPackages,
+        queries: &[
+            "SELECT * FROM rpm_packages;",
+            "SELECT * FROM deb_packages;",
+            "SELECT * FROM portage_packages;",
+            "SELECT * FROM pkg_packages;",
+        ],
+    },
+    SectionPlan { section: Section::InterfaceAddresses, queries: &["SELECT * FROM interface_addresses;"] },
+];
+
+// Hosts without systemd still list their init scripts in startup_items. Timers
+// have no such fallback; without systemd_units that query is just skipped.
+const LINUX_SUBSTITUTES: &[Substitute] = &[Substitute {
+    replaces: LINUX_SERVICES_QUERY,
+    query: "SELECT name, path, status FROM startup_items;",
+}];
+
+impl Agent for LinuxAgent {
+    fn collect_with(&self, options: &CollectOptions) -> SystemInfo {
+        self.collector.collect(options)
+    }
+
+    fn capabilities(&self) -> Option<OsqueryCapabilities> {
+        self.collector.capabilities()
+    }
+
+    fn query(&self, sql: &str) -> Result<Vec<Value>, OsqueryError> {
+        self.collector.execute(sql)
+    }
+}
+
 /// MacOS implementation
-#[cfg(target_os = "macos")]
-pub struct MacAgent {}
+pub struct MacAgent {
+    collector: Collector,
+}
+
+impl MacAgent {
+    pub fn new(backend: Box<dyn QueryBackend>) -> Self {
+        MacAgent {
+            collector: Collector::new(backend, MAC_PLAN, &[]),
+        }
+    }
+}
+
+// The launchd table has StartInterval but not StartCalendarInterval, which is
+// read from each job's plist (one row per calendar key)
+const LAUNCHD_SCHEDULES_QUERY: &str = "SELECT launchd.label, launchd.path, launchd.program, \
+    launchd.program_arguments, launchd.username, launchd.disabled, launchd.start_interval, \
+    plist.subkey AS calendar_key, plist.value AS calendar_value \
+    FROM launchd LEFT JOIN plist ON plist.path = launchd.path AND plist.key = 'StartCalendarInterval' \
+    WHERE launchd.start_interval != '' OR plist.key IS NOT NULL;";
+
+const MAC_PLAN: &[SectionPlan] = &[
+    SectionPlan { section: Section::OsVersion, queries: &["SELECT * FROM os_version;"] },
+    SectionPlan { section: Section::SystemInfo, queries: &["SELECT * FROM system_info;"] },
+    SectionPlan { section: Section::Processes, queries: &["SELECT * FROM processes;"] },
+    SectionPlan { section: Section::NetworkConnections, queries: &["SELECT * FROM process_open_sockets;"] },
+    SectionPlan { section: Section::ListeningPorts, queries: &["SELECT * FROM listening_ports;"] },
+    SectionPlan { section: Section::Users, queries: &["SELECT * FROM users;"] },
+    SectionPlan { section: Section::Services, queries: &["SELECT * FROM launchd;"] },
+    SectionPlan {
+        section: Section::ScheduledTasks,
+        queries: &["SELECT * FROM crontab;", LAUNCHD_SCHEDULES_QUERY],
+    },
+    // Try different package managers, collect all results
+    SectionPlan {
+        section: Section::InstalledPackages,
+        queries: &["SELECT * FROM homebrew_packages;", "SELECT * FROM macports_packages;"],
+    },
+    SectionPlan { section: Section::InterfaceAddresses, queries: &["SELECT * FROM interface_addresses;"] },
+];
 
-#[cfg(target_os = "macos")]
 impl Agent for MacAgent {
-    fn collect_system_info(&self) -> SystemInfo {
-        SystemInfo {
-            os_version: query_to_struct::<OsVersion>("SELECT * FROM os_version;")
-                .ok()
-                .and_then(|v| v.into_iter().next()),
-            
-            system_info: query_to_struct::<SystemDetails>("SELECT * FROM system_info;")
-                .ok()
-                .and_then(|v| v.into_iter().next()),
-            
-            processes: query_to_struct::<ProcessInfo>("SELECT * FROM processes;")
-                .unwrap_or_default(),
-            
-            network_connections: query_to_struct::<NetworkConnection>(
-                "SELECT * FROM process_open_sockets;"
-            ).unwrap_or_default(),
-            
-            listening_ports: query_to_struct::<ListeningPort>(
-                "SELECT * FROM listening_ports;"
-            ).unwrap_or_default(),
-            
-            users: query_to_struct::<UserInfo>(
-                "SELECT * FROM users;"
-            ).unwrap_or_default(),
-            
-            services: query_to_struct::<ServiceInfo>(
-                "SELECT * FROM launchd;"
-            ).unwrap_or_default(),
-            
-            scheduled_tasks: query_to_struct::<ScheduledTask>(
-                "SELECT * FROM crontab;"
-            ).unwrap_or_default(),
-            
-            installed_packages: {
-                // Try different package managers, collect all results
-                let mut packages = Vec::new();
-                packages.extend(query_to_struct::<PackageInfo>("SELECT * FROM homebrew_packages;").unwrap_or_default());
-                packages.extend(query_to_struct::<PackageInfo>("SELECT * FROM macports_packages;").unwrap_or_default());
-                packages
-            },
-            
-            interface_addresses: query_to_struct::<InterfaceAddress>(
-                "SELECT * FROM interface_addresses;"
-            ).unwrap_or_default(),
-        }
-    }
-}
-
-/// Returns the correct agent for the platform
-pub fn get_agent() -> Box<dyn Agent> {
-    #[cfg(target_os = "windows")]
-    {
-        Box::new(WindowsAgent {})
+    fn collect_with(&self, options: &CollectOptions) -> SystemInfo {
+        self.collector.collect(options)
     }
-    #[cfg(target_os = "linux")]
-    {
-        Box::new(LinuxAgent {})
+
+    fn capabilities(&self) -> Option<OsqueryCapabilities> {
+        self.collector.capabilities()
     }
-    #[cfg(target_os = "macos")]
-    {
-        Box::new(MacAgent {})
+
+    fn query(&self, sql: &str) -> Result<Vec<Value>, OsqueryError> {
+        self.collector.execute(sql)
     }
 }
 
+/// Operating systems with an agent implementation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Windows,
+    Linux,
+    MacOs,
+}
+
+impl Platform {
+    /// Platform this binary was compiled for
+    pub fn current() -> Platform {
+        #[cfg(target_os = "windows")]
+        {
+            Platform::Windows
+        }
+        #[cfg(target_os = "linux")]
+        {
+            Platform::Linux
+        }
+        #[cfg(target_os = "macos")]
+        {
+            Platform::MacOs
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Windows => "windows",
+            Platform::Linux => "linux",
+            Platform::MacOs => "macos",
+        }
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "windows" => Ok(Platform::Windows),
+            "linux" => Ok(Platform::Linux),
+            "macos" | "darwin" => Ok(Platform::MacOs),
+            other => Err(format!("unknown platform '{}' (expected windows, linux or macos)", other)),
+        }
+    }
+}
+
+/// Returns the agent for an explicit platform, e.g. to replay Windows fixtures on Linux
+pub fn agent_for_platform(platform: Platform, backend: Box<dyn QueryBackend>) -> Box<dyn Agent> {
+    match platform {
+        Platform::Windows => Box::new(WindowsAgent::new(backend)),
+        Platform::Linux => Box::new(LinuxAgent::new(backend)),
+        Platform::MacOs => Box::new(MacAgent::new(backend)),
+    }
+}
+
+/// Returns the correct agent for the platform, backed by `osqueryi`
+pub fn get_agent() -> Box<dyn Agent> {
+    get_agent_with_backend(Box::new(OsqueryiBackend::new()))
+}
+
+/// Returns the correct agent for the platform using the given query backend
+pub fn get_agent_with_backend(backend: Box<dyn QueryBackend>) -> Box<dyn Agent> {
+    agent_for_platform(Platform::current(), backend)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::ReplayBackend;
+    use serde_json::json;
+
+    fn replay(dir: &str) -> ReplayBackend {
+        ReplayBackend::load(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(dir)).unwrap()
+    }
+
+    /// The Linux fixtures on a host without systemd whose users query fails,
+    /// so collection goes through both a substitute and a failed section
+    struct Degraded(ReplayBackend);
+
+    impl QueryBackend for Degraded {
+        fn execute(&self, query: &str) -> Result<Vec<Value>, OsqueryError> {
+            if query.contains("FROM startup_items") {
+                return Ok(vec![json!({"name": "sshd", "path": "/etc/init.d/sshd", "status": "enabled"})]);
+            }
+            if query.contains("FROM users") {
+                return Err(OsqueryError::Transport {
+                    query: query.to_string(),
+                    message: "connection reset".to_string(),
+                });
+            }
+            let mut rows = self.0.execute(query)?;
+            if query.contains("FROM osquery_registry") {
+                rows.retain(|row| row["name"] != "systemd_units");
+            }
+            Ok(rows)
+        }
+    }
+
+    /// Collected data and section states, without the timings that differ between runs
+    fn collect(platform: Platform, backend: Box<dyn QueryBackend>, workers: usize, batch: bool) -> Value {
+        let options = CollectOptions {
+            workers,
+            batch,
+            sections: None,
+        };
+        let info = agent_for_platform(platform, backend).collect_with(&options);
+        let mut value = serde_json::to_value(info).unwrap();
+        for status in value["collection_status"].as_object_mut().unwrap().values_mut() {
+            status.as_object_mut().unwrap().remove("duration_ms");
+        }
+        value
+    }
+
+    #[test]
+    fn parallel_and_batched_collection_match_a_sequential_run() {
+        for (platform, dir) in [(Platform::Linux, "linux"), (Platform::MacOs, "macos"), (Platform::Windows, "windows")] {
+            let sequential = collect(platform, Box::new(replay(dir)), 1, false);
+            assert_eq!(collect(platform, Box::new(replay(dir)), 8, false), sequential, "{}", dir);
+            assert_eq!(collect(platform, Box::new(replay(dir)), 1, true), sequential, "{}", dir);
+        }
+    }
+
+    #[test]
+    fn substituted_and_failed_sections_do_not_depend_on_parallelism() {
+        let sequential = collect(Platform::Linux, Box::new(Degraded(replay("linux"))), 1, false);
+        assert_eq!(sequential["collection_status"]["services"]["state"], "ok");
+        assert_eq!(sequential["collection_status"]["services"]["rows"], 1);
+        assert_eq!(sequential["collection_status"]["users"]["state"], "failed");
+        assert_eq!(sequential["collection_status"]["scheduled_tasks"]["unsupported_tables"], json!(["systemd_units"]));
+
+        for workers in [2, 4, 16] {
+            let parallel = collect(Platform::Linux, Box::new(Degraded(replay("linux"))), workers, false);
+            assert_eq!(parallel, sequential, "{} workers", workers);
+        }
+        let batched = collect(Platform::Linux, Box::new(Degraded(replay("linux"))), 1, true);
+        assert_eq!(batched, sequential);
+    }
+}
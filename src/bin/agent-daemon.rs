@@ -3,54 +3,182 @@
 // Options: cargo run --bin agent-daemon -- --interval 300
 
 use clap::Parser;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::sync::Arc;
 use std::time::Duration;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
-use security_agent::models::SystemInfo;
-use security_agent::agent::{get_agent, Agent};
+use security_agent::agent::{get_agent_with_backend, Agent, CollectOptions};
+use security_agent::config::{DaemonConfig, DifferentialConfig, OutputConfig};
+use security_agent::diff;
+use security_agent::events::EventStream;
+use security_agent::models::SectionState;
+use security_agent::osquery::OsqueryiBackend;
+use security_agent::output::{open_sinks, OutputSink, StdoutSink};
+use security_agent::remote::RemoteClient;
+use security_agent::snapshot::Snapshot;
+use security_agent::state::{AgentState, StateStore};
+
+/// Least time between saves for remote results alone; each save rewrites the
+/// whole state file, last snapshot included
+const REMOTE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Parser, Debug)]
 #[command(name = "security-agent-daemon")]
 #[command(about = "Continuous security monitoring agent daemon")]
 struct Args {
+    /// TOML configuration file, e.g. /etc/security-agent/agent.toml. Options given on
+    /// the command line override environment variables, which override the file.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Validate the configuration, print it and exit
+    #[arg(long)]
+    check_config: bool,
+
     /// Collection interval in seconds (default: 300)
-    #[arg(short, long, default_value = "300")]
-    interval: u64,
+    #[arg(short, long)]
+    interval: Option<u64>,
     
-    /// Log level (trace, debug, info, warn, error)
-    #[arg(short, long, default_value = "info")]
-    log_level: String,
+    /// Log level (trace, debug, info, warn, error; default: info)
+    #[arg(short, long)]
+    log_level: Option<String>,
+
+    /// Query a running osqueryd through its extension socket instead of spawning osqueryi
+    /// (e.g. /var/osquery/osquery.em)
+    #[cfg(unix)]
+    #[arg(long)]
+    extensions_socket: Option<PathBuf>,
+
+    /// Per-query timeout in seconds; a stalled osqueryi is killed (0 disables; default: 60)
+    #[arg(long)]
+    query_timeout: Option<u64>,
+
+    /// Deadline in seconds for a whole collection cycle (0 disables; default: 600)
+    #[arg(long)]
+    cycle_timeout: Option<u64>,
+
+    /// Maximum number of osquery queries to run concurrently (default: 4)
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// Run each cycle's queries in a single osqueryi session instead of one process per query
+    #[arg(long)]
+    batch: bool,
+
+    /// Path to the osqueryi binary (overrides OSQUERY_BINARY and the default search)
+    #[arg(long)]
+    osquery_binary: Option<PathBuf>,
+
+    /// Print added/removed rows since the previous cycle as JSON-lines events on stdout
+    #[arg(long)]
+    differential: bool,
+
+    /// In differential mode, start a new epoch with a full snapshot every N cycles
+    /// (0: never; default: 12)
+    #[arg(long)]
+    epoch_interval: Option<u64>,
+
+    /// Directory where the cycle counter, last snapshot and differential cursors are
+    /// kept across restarts (not persisted when unset)
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Size cap of the state file in MiB (default: 64)
+    #[arg(long)]
+    state_max_mb: Option<u64>,
+
+    /// Extra flag passed to osqueryi, e.g. --osquery-flag=--disable_events (repeatable)
+    #[arg(long = "osquery-flag", allow_hyphen_values = true)]
+    osquery_flags: Vec<String>,
+}
+
+impl Args {
+    /// Command-line options are the last configuration layer
+    fn apply(&self, config: &mut DaemonConfig) {
+        if let Some(interval) = self.interval {
+            config.interval = interval;
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log_level = log_level.clone();
+        }
+        #[cfg(unix)]
+        if let Some(socket) = &self.extensions_socket {
+            config.extensions_socket = Some(socket.clone());
+        }
+        if let Some(timeout) = self.query_timeout {
+            config.collection.query_timeout = timeout;
+        }
+        if let Some(timeout) = self.cycle_timeout {
+            config.collection.cycle_timeout = timeout;
+        }
+        if let Some(workers) = self.workers {
+            config.collection.workers = workers;
+        }
+        if self.batch {
+            config.collection.batch = true;
+        }
+        if let Some(binary) = &self.osquery_binary {
+            config.osquery.binary = Some(binary.clone());
+        }
+        config.osquery.extra_flags.extend(self.osquery_flags.iter().cloned());
+        if self.differential {
+            config.differential.enabled = true;
+        }
+        if let Some(epoch_interval) = self.epoch_interval {
+            config.differential.epoch_interval = epoch_interval;
+        }
+        if let Some(dir) = &self.state_dir {
+            config.state.dir = Some(dir.clone());
+        }
+        if let Some(max_mb) = self.state_max_mb {
+            config.state.max_mb = max_mb;
+        }
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    
-    // Setup logging first (before any log calls)
-    // Check for RUST_LOG env var first, then use CLI arg
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", &args.log_level);
-    }
-    env_logger::Builder::from_default_env()
-        .format_timestamp_secs()
-        .init();
-    
-    // Get interval: check environment variable first, then use CLI arg
-    let interval = if let Ok(env_interval) = std::env::var("AGENT_INTERVAL") {
-        if let Ok(interval) = env_interval.parse::<u64>() {
-            log::info!("Using interval from AGENT_INTERVAL environment variable: {} seconds", interval);
-            interval
-        } else {
-            log::warn!("Invalid AGENT_INTERVAL value, using CLI/default: {} seconds", args.interval);
-            args.interval
+    let config = DaemonConfig::resolve(args.config.as_deref(), |config| args.apply(config));
+
+    // Setup logging first (before any other log calls). RUST_LOG takes
+    // precedence over the configuration file but not over --log-level.
+    let filter = match (&args.log_level, std::env::var("RUST_LOG"), &config) {
+        (Some(level), _, _) => level.clone(),
+        (None, Ok(rust_log), _) => rust_log,
+        (None, Err(_), Ok(config)) => config.log_level.clone(),
+        (None, Err(_), Err(_)) => "info".to_string(),
+    };
+    let mut logger = env_logger::Builder::new();
+    logger.format_timestamp_secs();
+    match filter.parse::<log::LevelFilter>() {
+        // A plain level is enforced through the global maximum so a reload can change it
+        Ok(level) => {
+            logger.filter_level(log::LevelFilter::Trace).init();
+            log::set_max_level(level);
+        }
+        Err(_) => logger.parse_filters(&filter).init(),
+    }
+
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
         }
-    } else {
-        args.interval
     };
+    if args.check_config {
+        println!("{}", serde_json::to_string_pretty(&config).unwrap_or_default());
+        return;
+    }
     
     log::info!("Security Agent Daemon starting...");
-    log::info!("Collection interval: {} seconds", interval);
+    if let Some(path) = &args.config {
+        log::info!("Configuration file: {}", path.display());
+    }
+    log::info!("Collection interval: {} seconds", config.interval);
     
     // Setup graceful shutdown handling
     let running = Arc::new(AtomicBool::new(true));
@@ -61,11 +189,41 @@ fn main() {
         r.store(false, Ordering::SeqCst);
     })
     .expect("Error setting Ctrl-C handler");
+
+    // SIGHUP only raises a flag; the reload itself happens between cycles
+    let reload = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, reload.clone()) {
+        log::warn!("Could not install SIGHUP handler, configuration reload is disabled: {}", e);
+    }
     
     // Initialize agent
-    let agent = get_agent();
-    let interval_duration = Duration::from_secs(interval);
-    let mut cycle_count = 0u64;
+    let sinks = match open_sinks(&config.outputs) {
+        Ok(sinks) => sinks,
+        Err(e) => {
+            log::error!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let remote = match config.remote.clone().map(RemoteClient::new).transpose() {
+        Ok(remote) => remote,
+        Err(e) => {
+            log::error!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let agent = start_agent(&config);
+    let (store, mut state) = open_state(&config);
+    if config.remote.is_some() && store.is_none() {
+        log::warn!("Without a state directory the node key is not kept; the daemon enrolls again on every start");
+    }
+    let mut runtime = Runtime::new(config, agent, store, sinks, remote);
+    let collecting = Arc::new(AtomicBool::new(false));
+    let mut cycle_count = state.cycle;
+    let differential = &runtime.config.differential;
+    let mut events = differential
+        .enabled
+        .then(|| EventStream::resume(differential.epoch_interval, &state.cursors));
     
     log::info!("Starting continuous monitoring loop...");
     log::info!("Press Ctrl+C to stop\n");
@@ -78,8 +236,27 @@ fn main() {
         log::info!("=== Collection Cycle #{} ===", cycle_count);
         
         // Collect system information
-        match collect_and_log(&*agent, cycle_count) {
-            Ok(()) => {
+        match collect_with_deadline(&runtime.agent, &runtime.options, &collecting, runtime.cycle_timeout) {
+            Ok(snapshot) => {
+                log_collection(&snapshot, cycle_count);
+                if let Some(stream) = events.as_mut() {
+                    emit_events(stream, &snapshot, cycle_count, &mut runtime.sinks);
+                    state.cursors = stream.cursors();
+                } else {
+                    write_records(std::slice::from_ref(&snapshot), &mut runtime.sinks);
+                }
+                if let Some(previous) = &state.last_snapshot {
+                    let changes = diff::diff(&previous.system_info, &snapshot.system_info);
+                    let (added, removed, modified) = changes.counts();
+                    log::info!(
+                        "Collection #{} changes: {} added, {} removed, {} modified",
+                        cycle_count,
+                        added,
+                        removed,
+                        modified
+                    );
+                }
+                state.last_snapshot = Some(snapshot);
                 let elapsed = cycle_start.elapsed()
                     .unwrap_or(Duration::from_secs(0));
                 log::info!("Cycle #{} completed in {:.2}s", cycle_count, elapsed.as_secs_f64());
@@ -88,6 +265,12 @@ fn main() {
                 log::error!("Cycle #{} failed: {}", cycle_count, e);
             }
         }
+
+        state.cycle = cycle_count;
+        save_state(&runtime, &mut state);
+        // Remote changes not saved since
+        let mut remote_unsaved = false;
+        let mut saved_at = Instant::now();
         
         // Check if we should continue
         if !running.load(Ordering::SeqCst) {
@@ -95,29 +278,399 @@ fn main() {
         }
         
         // Wait for next cycle
-        log::info!("Waiting {} seconds until next cycle...\n", interval);
+        log::info!("Waiting {} seconds until next cycle...\n", runtime.config.interval);
         
-        // Sleep in smaller chunks to allow for quicker shutdown response
+        // Sleep in smaller chunks to allow for quicker shutdown and reload response
         let sleep_chunk = Duration::from_secs(1);
-        let mut remaining = interval_duration;
+        let waiting_since = SystemTime::now();
         
-        while remaining.as_secs() > 0 && running.load(Ordering::SeqCst) {
-            std::thread::sleep(sleep_chunk.min(remaining));
-            if remaining > sleep_chunk {
-                remaining -= sleep_chunk;
-            } else {
+        while running.load(Ordering::SeqCst) {
+            if reload.swap(false, Ordering::SeqCst) {
+                runtime = reload_config(&args, runtime);
+                update_event_stream(&mut events, &runtime.config.differential, &state);
+            }
+            if let Some(remote) = runtime.remote.as_mut() {
+                // A new node key is saved right away so a restart does not
+                // enroll again. Queued results are saved with the query results
+                // they came from, so after a crash the next run reports lost
+                // records again; accepted ones may be sent twice.
+                let node_key = state.node_key.clone();
+                remote_unsaved |= remote.poll(runtime.agent.as_ref(), &mut state);
+                if remote_unsaved && (state.node_key != node_key || saved_at.elapsed() >= REMOTE_SAVE_INTERVAL) {
+                    save_state(&runtime, &mut state);
+                    remote_unsaved = false;
+                    saved_at = Instant::now();
+                }
+            }
+            // Measured against the current interval, which a reload may have changed
+            let waited = waiting_since.elapsed().unwrap_or_default();
+            let Some(remaining) = Duration::from_secs(runtime.config.interval).checked_sub(waited) else {
+                break;
+            };
+            if remaining.is_zero() {
                 break;
             }
+            std::thread::sleep(sleep_chunk.min(remaining));
         }
     }
     
+    // Keeps remote results queued since the last cycle
+    save_state(&runtime, &mut state);
     log::info!("Daemon stopped. Total cycles completed: {}", cycle_count);
 }
 
-/// Collects system information and logs summary statistics
-fn collect_and_log(agent: &dyn Agent, cycle: u64) -> anyhow::Result<()> {
-    let system_info = agent.collect_system_info();
-    
+/// The configuration and everything built from it; replaced as a whole on reload
+struct Runtime {
+    config: DaemonConfig,
+    agent: Arc<dyn Agent>,
+    options: CollectOptions,
+    cycle_timeout: Option<Duration>,
+    store: Option<StateStore>,
+    sinks: Vec<Box<dyn OutputSink>>,
+    remote: Option<RemoteClient>,
+}
+
+impl Runtime {
+    fn new(
+        config: DaemonConfig,
+        agent: Arc<dyn Agent>,
+        store: Option<StateStore>,
+        sinks: Vec<Box<dyn OutputSink>>,
+        remote: Option<RemoteClient>,
+    ) -> Runtime {
+        let options = config.collect_options();
+        if let Some(sections) = &options.sections {
+            let names: Vec<&str> = sections.iter().map(|section| section.name()).collect();
+            log::info!("Collecting sections: {}", names.join(", "));
+        }
+        if config.differential.enabled {
+            log::info!("Differential mode: full snapshot every {} cycles", config.differential.epoch_interval);
+        }
+        for sink in &sinks {
+            log::info!("Output: {}", sink.describe());
+        }
+        if let Some(remote) = &remote {
+            log::info!("Remote server: {}", remote.url());
+        }
+        let cycle_timeout = config.collection.cycle_timeout;
+        Runtime {
+            cycle_timeout: (cycle_timeout > 0).then(|| Duration::from_secs(cycle_timeout)),
+            options,
+            agent,
+            store,
+            sinks,
+            remote,
+            config,
+        }
+    }
+}
+
+/// Re-reads the configuration after SIGHUP and returns the runtime to continue
+/// with: a new one, or `current` unchanged when the new configuration is
+/// invalid, cannot be applied or is the same.
+fn reload_config(args: &Args, mut current: Runtime) -> Runtime {
+    log::info!("SIGHUP received, reloading configuration");
+    let config = match DaemonConfig::resolve(args.config.as_deref(), |config| args.apply(config)) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Keeping the current configuration: {}", e);
+            return current;
+        }
+    };
+
+    if config == current.config {
+        log::info!("Configuration unchanged");
+        return current;
+    }
+    // The serialized form hides secrets, so it only describes the change
+    let old = serde_json::to_value(&current.config).unwrap_or_default();
+    let new = serde_json::to_value(&config).unwrap_or_default();
+    for change in diff::compare_json(&old, &new) {
+        log::info!("Configuration changed: {}: {} -> {}", change.field, change.old, change.new);
+    }
+    for field in hidden_changes(&current.config, &config) {
+        log::info!("Configuration changed: {} (value not shown)", field);
+    }
+
+    // Open everything new before switching, so a failure keeps the old configuration
+    let new_store = if config.state != current.config.state {
+        match &config.state.dir {
+            Some(dir) => match StateStore::open(dir, config.state.max_mb.saturating_mul(1024 * 1024)) {
+                Ok(store) => Some(Some(store)),
+                Err(e) => {
+                    log::error!("Keeping the current configuration: {:#}", e);
+                    return current;
+                }
+            },
+            None => Some(None),
+        }
+    } else {
+        None
+    };
+    let new_remote = if config.remote != current.config.remote {
+        match config.remote.clone().map(RemoteClient::new).transpose() {
+            Ok(remote) => Some(remote),
+            Err(e) => {
+                log::error!("Keeping the current configuration: {:#}", e);
+                return current;
+            }
+        }
+    } else {
+        None
+    };
+    let new_sinks = if config.outputs != current.config.outputs {
+        // Sinks can hold a spool directory their replacements need, so close them first
+        current.sinks.clear();
+        match open_sinks(&config.outputs) {
+            Ok(sinks) => Some(sinks),
+            Err(e) => {
+                log::error!("Keeping the current configuration: {:#}", e);
+                match open_sinks(&current.config.outputs) {
+                    Ok(sinks) => current.sinks = sinks,
+                    Err(e) => log::error!("Failed to reopen the current outputs: {:#}", e),
+                }
+                return current;
+            }
+        }
+    } else {
+        None
+    };
+    let store = new_store.unwrap_or_else(|| current.store.take());
+    let sinks = new_sinks.unwrap_or_else(|| std::mem::take(&mut current.sinks));
+    let remote = new_remote.unwrap_or_else(|| current.remote.take());
+
+    if config.log_level != current.config.log_level {
+        match config.log_level.parse::<log::LevelFilter>() {
+            Ok(level) if args.log_level.is_none() && std::env::var("RUST_LOG").is_err() => log::set_max_level(level),
+            _ => log::warn!("log_level is overridden by --log-level or RUST_LOG; not changed"),
+        }
+    }
+
+    let backend_changed = current.config.osquery != config.osquery
+        || current.config.extensions_socket != config.extensions_socket
+        || current.config.collection.query_timeout != config.collection.query_timeout;
+    let agent = if backend_changed {
+        start_agent(&config)
+    } else {
+        current.agent.clone()
+    };
+    Runtime::new(config, agent, store, sinks, remote)
+}
+
+/// Changed settings whose values are redacted or not serialized at all
+fn hidden_changes(old: &DaemonConfig, new: &DaemonConfig) -> Vec<String> {
+    let mut fields = Vec::new();
+    if old.osquery.env != new.osquery.env {
+        fields.push("osquery.env".to_string());
+    }
+    for (i, (old, new)) in old.outputs.iter().zip(&new.outputs).enumerate() {
+        if let (OutputConfig::Http(old), OutputConfig::Http(new)) = (old, new) {
+            if old.bearer_token.is_some() && new.bearer_token.is_some() && old.bearer_token != new.bearer_token {
+                fields.push(format!("outputs[{}].bearer_token", i));
+            }
+            for (name, value) in &new.headers {
+                if old.headers.get(name).is_some_and(|old| old != value) {
+                    fields.push(format!("outputs[{}].headers.{}", i, name));
+                }
+            }
+        }
+    }
+    if let (Some(old), Some(new)) = (&old.remote, &new.remote) {
+        if old.enroll_secret.is_some() && new.enroll_secret.is_some() && old.enroll_secret != new.enroll_secret {
+            fields.push("remote.enroll_secret".to_string());
+        }
+    }
+    fields
+}
+
+/// Starts, stops or re-times the differential stream to match a reloaded configuration
+fn update_event_stream(events: &mut Option<EventStream>, differential: &DifferentialConfig, state: &AgentState) {
+    match (events.as_mut(), differential.enabled) {
+        (Some(stream), true) => stream.set_epoch_interval(differential.epoch_interval),
+        (Some(_), false) => *events = None,
+        (None, true) => *events = Some(EventStream::resume(differential.epoch_interval, &state.cursors)),
+        (None, false) => {}
+    }
+}
+
+fn save_state(runtime: &Runtime, state: &mut AgentState) {
+    if let Some(store) = &runtime.store {
+        if let Err(e) = store.save(state) {
+            log::error!("Failed to save state: {:#}", e);
+        }
+    }
+}
+
+/// Loads the saved state when a state directory is configured. A store that cannot be
+/// opened or read leaves the daemon running without persistence.
+fn open_state(config: &DaemonConfig) -> (Option<StateStore>, AgentState) {
+    let Some(dir) = &config.state.dir else {
+        return (None, AgentState::default());
+    };
+    let store = match StateStore::open(dir, config.state.max_mb.saturating_mul(1024 * 1024)) {
+        Ok(store) => store,
+        Err(e) => {
+            log::error!("State will not be persisted: {:#}", e);
+            return (None, AgentState::default());
+        }
+    };
+    match store.load() {
+        Ok(state) => {
+            log::info!(
+                "State: {} (cycle {}, {}, {} cursor(s), {} pending record(s))",
+                store.path().display(),
+                state.cycle,
+                if state.last_snapshot.is_some() { "last snapshot restored" } else { "no previous snapshot" },
+                state.cursors.len(),
+                state.pending.len()
+            );
+            (Some(store), state)
+        }
+        Err(e) => {
+            log::error!("State will not be persisted: {:#}", e);
+            (None, AgentState::default())
+        }
+    }
+}
+
+/// Builds the agent and logs what its osquery offers
+fn start_agent(config: &DaemonConfig) -> Arc<dyn Agent> {
+    let agent: Arc<dyn Agent> = Arc::from(build_agent(config));
+    match agent.capabilities() {
+        Some(capabilities) => log::info!(
+            "osquery {} with {} tables available",
+            capabilities.version.as_deref().unwrap_or("(unknown version)"),
+            capabilities.tables.len()
+        ),
+        None => log::warn!("Could not probe osquery tables; will retry on the first cycle"),
+    }
+    agent
+}
+
+/// Builds the platform agent with the configured query backend
+fn build_agent(config: &DaemonConfig) -> Box<dyn Agent> {
+    let query_timeout = config.collection.query_timeout;
+    let query_timeout = (query_timeout > 0).then(|| Duration::from_secs(query_timeout));
+
+    #[cfg(unix)]
+    if let Some(socket) = &config.extensions_socket {
+        use security_agent::extension::ExtensionSocketBackend;
+
+        log::info!("Using osquery extension socket: {}", socket.display());
+        let mut backend = ExtensionSocketBackend::new(socket);
+        if let Some(timeout) = query_timeout {
+            backend = backend.with_timeout(timeout);
+        }
+        return get_agent_with_backend(Box::new(backend));
+    }
+    #[cfg(not(unix))]
+    if config.extensions_socket.is_some() {
+        log::warn!("The osquery extension socket is only supported on Unix; using osqueryi");
+    }
+
+    let backend = OsqueryiBackend::with_config(config.osquery.clone()).with_timeout(query_timeout);
+    log::info!(
+        "Using osqueryi binary: {} (version {})",
+        backend.binary(),
+        backend.version().unwrap_or("unknown")
+    );
+    let flags = backend.config().flags();
+    if !flags.is_empty() {
+        log::info!("osqueryi flags: {}", flags.join(" "));
+    }
+    get_agent_with_backend(Box::new(backend))
+}
+
+/// Runs a collection on a worker thread and gives up on it once `deadline` passes.
+/// While an abandoned collection is still running, later cycles are skipped
+/// rather than stacking up more osquery work.
+fn collect_with_deadline(
+    agent: &Arc<dyn Agent>,
+    options: &CollectOptions,
+    collecting: &Arc<AtomicBool>,
+    deadline: Option<Duration>,
+) -> anyhow::Result<Snapshot> {
+    if collecting.swap(true, Ordering::SeqCst) {
+        anyhow::bail!("previous collection is still running past its deadline; skipping this cycle");
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let worker_agent = agent.clone();
+    let worker_flag = collecting.clone();
+    let worker_options = options.clone();
+    std::thread::spawn(move || {
+        // Clears the in-flight flag even if collection panics
+        let _done = ClearOnDrop(worker_flag);
+        let _ = tx.send(Snapshot::collect(worker_agent.as_ref(), &worker_options));
+    });
+
+    match deadline {
+        Some(deadline) => rx.recv_timeout(deadline).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => {
+                anyhow::anyhow!("collection exceeded cycle deadline of {}s", deadline.as_secs())
+            }
+            mpsc::RecvTimeoutError::Disconnected => anyhow::anyhow!("collection thread panicked"),
+        }),
+        None => rx.recv().map_err(|_| anyhow::anyhow!("collection thread panicked")),
+    }
+}
+
+struct ClearOnDrop(Arc<AtomicBool>);
+
+impl Drop for ClearOnDrop {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Serializes records once and writes them to every sink. A failing sink is
+/// logged and does not keep the others from receiving the records.
+fn write_records<T: serde::Serialize>(records: &[T], sinks: &mut [Box<dyn OutputSink>]) {
+    if sinks.is_empty() || records.is_empty() {
+        return;
+    }
+    let lines: Vec<String> = records
+        .iter()
+        .filter_map(|record| match serde_json::to_string(record) {
+            Ok(line) => Some(line),
+            Err(e) => {
+                log::error!("Failed to serialize record: {}", e);
+                None
+            }
+        })
+        .collect();
+    for sink in sinks {
+        if let Err(e) = sink.write(&lines) {
+            log::error!("Output {} failed: {:#}", sink.describe(), e);
+        }
+    }
+}
+
+/// Writes the cycle's differential events; without configured outputs they go to stdout
+fn emit_events(stream: &mut EventStream, snapshot: &Snapshot, cycle: u64, sinks: &mut [Box<dyn OutputSink>]) {
+    let events = stream.process(snapshot);
+    if sinks.is_empty() {
+        write_records(&events, &mut [Box::new(StdoutSink) as Box<dyn OutputSink>]);
+    } else {
+        write_records(&events, sinks);
+    }
+
+    let counters = stream.counters();
+    log::info!(
+        "Collection #{} events: {} (epoch {}, counter {}); totals: {} added, {} removed, {} snapshots",
+        cycle,
+        events.len(),
+        stream.epoch(),
+        stream.counter(),
+        counters.added,
+        counters.removed,
+        counters.snapshots
+    );
+}
+
+/// Logs summary statistics for a collection
+fn log_collection(snapshot: &Snapshot, cycle: u64) {
+    let system_info = &snapshot.system_info;
     // Log summary statistics
     log::info!(
         "Collection #{} summary: {} processes, {} connections, {} ports, {} users, {} services, {} tasks, {} packages",
@@ -146,27 +699,45 @@ fn collect_and_log(agent: &dyn Agent, cycle: u64) -> anyhow::Result<()> {
             log::debug!("Hostname: {}", hostname);
         }
     }
+    log::debug!(
+        "Host ID: {} (from {:?}), snapshot schema v{}",
+        snapshot.host_id,
+        snapshot.host_id_source,
+        snapshot.schema_version
+    );
     
-    // Log any warnings (empty collections might indicate issues)
-    let mut warnings = Vec::new();
-    
-    if system_info.processes.is_empty() {
-        warnings.push("No processes collected (OSquery may have issues)");
-    }
-    if system_info.network_connections.is_empty() && system_info.listening_ports.is_empty() {
-        warnings.push("No network data collected");
-    }
-    if system_info.users.is_empty() {
-        warnings.push("No users collected");
-    }
-    if system_info.services.is_empty() {
-        warnings.push("No services collected");
-    }
-    
-    for warning in warnings {
-        log::warn!("{}", warning);
+    // Log how long each section took, slowest first
+    let mut timings: Vec<_> = system_info.collection_status.iter().collect();
+    timings.sort_by_key(|(_, status)| std::cmp::Reverse(status.duration_ms));
+    let timings: Vec<String> = timings
+        .iter()
+        .map(|(section, status)| format!("{} {}ms", section, status.duration_ms))
+        .collect();
+    log::info!("Collection #{} section timings: {}", cycle, timings.join(", "));
+
+    // Log per-section problems reported by the agent
+    for (section, status) in &system_info.collection_status {
+        match status.state {
+            SectionState::Failed => log::warn!(
+                "Section '{}' failed: {}",
+                section,
+                status.error.as_deref().unwrap_or("unknown error")
+            ),
+            SectionState::Unsupported => log::debug!(
+                "Section '{}' is not supported on this host (tables: {})",
+                section,
+                status.unsupported_tables.join(", ")
+            ),
+            SectionState::Empty => log::debug!("Section '{}' returned no rows", section),
+            SectionState::Disabled => log::debug!("Section '{}' is disabled", section),
+            SectionState::Ok => {}
+        }
+        if status.rows_skipped > 0 {
+            log::warn!(
+                "Section '{}': {} rows did not match the expected schema and were skipped",
+                section,
+                status.rows_skipped
+            );
+        }
     }
-    
-    Ok(())
 }
-
@@ -1,11 +1,39 @@
 // === OSquery Agent Test Tool ===
 // Run with: cargo run --bin testosquery
+// Record fixtures: cargo run --bin testosquery -- --record fixtures/linux
+// Replay fixtures: cargo run --bin testosquery -- --replay fixtures/windows --platform windows
+
+use clap::Parser;
+use std::path::PathBuf;
 
 // Use the library crate
 use security_agent::models::SystemInfo;
-use security_agent::agent::get_agent;
+use security_agent::agent::{agent_for_platform, CollectOptions, Platform};
+use security_agent::fixture::{RecordingBackend, ReplayBackend};
+use security_agent::osquery::{OsqueryiBackend, QueryBackend};
+use security_agent::snapshot::Snapshot;
+
+#[derive(Parser, Debug)]
+#[command(name = "testosquery")]
+#[command(about = "Standalone test tool for the OSquery integration")]
+struct Args {
+    /// Record every query and its result into this fixture directory
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Answer queries from this fixture directory instead of running osquery
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Agent implementation to run (windows, linux, macos); defaults to this host
+    #[arg(long)]
+    platform: Option<Platform>,
+}
 
 fn main() {
+    let args = Args::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
     println!("╔══════════════════════════════════════════════════════════════╗");
     println!("║         Security Agent - OSquery Test Tool                  ║");
     println!("╚══════════════════════════════════════════════════════════════╝\n");
@@ -13,11 +41,45 @@ fn main() {
     println!("Collecting system information via OSquery...\n");
     
     // Get platform-specific agent
-    let agent = get_agent();
+    let backend: Box<dyn QueryBackend> = if let Some(dir) = &args.replay {
+        match ReplayBackend::load(dir) {
+            Ok(backend) => Box::new(backend),
+            Err(e) => {
+                eprintln!("[ERROR] {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(dir) = &args.record {
+        match RecordingBackend::new(OsqueryiBackend::new(), dir) {
+            Ok(backend) => Box::new(backend),
+            Err(e) => {
+                eprintln!("[ERROR] {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        Box::new(OsqueryiBackend::new())
+    };
+    let platform = args.platform.unwrap_or_else(Platform::current);
+    let agent = agent_for_platform(platform, backend);
     
     // Collect system info
     println!("[INFO] Querying OSquery...");
-    let system_info = agent.collect_system_info();
+    let snapshot = Snapshot::collect(agent.as_ref(), &CollectOptions::default());
+    let system_info = &snapshot.system_info;
+    if let Some(capabilities) = agent.capabilities() {
+        println!(
+            "[INFO] osquery {} ({} tables available)",
+            capabilities.version.as_deref().unwrap_or("(unknown version)"),
+            capabilities.tables.len()
+        );
+    }
+    println!(
+        "[INFO] Host ID {} (from {:?}), collected in {} ms",
+        snapshot.host_id,
+        snapshot.host_id_source,
+        snapshot.duration_ms()
+    );
     
     // Display results
     println!("\n═══════════════════════════════════════════════════════════════");
@@ -25,19 +87,19 @@ fn main() {
     println!("═══════════════════════════════════════════════════════════════\n");
     
     // Print OS Version
-    print_os_version(&system_info);
+    print_os_version(system_info);
     
     // Print System Info
-    print_system_info(&system_info);
+    print_system_info(system_info);
     
     // Print summary counts
-    print_summary(&system_info);
+    print_summary(system_info);
     
     // Print sample data
-    print_sample_data(&system_info);
+    print_sample_data(system_info);
     
     // Print full JSON output option
-    print_json_option(&system_info);
+    print_json_option(&snapshot);
 }
 
 fn print_os_version(system_info: &SystemInfo) {
@@ -73,15 +135,11 @@ fn print_system_info(system_info: &SystemInfo) {
         if let Some(cpu_brand) = &sys_info.cpu_brand {
             println!("│ CPU Brand:   {:50} │", cpu_brand);
         }
-        if let Some(mem_str) = &sys_info.physical_memory {
-            if let Ok(bytes) = mem_str.parse::<f64>() {
-                let mem_gb = bytes / 1_073_741_824.0; // Convert to GB
-                println!("│ Memory:      {:47.2} GB │", mem_gb);
-            } else {
-                println!("│ Memory:      {:47} (parse error) │", mem_str);
-            }
+        if let Some(bytes) = sys_info.physical_memory {
+            let mem_gb = bytes as f64 / 1_073_741_824.0; // Convert to GB
+            println!("│ Memory:      {:47.2} GB │", mem_gb);
         }
-        if let (Some(phys), Some(log)) = (&sys_info.cpu_physical_cores, &sys_info.cpu_logical_cores) {
+        if let (Some(phys), Some(log)) = (sys_info.cpu_physical_cores, sys_info.cpu_logical_cores) {
             println!("│ CPU Cores:   {:25} (Physical/Logical) │", 
                      format!("{}/{}", phys, log));
         }
@@ -121,7 +179,7 @@ fn print_sample_data(system_info: &SystemInfo) {
         println!("│ Top 3 Processes:                                            │");
         for (i, proc) in system_info.processes.iter().take(3).enumerate() {
             let name = proc.name.as_deref().unwrap_or("Unknown");
-            let pid = proc.pid.clone().unwrap_or_else(|| "N/A".to_string());
+            let pid = proc.pid.map_or_else(|| "N/A".to_string(), |pid| pid.to_string());
             println!("│   {}. {} (PID: {})", i + 1, 
                      truncate(name, 35), 
                      truncate(&pid, 10));
@@ -133,7 +191,7 @@ fn print_sample_data(system_info: &SystemInfo) {
     if !system_info.listening_ports.is_empty() {
         println!("│ Top 3 Listening Ports:                                     │");
         for (i, port) in system_info.listening_ports.iter().take(3).enumerate() {
-            let port_num = port.port.as_deref().unwrap_or("N/A");
+            let port_num = port.port.map_or_else(|| "N/A".to_string(), |port| port.to_string());
             let address = port.address.as_deref().unwrap_or("N/A");
             println!("│   {}. {}:{}", i + 1, address, port_num);
         }
@@ -145,7 +203,7 @@ fn print_sample_data(system_info: &SystemInfo) {
         println!("│ Sample Users:                                               │");
         for (i, user) in system_info.users.iter().take(3).enumerate() {
             let username = user.username.as_deref().unwrap_or("Unknown");
-            let uid = user.uid.as_deref().unwrap_or("N/A");
+            let uid = user.uid.map_or_else(|| "N/A".to_string(), |uid| uid.to_string());
             println!("│   {}. {} (UID: {})", i + 1, username, uid);
         }
     }
@@ -153,12 +211,12 @@ fn print_sample_data(system_info: &SystemInfo) {
     println!("└──────────────────────────────────────────────────────────────┘\n");
 }
 
-fn print_json_option(system_info: &SystemInfo) {
+fn print_json_option(snapshot: &Snapshot) {
     println!("═══════════════════════════════════════════════════════════════");
     println!("              FULL JSON OUTPUT (First 2000 chars)");
     println!("═══════════════════════════════════════════════════════════════\n");
     
-    match serde_json::to_string_pretty(system_info) {
+    match serde_json::to_string_pretty(snapshot) {
         Ok(json) => {
             let preview = if json.len() > 2000 {
                 format!("{}\n\n... (truncated, full output is {} characters) ...", 
@@ -0,0 +1,700 @@
+// ============================================================================
+// Daemon Configuration
+// ============================================================================
+//
+// `agent-daemon` settings, read from a TOML file (`--config`). Settings are
+// layered: built-in defaults, then the file, then environment variables, then
+// command-line options. Every problem in the file is reported at once rather
+// than one per run:
+//
+//   interval = 300
+//   sections = ["processes", "listening_ports", "installed_packages"]
+//
+//   [osquery]
+//   binary = "/opt/osquery/bin/osqueryi"
+//   flags = ["--disable_events"]
+//
+//   [[rules]]
+//   name = "new-listener"
+//   section = "listening_ports"
+//   on = ["added"]
+//   severity = "high"
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::agent::CollectOptions;
+use crate::http::HttpOutput;
+use crate::models::Section;
+use crate::osquery::OsqueryConfig;
+use crate::remote::RemoteConfig;
+use crate::rotation::{Compression, RotationPolicy};
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid TOML in {path}: {message}")]
+    Syntax { path: PathBuf, message: String },
+
+    #[error("Invalid configuration:\n  - {}", .errors.join("\n  - "))]
+    Invalid { errors: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DaemonConfig {
+    /// Seconds between collection cycles
+    pub interval: u64,
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+    pub log_level: String,
+    /// `Section::name()`s to collect; empty collects every section
+    pub sections: Vec<String>,
+    pub collection: CollectionConfig,
+    pub osquery: OsqueryConfig,
+    /// Query a running osqueryd through its extension socket instead of spawning osqueryi
+    pub extensions_socket: Option<PathBuf>,
+    pub state: StateConfig,
+    pub differential: DifferentialConfig,
+    pub outputs: Vec<OutputConfig>,
+    /// Change rule settings; validated and kept here, not evaluated by the daemon
+    pub rules: Vec<Rule>,
+    /// osquery TLS remote API server to enroll with, see `remote.rs`
+    pub remote: Option<RemoteConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CollectionConfig {
+    /// Maximum number of osquery queries running at once
+    pub workers: usize,
+    /// Run each cycle in a single osqueryi session
+    pub batch: bool,
+    /// Per-query timeout in seconds (0 disables)
+    pub query_timeout: u64,
+    /// Deadline for a whole cycle in seconds (0 disables)
+    pub cycle_timeout: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StateConfig {
+    /// Where state is persisted across restarts; not persisted when unset
+    pub dir: Option<PathBuf>,
+    /// Size cap of the state file in MiB
+    pub max_mb: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DifferentialConfig {
+    /// Emit added/removed events instead of whole snapshots
+    pub enabled: bool,
+    /// Cycles per epoch; 0 only emits the initial snapshot
+    pub epoch_interval: u64,
+}
+
+/// Where a cycle's records go
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum OutputConfig {
+    /// JSON lines on standard output
+    Stdout,
+    /// JSON lines appended to one file
+    File { path: PathBuf },
+    /// JSON lines in a file rotated by size or age, see `rotation.rs`
+    RotatingFile {
+        path: PathBuf,
+        /// Size in MiB at which the file is rotated
+        #[serde(default = "default_rotate_mb")]
+        max_mb: u64,
+        /// Age in seconds at which the file is rotated
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rotate_after_secs: Option<u64>,
+        #[serde(default)]
+        compression: Compression,
+        /// Rotated segments kept
+        #[serde(default = "default_rotated_files")]
+        max_files: usize,
+        /// Total size in MiB of the rotated segments kept
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retain_mb: Option<u64>,
+        /// Rotated segments older than this many days are removed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retain_days: Option<u64>,
+    },
+    /// Batches POSTed to a collector, see `http.rs`
+    Http(HttpOutput),
+}
+
+impl OutputConfig {
+    /// Rotation settings of a `rotating_file` output
+    pub fn rotation_policy(&self) -> Option<RotationPolicy> {
+        match self {
+            OutputConfig::RotatingFile {
+                max_mb,
+                rotate_after_secs,
+                compression,
+                max_files,
+                retain_mb,
+                retain_days,
+                ..
+            } => Some(RotationPolicy {
+                max_bytes: max_mb.saturating_mul(1024 * 1024),
+                max_age: rotate_after_secs.map(Duration::from_secs),
+                compression: *compression,
+                max_files: Some(*max_files),
+                retain_bytes: retain_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+                retain_age: retain_days.map(|days| Duration::from_secs(days.saturating_mul(86400))),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// `[[rules]]` entry: a section to watch, the kinds of change and optionally a
+/// substring of the row's natural key, e.g. "anything new on 0.0.0.0"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    /// `Section::name()` of the section to watch, e.g. `listening_ports`
+    pub section: String,
+    /// Changes the rule is about; empty means any change
+    #[serde(default)]
+    pub on: Vec<ChangeKind>,
+    /// Only rows whose natural key contains this text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_contains: Option<String>,
+    #[serde(default)]
+    pub severity: Severity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Low,
+    #[default]
+    Medium,
+    High,
+    Critical,
+}
+
+fn default_rotate_mb() -> u64 {
+    100
+}
+
+fn default_rotated_files() -> usize {
+    5
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            interval: 300,
+            log_level: "info".to_string(),
+            sections: Vec::new(),
+            collection: CollectionConfig {
+                workers: 4,
+                batch: false,
+                query_timeout: 60,
+                cycle_timeout: 600,
+            },
+            osquery: OsqueryConfig::default(),
+            extensions_socket: None,
+            state: StateConfig {
+                dir: None,
+                max_mb: 64,
+            },
+            differential: DifferentialConfig {
+                enabled: false,
+                epoch_interval: 12,
+            },
+            outputs: Vec::new(),
+            rules: Vec::new(),
+            remote: None,
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Layers defaults, the file at `path` (if any), the environment and
+    /// finally `overrides` (command-line options), then validates the result.
+    /// All problems found along the way are returned together.
+    pub fn resolve(path: Option<&Path>, overrides: impl FnOnce(&mut DaemonConfig)) -> Result<DaemonConfig, ConfigError> {
+        let mut config = DaemonConfig::default();
+        let mut errors = Vec::new();
+        if let Some(path) = path {
+            let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+                path: path.to_path_buf(),
+                source,
+            })?;
+            let table: toml::Table = text.parse().map_err(|e: toml::de::Error| ConfigError::Syntax {
+                path: path.to_path_buf(),
+                message: e.to_string().trim_end().to_string(),
+            })?;
+            errors.extend(
+                config
+                    .read_table(table)
+                    .into_iter()
+                    .map(|e| format!("{}: {}", path.display(), e)),
+            );
+        }
+        errors.extend(config.apply_env());
+        overrides(&mut config);
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid { errors })
+        }
+    }
+
+    /// Applies `AGENT_INTERVAL`, `AGENT_ENROLL_SECRET` (with a `[remote]`
+    /// section) and the `OSQUERY_*` variables (see `OsqueryConfig::apply_env()`),
+    /// returning problems with their values
+    pub fn apply_env(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Ok(value) = std::env::var("AGENT_INTERVAL") {
+            match value.trim().parse() {
+                Ok(interval) => self.interval = interval,
+                Err(_) => errors.push(format!("AGENT_INTERVAL: expected a number of seconds, got \"{}\"", value)),
+            }
+        }
+        if let (Some(remote), Ok(secret)) = (self.remote.as_mut(), std::env::var("AGENT_ENROLL_SECRET")) {
+            remote.enroll_secret = Some(secret);
+        }
+        self.osquery.apply_env();
+        errors
+    }
+
+    /// Problems with the combined settings; empty when they are usable
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.interval == 0 {
+            errors.push("interval: must be at least 1 second".to_string());
+        }
+        if !LOG_LEVELS.contains(&self.log_level.to_ascii_lowercase().as_str()) {
+            errors.push(format!(
+                "log_level: \"{}\" is not one of {}",
+                self.log_level,
+                LOG_LEVELS.join(", ")
+            ));
+        }
+        for name in &self.sections {
+            if let Err(e) = name.parse::<Section>() {
+                errors.push(format!("sections: {} (expected one of {})", e, section_names()));
+            }
+        }
+        if self.collection.workers == 0 {
+            errors.push("collection.workers: must be at least 1".to_string());
+        }
+        if self.state.max_mb == 0 {
+            errors.push("state.max_mb: must be at least 1".to_string());
+        }
+
+        let mut spool_dirs = BTreeSet::new();
+        for (i, output) in self.outputs.iter().enumerate() {
+            match output {
+                OutputConfig::Stdout => {}
+                OutputConfig::Http(http) => {
+                    errors.extend(http.validate(&format!("outputs[{}]", i)));
+                    if let Some(dir) = &http.spool_dir {
+                        if !spool_dirs.insert(dir) {
+                            errors.push(format!("outputs[{}].spool_dir: {} is used by an earlier output", i, dir.display()));
+                        }
+                    }
+                }
+                OutputConfig::File { path } | OutputConfig::RotatingFile { path, .. } if path.as_os_str().is_empty() => {
+                    errors.push(format!("outputs[{}].path: must not be empty", i));
+                }
+                OutputConfig::File { .. } => {}
+                OutputConfig::RotatingFile {
+                    max_mb,
+                    rotate_after_secs,
+                    ..
+                } => {
+                    if *max_mb == 0 {
+                        errors.push(format!("outputs[{}].max_mb: must be at least 1", i));
+                    }
+                    if *rotate_after_secs == Some(0) {
+                        errors.push(format!("outputs[{}].rotate_after_secs: must be at least 1", i));
+                    }
+                }
+            }
+        }
+
+        if let Some(remote) = &self.remote {
+            errors.extend(remote.validate());
+        }
+
+        let mut rule_names = BTreeSet::new();
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.name.trim().is_empty() {
+                errors.push(format!("rules[{}].name: must not be empty", i));
+            } else if !rule_names.insert(rule.name.as_str()) {
+                errors.push(format!("rules[{}].name: \"{}\" is used by an earlier rule", i, rule.name));
+            }
+            if let Err(e) = rule.section.parse::<Section>() {
+                errors.push(format!("rules[{}].section: {} (expected one of {})", i, e, section_names()));
+            }
+        }
+        errors
+    }
+
+    /// Collection settings for `Agent::collect_with()`
+    pub fn collect_options(&self) -> CollectOptions {
+        let sections = (!self.sections.is_empty())
+            .then(|| self.sections.iter().filter_map(|name| name.parse().ok()).collect());
+        CollectOptions {
+            workers: self.collection.workers.max(1),
+            batch: self.collection.batch,
+            sections,
+        }
+    }
+
+    /// Copies every key of the document into `self`, collecting type errors
+    /// and unknown keys
+    fn read_table(&mut self, table: toml::Table) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut root = Fields::new(table, "", &mut errors);
+        set(&mut self.interval, root.get("interval"));
+        set(&mut self.log_level, root.get("log_level"));
+        set(&mut self.sections, root.get("sections"));
+
+        if let Some(mut collection) = root.table("collection") {
+            set(&mut self.collection.workers, collection.get("workers"));
+            set(&mut self.collection.batch, collection.get("batch"));
+            set(&mut self.collection.query_timeout, collection.get("query_timeout"));
+            set(&mut self.collection.cycle_timeout, collection.get("cycle_timeout"));
+            collection.finish();
+        }
+        if let Some(mut osquery) = root.table("osquery") {
+            let config = &mut self.osquery;
+            config.binary = osquery.get("binary").or(config.binary.take());
+            config.config_path = osquery.get("config_path").or(config.config_path.take());
+            config.database_path = osquery.get("database_path").or(config.database_path.take());
+            set(&mut config.disable_events, osquery.get("disable_events"));
+            config.extensions_autoload = osquery.get("extensions_autoload").or(config.extensions_autoload.take());
+            set(&mut config.extra_flags, osquery.get("flags"));
+            if let Some(env) = osquery.get::<BTreeMap<String, String>>("env") {
+                config.env = env.into_iter().collect();
+            }
+            self.extensions_socket = osquery.get("extensions_socket").or(self.extensions_socket.take());
+            osquery.finish();
+        }
+        if let Some(mut state) = root.table("state") {
+            self.state.dir = state.get("dir").or(self.state.dir.take());
+            set(&mut self.state.max_mb, state.get("max_mb"));
+            state.finish();
+        }
+        if let Some(mut differential) = root.table("differential") {
+            set(&mut self.differential.enabled, differential.get("enabled"));
+            set(&mut self.differential.epoch_interval, differential.get("epoch_interval"));
+            differential.finish();
+        }
+        set(&mut self.outputs, root.list("outputs"));
+        set(&mut self.rules, root.list("rules"));
+        self.remote = root.get("remote").or(self.remote.take());
+        root.finish();
+        errors
+    }
+}
+
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+fn section_names() -> String {
+    Section::ALL.iter().map(|section| section.name()).collect::<Vec<_>>().join(", ")
+}
+
+/// Takes typed values out of one TOML table. Type errors and keys left over
+/// by `finish()` are added to a shared error list so that every mistake in a
+/// file is reported together.
+struct Fields<'a> {
+    table: toml::Table,
+    prefix: String,
+    errors: &'a mut Vec<String>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(table: toml::Table, prefix: &str, errors: &'a mut Vec<String>) -> Self {
+        Fields {
+            table,
+            prefix: prefix.to_string(),
+            errors,
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn get<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        let value = self.table.remove(key)?;
+        match value.try_into() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                let message = format!("{}: {}", self.path(key), e.message());
+                self.errors.push(message);
+                None
+            }
+        }
+    }
+
+    fn table(&mut self, key: &str) -> Option<Fields<'_>> {
+        let table = self.get::<toml::Table>(key)?;
+        let prefix = format!("{}.", self.path(key));
+        Some(Fields::new(table, &prefix, self.errors))
+    }
+
+    /// An array of tables such as `[[rules]]`; each entry is checked on its own
+    fn list<T: DeserializeOwned>(&mut self, key: &str) -> Option<Vec<T>> {
+        let entries = self.get::<Vec<toml::Value>>(key)?;
+        let mut items = Vec::new();
+        for (i, entry) in entries.into_iter().enumerate() {
+            match entry.try_into() {
+                Ok(item) => items.push(item),
+                Err(e) => {
+                    let message = format!("{}[{}]: {}", self.path(key), i, e.message());
+                    self.errors.push(message);
+                }
+            }
+        }
+        Some(items)
+    }
+
+    fn finish(self) {
+        for key in self.table.keys() {
+            self.errors.push(format!("{}{}: unknown setting", self.prefix, key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> (DaemonConfig, Vec<String>) {
+        let mut config = DaemonConfig::default();
+        let mut errors = config.read_table(text.parse().unwrap());
+        errors.extend(config.validate());
+        (config, errors)
+    }
+
+    #[test]
+    fn reads_rule_settings() {
+        let (config, errors) = read(
+            r#"
+            [[rules]]
+            name = "new-listener"
+            section = "listening_ports"
+            on = ["added"]
+            severity = "high"
+
+            [[rules]]
+            name = "ssh-service-change"
+            section = "services"
+            key_contains = "ssh"
+            "#,
+        );
+        assert_eq!(errors, Vec::<String>::new());
+        assert_eq!(config.rules.len(), 2);
+        assert_eq!(config.rules[0].on, vec![ChangeKind::Added]);
+        assert_eq!(config.rules[0].severity, Severity::High);
+        assert!(config.rules[1].on.is_empty());
+        assert_eq!(config.rules[1].severity, Severity::Medium);
+        assert_eq!(config.rules[1].key_contains.as_deref(), Some("ssh"));
+    }
+
+    #[test]
+    fn reports_every_rule_problem_at_once() {
+        let (_, errors) = read(
+            r#"
+            [[rules]]
+            name = "dup"
+            section = "processes"
+
+            [[rules]]
+            name = "dup"
+            section = "kernel_modules"
+
+            [[rules]]
+            name = "typo"
+            section = "users"
+            severty = "low"
+            "#,
+        );
+        assert_eq!(errors.len(), 3, "{:#?}", errors);
+        assert!(errors[0].starts_with("rules[2]: unknown field `severty`"), "{}", errors[0]);
+        assert_eq!(errors[1], "rules[1].name: \"dup\" is used by an earlier rule");
+        assert!(errors[2].starts_with("rules[1].section: unknown section 'kernel_modules'"), "{}", errors[2]);
+    }
+
+    #[test]
+    fn environment_overrides_the_file_and_the_command_line_overrides_both() {
+        // The only test that touches these variables, so parallel tests can't race on them
+        let dir = std::env::temp_dir().join(format!("agent-config-{}-layers", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("agent.toml");
+        std::fs::write(
+            &path,
+            r#"
+            interval = 60
+            [collection]
+            workers = 2
+            [osquery]
+            binary = "/file/osqueryi"
+            config_path = "/file/osquery.conf"
+            "#,
+        )
+        .unwrap();
+
+        std::env::set_var("AGENT_INTERVAL", "120");
+        std::env::set_var("OSQUERY_BINARY", "/env/osqueryi");
+        std::env::set_var("OSQUERY_DATABASE_PATH", "/env/osquery.db");
+        let layered = DaemonConfig::resolve(Some(&path), |config| {
+            config.osquery.binary = Some(PathBuf::from("/cli/osqueryi"));
+        });
+        std::env::set_var("AGENT_INTERVAL", "soon");
+        let bad_env = DaemonConfig::resolve(Some(&path), |_| {});
+        std::env::remove_var("AGENT_INTERVAL");
+        std::env::remove_var("OSQUERY_BINARY");
+        std::env::remove_var("OSQUERY_DATABASE_PATH");
+        let file_only = DaemonConfig::resolve(Some(&path), |_| {}).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(file_only.interval, 60);
+        assert_eq!(file_only.osquery.binary, Some(PathBuf::from("/file/osqueryi")));
+        assert_eq!(file_only.osquery.database_path, None);
+
+        let layered = layered.unwrap();
+        assert_eq!(layered.interval, 120);
+        assert_eq!(layered.collection.workers, 2);
+        assert_eq!(layered.osquery.binary, Some(PathBuf::from("/cli/osqueryi")));
+        assert_eq!(layered.osquery.config_path, Some(PathBuf::from("/file/osquery.conf")));
+        assert_eq!(layered.osquery.database_path, Some(PathBuf::from("/env/osquery.db")));
+
+        match bad_env {
+            Err(ConfigError::Invalid { errors }) => assert_eq!(
+                errors,
+                vec!["AGENT_INTERVAL: expected a number of seconds, got \"soon\"".to_string()]
+            ),
+            other => panic!("expected an invalid configuration, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_unknown_settings_in_every_section() {
+        let (_, errors) = read(
+            r#"
+            intervall = 10
+            [collection]
+            worker = 2
+            [osquery]
+            binery = "/usr/bin/osqueryi"
+            [state]
+            max_size = 5
+            [differential]
+            enable = true
+            "#,
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "collection.worker: unknown setting",
+                "osquery.binery: unknown setting",
+                "state.max_size: unknown setting",
+                "differential.enable: unknown setting",
+                "intervall: unknown setting",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_type_errors_from_several_sections_together() {
+        let dir = std::env::temp_dir().join(format!("agent-config-{}-types", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("agent.toml");
+        std::fs::write(
+            &path,
+            r#"
+            log_level = 3
+            [collection]
+            workers = "four"
+            [state]
+            max_mb = true
+            [[outputs]]
+            type = "file"
+            "#,
+        )
+        .unwrap();
+        let result = DaemonConfig::resolve(Some(&path), |_| {});
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let errors = match result {
+            Err(ConfigError::Invalid { errors }) => errors,
+            other => panic!("expected an invalid configuration, got {:?}", other),
+        };
+        assert_eq!(errors.len(), 4, "{:#?}", errors);
+        let prefix = format!("{}: ", path.display());
+        for (error, field) in errors.iter().zip(["log_level:", "collection.workers:", "state.max_mb:", "outputs[0]:"]) {
+            assert!(error.starts_with(&format!("{}{}", prefix, field)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_unusable_values() {
+        let (_, errors) = read(
+            r#"
+            interval = 0
+            log_level = "loud"
+            sections = ["users", "gpus"]
+            [collection]
+            workers = 0
+            [state]
+            max_mb = 0
+            [[outputs]]
+            type = "file"
+            path = ""
+            [[outputs]]
+            type = "rotating_file"
+            path = "/var/log/agent.jsonl"
+            max_mb = 0
+            rotate_after_secs = 0
+            "#,
+        );
+        assert_eq!(errors.len(), 8, "{:#?}", errors);
+        assert_eq!(errors[0], "interval: must be at least 1 second");
+        assert!(errors[1].starts_with("log_level: \"loud\" is not one of off, error"), "{}", errors[1]);
+        assert!(errors[2].starts_with("sections: unknown section 'gpus'"), "{}", errors[2]);
+        assert_eq!(errors[3], "collection.workers: must be at least 1");
+        assert_eq!(errors[4], "state.max_mb: must be at least 1");
+        assert_eq!(errors[5], "outputs[0].path: must not be empty");
+        assert_eq!(errors[6], "outputs[1].max_mb: must be at least 1");
+        assert_eq!(errors[7], "outputs[1].rotate_after_secs: must be at least 1");
+        assert_eq!(read("").1, Vec::<String>::new());
+    }
+}
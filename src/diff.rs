@@ -0,0 +1,418 @@
+// ============================================================================
+// Snapshot Diffing
+// ============================================================================
+//
+// Compares two `SystemInfo` collections section by section. Rows are matched
+// by a natural key (e.g. pid + start time for processes), so a row is either
+// added, removed, or modified with a list of changed fields. Rows are
+// compared through their JSON form, so field names match the snapshot output
+// (`details.active_state`, `install_time`, ...).
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::*;
+
+/// Changes between two collections, keyed by `Section::name()`. Sections
+/// without changes are left out.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SnapshotDiff {
+    pub sections: BTreeMap<String, SectionDiff>,
+    /// Sections that were not compared because they failed or were
+    /// unsupported in one of the collections
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_sections: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SectionDiff {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<Record>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<Record>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modified: Vec<ModifiedRecord>,
+}
+
+/// A whole row that appeared or disappeared
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Record {
+    pub key: String,
+    pub row: Value,
+}
+
+/// A row present in both collections whose fields differ
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModifiedRecord {
+    pub key: String,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FieldChange {
+    /// Dotted path of the field, e.g. `state` or `details.sub_state`
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    /// Total added, removed and modified rows across all sections
+    pub fn counts(&self) -> (usize, usize, usize) {
+        self.sections.values().fold((0, 0, 0), |(a, r, m), section| {
+            (a + section.added.len(), r + section.removed.len(), m + section.modified.len())
+        })
+    }
+}
+
+impl SectionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Identity of a row that stays the same while its other fields change
+pub trait NaturalKey {
+    fn natural_key(&self) -> String;
+}
+
+fn part<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(T::to_string).unwrap_or_default()
+}
+
+impl NaturalKey for OsVersion {
+    fn natural_key(&self) -> String {
+        Section::OsVersion.name().to_string()
+    }
+}
+
+impl NaturalKey for SystemDetails {
+    fn natural_key(&self) -> String {
+        Section::SystemInfo.name().to_string()
+    }
+}
+
+impl NaturalKey for ProcessInfo {
+    /// PIDs are reused, so the start time is part of the key
+    fn natural_key(&self) -> String {
+        format!("{}@{}", part(&self.pid), part(&self.start_time))
+    }
+}
+
+impl NaturalKey for NetworkConnection {
+    fn natural_key(&self) -> String {
+        format!(
+            "{} {}:{} -> {}:{}",
+            part(&self.pid),
+            part(&self.local_address),
+            part(&self.local_port),
+            part(&self.remote_address),
+            part(&self.remote_port)
+        )
+    }
+}
+
+impl NaturalKey for ListeningPort {
+    /// TCP and UDP may listen on the same address and port, so the protocol is included
+    fn natural_key(&self) -> String {
+        format!("{}:{}/{}", part(&self.address), part(&self.port), part(&self.protocol))
+    }
+}
+
+impl NaturalKey for UserInfo {
+    fn natural_key(&self) -> String {
+        part(&self.username)
+    }
+}
+
+impl NaturalKey for ServiceInfo {
+    fn natural_key(&self) -> String {
+        part(&self.name)
+    }
+}
+
+impl NaturalKey for ScheduledTask {
+    /// Source and name, so a changed schedule or command is a modification.
+    /// A cron entry's name is its crontab file, so its command tells the
+    /// entries of one file apart.
+    fn natural_key(&self) -> String {
+        let source = match &self.details {
+            ScheduledTaskDetails::Windows(_) => "windows",
+            ScheduledTaskDetails::Cron(_) => return format!("cron {} {}", part(&self.name), part(&self.command)),
+            ScheduledTaskDetails::SystemdTimer(_) => "systemd_timer",
+            ScheduledTaskDetails::Launchd(_) => "launchd",
+        };
+        format!("{} {}", source, part(&self.name))
+    }
+}
+
+impl NaturalKey for PackageInfo {
+    fn natural_key(&self) -> String {
+        format!("{}@{}", part(&self.name), part(&self.version))
+    }
+}
+
+impl NaturalKey for InterfaceAddress {
+    fn natural_key(&self) -> String {
+        format!("{} {}", part(&self.interface), part(&self.address))
+    }
+}
+
+/// Compares two collections section by section
+pub fn diff(old: &SystemInfo, new: &SystemInfo) -> SnapshotDiff {
+    let mut result = SnapshotDiff::default();
+    for section in Section::ALL {
+        if !comparable(old, section) || !comparable(new, section) {
+            result.skipped_sections.push(section.name().to_string());
+            continue;
+        }
+        let changes = diff_keyed(keyed_rows(old, section), keyed_rows(new, section));
+        if !changes.is_empty() {
+            result.sections.insert(section.name().to_string(), changes);
+        }
+    }
+    result
+}
+
+/// Rows of one section by natural key, as JSON
+pub fn keyed_rows(info: &SystemInfo, section: Section) -> BTreeMap<String, Value> {
+    match section {
+        Section::OsVersion => keyed(info.os_version.as_slice()),
+        Section::SystemInfo => keyed(info.system_info.as_slice()),
+        Section::Processes => keyed(&info.processes),
+        Section::NetworkConnections => keyed(&info.network_connections),
+        Section::ListeningPorts => keyed(&info.listening_ports),
+        Section::Users => keyed(&info.users),
+        Section::Services => keyed(&info.services),
+        Section::ScheduledTasks => keyed(&info.scheduled_tasks),
+        Section::InstalledPackages => keyed(&info.installed_packages),
+        Section::InterfaceAddresses => keyed(&info.interface_addresses),
+    }
+}
+
+/// A failed or unsupported section has no rows to compare against; treating
+/// it as empty would report every row as removed. Collections without a
+/// status (older snapshots) are compared.
+pub fn comparable(info: &SystemInfo, section: Section) -> bool {
+    info.status(section)
+        .is_none_or(|status| matches!(status.state, SectionState::Ok | SectionState::Empty))
+}
+
+/// Diffs two lists of rows by natural key
+pub fn diff_rows<T: NaturalKey + Serialize>(old: &[T], new: &[T]) -> SectionDiff {
+    diff_keyed(keyed(old), keyed(new))
+}
+
+/// Field-level differences between two JSON values, by dotted path
+pub fn compare_json(old: &Value, new: &Value) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    compare_values("", old, new, &mut changes);
+    changes
+}
+
+fn diff_keyed(old: BTreeMap<String, Value>, mut new: BTreeMap<String, Value>) -> SectionDiff {
+    let mut diff = SectionDiff::default();
+
+    for (key, old_row) in old {
+        match new.remove(&key) {
+            Some(new_row) => {
+                let mut changes = Vec::new();
+                compare_values("", &old_row, &new_row, &mut changes);
+                if !changes.is_empty() {
+                    diff.modified.push(ModifiedRecord { key, changes });
+                }
+            }
+            None => diff.removed.push(Record { key, row: old_row }),
+        }
+    }
+    diff.added = new.into_iter().map(|(key, row)| Record { key, row }).collect();
+    diff
+}
+
+/// Rows by natural key; repeated keys get a `#2`, `#3`... suffix in row order
+fn keyed<T: NaturalKey + Serialize>(rows: &[T]) -> BTreeMap<String, Value> {
+    let mut map = BTreeMap::new();
+    for row in rows {
+        let base = row.natural_key();
+        let mut key = base.clone();
+        let mut n = 2;
+        while map.contains_key(&key) {
+            key = format!("{}#{}", base, n);
+            n += 1;
+        }
+        map.insert(key, serde_json::to_value(row).unwrap_or(Value::Null));
+    }
+    map
+}
+
+/// Recurses into objects; anything else is compared as a whole
+fn compare_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<FieldChange>) {
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            let mut names: Vec<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                let field = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                compare_values(
+                    &field,
+                    old_fields.get(name).unwrap_or(&Value::Null),
+                    new_fields.get(name).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ if old != new => changes.push(FieldChange {
+            field: path.to_string(),
+            old: old.clone(),
+            new: new.clone(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rows<T: serde::de::DeserializeOwned>(rows: Value) -> Vec<T> {
+        serde_json::from_value(rows).unwrap()
+    }
+
+    fn fields(changes: &[FieldChange]) -> Vec<&str> {
+        changes.iter().map(|change| change.field.as_str()).collect()
+    }
+
+    #[test]
+    fn matches_rows_by_key_regardless_of_order() {
+        let old: Vec<ProcessInfo> = rows(json!([
+            {"pid": 1, "start_time": 100, "name": "init"},
+            {"pid": 2, "start_time": 200, "name": "sshd"},
+        ]));
+        let new: Vec<ProcessInfo> = rows(json!([
+            {"pid": 2, "start_time": 200, "name": "sshd"},
+            {"pid": 1, "start_time": 100, "name": "init"},
+        ]));
+        assert!(diff_rows(&old, &new).is_empty());
+
+        // A reused pid is a different process
+        let new: Vec<ProcessInfo> = rows(json!([
+            {"pid": 1, "start_time": 100, "name": "init"},
+            {"pid": 2, "start_time": 900, "name": "cron"},
+        ]));
+        let changes = diff_rows(&old, &new);
+        assert_eq!(changes.removed.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(), ["2@200"]);
+        assert_eq!(changes.added.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(), ["2@900"]);
+        assert!(changes.modified.is_empty());
+    }
+
+    #[test]
+    fn lists_the_changed_field_paths() {
+        let old = json!({"name": "sshd", "state": "running", "details": {"sub_state": "running", "pid": 10}});
+        let new = json!({"name": "sshd", "state": "stopped", "details": {"sub_state": "dead", "pid": 10}, "extra": 1});
+        let changes = compare_json(&old, &new);
+        assert_eq!(fields(&changes), ["details.sub_state", "extra", "state"]);
+        assert_eq!(
+            changes[0],
+            FieldChange {
+                field: "details.sub_state".to_string(),
+                old: json!("running"),
+                new: json!("dead"),
+            }
+        );
+        assert_eq!(changes[1].old, Value::Null);
+    }
+
+    #[test]
+    fn numbers_repeated_keys_in_row_order() {
+        let old: Vec<UserInfo> = rows(json!([{"username": "root", "uid": 0}, {"username": "root", "uid": 1}]));
+        let keys: Vec<String> = keyed(&old).into_keys().collect();
+        assert_eq!(keys, ["root", "root#2"]);
+
+        let new: Vec<UserInfo> = rows(json!([{"username": "root", "uid": 0}, {"username": "root", "uid": 2}]));
+        let changes = diff_rows(&old, &new);
+        assert_eq!(changes.modified.len(), 1);
+        assert_eq!(changes.modified[0].key, "root#2");
+        assert_eq!(fields(&changes.modified[0].changes), ["uid"]);
+    }
+
+    #[test]
+    fn skips_sections_that_failed_or_are_unsupported() {
+        let old = SystemInfo {
+            users: rows(json!([{"username": "root"}])),
+            services: rows(json!([{"name": "sshd", "details": {"kind": "systemd", "id": "sshd.service"}}])),
+            ..SystemInfo::default()
+        };
+        let mut new = SystemInfo::default();
+        for (section, state) in [(Section::Users, SectionState::Failed), (Section::Services, SectionState::Unsupported)] {
+            new.collection_status.insert(
+                section.name().to_string(),
+                SectionStatus {
+                    state,
+                    ..SectionStatus::default()
+                },
+            );
+        }
+
+        let changes = diff(&old, &new);
+        assert!(changes.is_empty(), "{:?}", changes.sections);
+        assert_eq!(changes.skipped_sections, ["users", "services"]);
+    }
+
+    #[test]
+    fn tells_tcp_and_udp_on_the_same_port_apart() {
+        let old: Vec<ListeningPort> = rows(json!([{"address": "0.0.0.0", "port": 53, "protocol": 6}]));
+        let new: Vec<ListeningPort> = rows(json!([
+            {"address": "0.0.0.0", "port": 53, "protocol": 6},
+            {"address": "0.0.0.0", "port": 53, "protocol": 17},
+        ]));
+        let changes = diff_rows(&old, &new);
+        assert_eq!(changes.added.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(), ["0.0.0.0:53/17"]);
+        assert!(changes.removed.is_empty() && changes.modified.is_empty());
+    }
+
+    #[test]
+    fn a_rescheduled_job_is_modified() {
+        let timer = |schedule: &str| {
+            let job: LaunchdSchedule = serde_json::from_value(json!({
+                "label": "com.example.backup",
+                "program": "/usr/local/bin/backup",
+                "start_interval": schedule,
+            }))
+            .unwrap();
+            ScheduledTask::from(ScheduledTaskDetails::Launchd(job))
+        };
+        let changes = diff_rows(&[timer("3600")], &[timer("600")]);
+        assert!(changes.added.is_empty() && changes.removed.is_empty());
+        assert_eq!(changes.modified[0].key, "launchd com.example.backup");
+        assert_eq!(fields(&changes.modified[0].changes), ["details.start_interval", "schedule"]);
+
+        // Entries of one crontab are told apart by their command
+        let cron = |minute: &str, command: &str| {
+            let entry: CronEntry = serde_json::from_value(json!({
+                "minute": minute,
+                "command": command,
+                "path": "/var/spool/cron/crontabs/alice",
+            }))
+            .unwrap();
+            ScheduledTask::from(ScheduledTaskDetails::Cron(entry))
+        };
+        let old = [cron("0", "backup"), cron("5", "cleanup")];
+        let new = [cron("30", "backup"), cron("5", "cleanup")];
+        let changes = diff_rows(&old, &new);
+        assert_eq!(changes.modified.len(), 1);
+        assert_eq!(changes.modified[0].key, "cron /var/spool/cron/crontabs/alice backup");
+        assert_eq!(fields(&changes.modified[0].changes), ["details.minute", "schedule"]);
+    }
+}
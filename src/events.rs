@@ -0,0 +1,358 @@
+// ============================================================================
+// Differential Event Stream
+// ============================================================================
+//
+// Turns successive snapshots into osquery-style differential results: rows
+// that appeared since the previous cycle are `added`, rows that went away are
+// `removed`, and a row whose fields changed is both. Every `epoch_interval`
+// cycles a new epoch starts with a full `snapshot` event per section, so a
+// consumer that missed events can resynchronize.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::diff::{comparable, keyed_rows};
+use crate::models::Section;
+use crate::snapshot::Snapshot;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventAction {
+    Added,
+    Removed,
+    /// Every row of the section; starts an epoch
+    Snapshot,
+}
+
+/// One differential result
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Event {
+    /// Section the row belongs to, e.g. `processes`
+    pub name: String,
+    pub host_identifier: String,
+    /// Collection time of the snapshot the event came from, Unix milliseconds
+    pub unix_time_ms: u64,
+    /// Increases every time a full snapshot is emitted
+    pub epoch: u64,
+    /// Cycles since the epoch started (0 for the snapshot itself)
+    pub counter: u64,
+    pub action: EventAction,
+    /// Natural key of the row (see `diff::NaturalKey`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// The row, for `added` and `removed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub columns: Option<Value>,
+    /// All rows, for `snapshot`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<Vec<Value>>,
+}
+
+/// Running totals, for logging
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct EventCounters {
+    pub cycles: u64,
+    pub snapshots: u64,
+    pub added: u64,
+    pub removed: u64,
+    /// Sections left out of a cycle because they failed or were unsupported
+    pub sections_skipped: u64,
+}
+
+/// Where a section's differential results left off. Persisted by the daemon's
+/// state store so a restart continues the epoch instead of starting over.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct QueryCursor {
+    pub epoch: u64,
+    pub counter: u64,
+    /// Last good rows of the section by natural key
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rows: BTreeMap<String, Value>,
+    /// `rows` were left out of the saved state because they equal the last
+    /// snapshot's rows of the section, which are read back in their place
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rows_in_snapshot: bool,
+}
+
+/// Remembers the previous cycle's rows and produces the events for the next one
+#[derive(Debug, Clone)]
+pub struct EventStream {
+    epoch_interval: u64,
+    epoch: u64,
+    counter: u64,
+    // Last good rows of each section; failed sections keep their old rows so
+    // the next successful cycle is compared with real data
+    previous: Option<BTreeMap<Section, BTreeMap<String, Value>>>,
+    counters: EventCounters,
+}
+
+impl EventStream {
+    /// `epoch_interval` is the number of cycles per epoch; 0 only emits the
+    /// initial snapshot
+    pub fn new(epoch_interval: u64) -> Self {
+        EventStream {
+            epoch_interval,
+            epoch: 0,
+            counter: 0,
+            previous: None,
+            counters: EventCounters::default(),
+        }
+    }
+
+    /// Continues from cursors saved by `cursors()`; without any, starts like `new()`
+    pub fn resume(epoch_interval: u64, cursors: &BTreeMap<String, QueryCursor>) -> Self {
+        let mut stream = EventStream::new(epoch_interval);
+        let Some(latest) = cursors.values().max_by_key(|cursor| (cursor.epoch, cursor.counter)) else {
+            return stream;
+        };
+        stream.epoch = latest.epoch;
+        stream.counter = latest.counter;
+        let previous = cursors
+            .iter()
+            .filter_map(|(name, cursor)| match name.parse::<Section>() {
+                Ok(section) => Some((section, cursor.rows.clone())),
+                Err(e) => {
+                    log::warn!("Ignoring saved cursor: {}", e);
+                    None
+                }
+            })
+            .collect();
+        stream.previous = Some(previous);
+        stream
+    }
+
+    /// Current position of every section, keyed by `Section::name()`
+    pub fn cursors(&self) -> BTreeMap<String, QueryCursor> {
+        self.previous
+            .iter()
+            .flatten()
+            .map(|(section, rows)| {
+                let cursor = QueryCursor {
+                    epoch: self.epoch,
+                    counter: self.counter,
+                    rows: rows.clone(),
+                    rows_in_snapshot: false,
+                };
+                (section.name().to_string(), cursor)
+            })
+            .collect()
+    }
+
+    pub fn set_epoch_interval(&mut self, epoch_interval: u64) {
+        self.epoch_interval = epoch_interval;
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    pub fn counters(&self) -> EventCounters {
+        self.counters
+    }
+
+    /// Events for `snapshot` relative to the previous call
+    pub fn process(&mut self, snapshot: &Snapshot) -> Vec<Event> {
+        let info = &snapshot.system_info;
+        self.counters.cycles += 1;
+
+        let full = match &self.previous {
+            None => true,
+            Some(_) => self.epoch_interval > 0 && self.counter + 1 >= self.epoch_interval,
+        };
+        if full {
+            self.epoch += 1;
+            self.counter = 0;
+            self.counters.snapshots += 1;
+        } else {
+            self.counter += 1;
+        }
+
+        let event = |name: &str, action, key, columns, rows| Event {
+            name: name.to_string(),
+            host_identifier: snapshot.host_id.clone(),
+            unix_time_ms: snapshot.finished_at_ms,
+            epoch: self.epoch,
+            counter: self.counter,
+            action,
+            key,
+            columns,
+            snapshot: rows,
+        };
+
+        let mut events = Vec::new();
+        let mut previous = self.previous.take().unwrap_or_default();
+        for section in Section::ALL {
+            if !comparable(info, section) {
+                self.counters.sections_skipped += 1;
+                continue;
+            }
+            let rows = keyed_rows(info, section);
+            let name = section.name();
+
+            if full {
+                let all = rows.values().cloned().collect();
+                events.push(event(name, EventAction::Snapshot, None, None, Some(all)));
+            } else {
+                let old = previous.remove(&section).unwrap_or_default();
+                for (key, row) in &old {
+                    if rows.get(key) != Some(row) {
+                        self.counters.removed += 1;
+                        events.push(event(name, EventAction::Removed, Some(key.clone()), Some(row.clone()), None));
+                    }
+                }
+                for (key, row) in &rows {
+                    if old.get(key) != Some(row) {
+                        self.counters.added += 1;
+                        events.push(event(name, EventAction::Added, Some(key.clone()), Some(row.clone()), None));
+                    }
+                }
+            }
+            previous.insert(section, rows);
+        }
+        self.previous = Some(previous);
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{SectionState, SectionStatus, SystemInfo};
+    use serde_json::json;
+
+    /// A collection with only users, or with the users section failed
+    fn snapshot(users: Option<Value>) -> Snapshot {
+        let mut info = SystemInfo::default();
+        match users {
+            Some(users) => info.users = serde_json::from_value(users).unwrap(),
+            None => {
+                let status = SectionStatus {
+                    state: SectionState::Failed,
+                    error: Some("osquery timed out".to_string()),
+                    ..SectionStatus::default()
+                };
+                info.collection_status.insert(Section::Users.name().to_string(), status);
+            }
+        }
+        Snapshot::new(info, 0, 1, None)
+    }
+
+    fn users(names: &[&str]) -> Option<Value> {
+        Some(names.iter().map(|name| json!({"username": name})).collect())
+    }
+
+    /// `(action, key)` of the events for the users section
+    fn user_events(events: &[Event]) -> Vec<(EventAction, Option<&str>)> {
+        events
+            .iter()
+            .filter(|event| event.name == "users")
+            .map(|event| (event.action, event.key.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn starts_with_a_full_epoch() {
+        let mut stream = EventStream::new(0);
+        let events = stream.process(&snapshot(users(&["root", "alice"])));
+        assert_eq!(events.len(), Section::ALL.len());
+        assert!(events.iter().all(|event| event.action == EventAction::Snapshot));
+        assert!(events.iter().all(|event| (event.epoch, event.counter) == (1, 0)));
+        let users = events.iter().find(|event| event.name == "users").unwrap();
+        assert_eq!(users.snapshot.as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn counts_cycles_within_the_epoch() {
+        let mut stream = EventStream::new(0);
+        stream.process(&snapshot(users(&["root"])));
+        for counter in 1..=3 {
+            assert!(stream.process(&snapshot(users(&["root"]))).is_empty());
+            assert_eq!((stream.epoch(), stream.counter()), (1, counter));
+        }
+    }
+
+    #[test]
+    fn reports_added_and_removed_rows() {
+        let mut stream = EventStream::new(0);
+        stream.process(&snapshot(users(&["root", "alice"])));
+        let events = stream.process(&snapshot(users(&["root", "bob"])));
+        assert_eq!(
+            user_events(&events),
+            [(EventAction::Removed, Some("alice")), (EventAction::Added, Some("bob"))]
+        );
+        assert!(events.iter().all(|event| (event.epoch, event.counter) == (1, 1)));
+        assert_eq!(events[0].columns.as_ref().unwrap()["username"], "alice");
+
+        // A changed row is removed with its old columns and added with the new ones
+        let events = stream.process(&snapshot(Some(json!([{"username": "root", "shell": "/bin/zsh"}, {"username": "bob"}]))));
+        assert_eq!(
+            user_events(&events),
+            [(EventAction::Removed, Some("root")), (EventAction::Added, Some("root"))]
+        );
+        assert_eq!(events[1].columns.as_ref().unwrap()["shell"], "/bin/zsh");
+        let counters = stream.counters();
+        assert_eq!((counters.cycles, counters.added, counters.removed), (3, 2, 2));
+    }
+
+    #[test]
+    fn starts_a_new_epoch_every_interval() {
+        let mut stream = EventStream::new(3);
+        let positions: Vec<(u64, u64, bool)> = (0..7)
+            .map(|_| {
+                let events = stream.process(&snapshot(users(&["root"])));
+                let full = events.iter().any(|event| event.action == EventAction::Snapshot);
+                (stream.epoch(), stream.counter(), full)
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                (1, 0, true),
+                (1, 1, false),
+                (1, 2, false),
+                (2, 0, true),
+                (2, 1, false),
+                (2, 2, false),
+                (3, 0, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_failed_section_keeps_its_previous_rows() {
+        let mut stream = EventStream::new(0);
+        stream.process(&snapshot(users(&["root", "alice"])));
+        assert!(user_events(&stream.process(&snapshot(None))).is_empty());
+        assert_eq!(stream.counters().sections_skipped, 1);
+        assert_eq!(stream.cursors()["users"].rows.len(), 2);
+
+        // Compared with the last good rows once it succeeds again
+        let events = stream.process(&snapshot(users(&["root", "alice", "bob"])));
+        assert_eq!(user_events(&events), [(EventAction::Added, Some("bob"))]);
+    }
+
+    #[test]
+    fn resumes_from_saved_cursors() {
+        let mut stream = EventStream::new(10);
+        stream.process(&snapshot(users(&["root"])));
+        stream.process(&snapshot(users(&["root", "alice"])));
+        let cursors: BTreeMap<String, QueryCursor> =
+            serde_json::from_str(&serde_json::to_string(&stream.cursors()).unwrap()).unwrap();
+
+        let mut resumed = EventStream::resume(10, &cursors);
+        assert_eq!((resumed.epoch(), resumed.counter()), (1, 1));
+        let events = resumed.process(&snapshot(users(&["alice"])));
+        assert_eq!(user_events(&events), [(EventAction::Removed, Some("root"))]);
+        assert!(events.iter().all(|event| (event.epoch, event.counter) == (1, 2)));
+
+        // Nothing saved: a fresh start
+        let mut fresh = EventStream::resume(10, &BTreeMap::new());
+        assert!(fresh.process(&snapshot(users(&["alice"]))).iter().all(|event| event.action == EventAction::Snapshot));
+    }
+}
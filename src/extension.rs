@@ -0,0 +1,597 @@
+// ============================================================================
+// OSquery Extension Socket Backend
+// ============================================================================
+//
+// Talks to a running osqueryd over its extension manager Unix socket
+// (`--extensions_socket`, `/var/osquery/osquery.em` by default) and issues
+// queries through the Thrift `ExtensionManager.query` call. osquery uses the
+// Thrift binary protocol (strict) over a buffered, unframed transport, which
+// is all this module implements.
+
+use std::io::{BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{Map, Value};
+
+use crate::osquery::{OsqueryError, QueryBackend};
+
+/// Default extension manager socket used by osqueryd
+pub const DEFAULT_EXTENSIONS_SOCKET: &str = "/var/osquery/osquery.em";
+
+// Thrift message types
+const MESSAGE_CALL: i32 = 1;
+const MESSAGE_REPLY: i32 = 2;
+const MESSAGE_EXCEPTION: i32 = 3;
+const VERSION_1: i32 = 0x8001_0000_u32 as i32;
+const VERSION_MASK: i32 = 0xffff_0000_u32 as i32;
+
+// Thrift field types
+const T_STOP: u8 = 0;
+const T_BOOL: u8 = 2;
+const T_BYTE: u8 = 3;
+const T_DOUBLE: u8 = 4;
+const T_I16: u8 = 6;
+const T_I32: u8 = 8;
+const T_I64: u8 = 10;
+const T_STRING: u8 = 11;
+const T_STRUCT: u8 = 12;
+const T_MAP: u8 = 13;
+const T_SET: u8 = 14;
+const T_LIST: u8 = 15;
+
+/// Upper bound on what a container length read off the wire may preallocate;
+/// larger containers grow as their elements actually arrive
+const MAX_PREALLOCATE: usize = 1024;
+
+/// Deepest nesting of containers `skip` follows; a reply nested deeper is
+/// rejected rather than recursing until the stack overflows
+const MAX_SKIP_DEPTH: usize = 64;
+
+/// Backend that queries osqueryd through its extension manager socket
+pub struct ExtensionSocketBackend {
+    socket_path: PathBuf,
+    timeout: Option<Duration>,
+    connection: Mutex<Option<Connection>>,
+}
+
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    seqid: i32,
+}
+
+impl ExtensionSocketBackend {
+    /// Creates a backend for the given socket path; connects lazily on first query
+    pub fn new(socket_path: impl AsRef<Path>) -> Self {
+        ExtensionSocketBackend {
+            socket_path: socket_path.as_ref().to_path_buf(),
+            timeout: None,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Sets a read/write timeout on the socket
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let stream = UnixStream::connect(&self.socket_path).with_context(|| {
+            format!(
+                "Failed to connect to osquery extension socket: {}",
+                self.socket_path.display()
+            )
+        })?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        let writer = stream.try_clone()?;
+        Ok(Connection {
+            reader: BufReader::new(stream),
+            writer,
+            seqid: 0,
+        })
+    }
+}
+
+impl QueryBackend for ExtensionSocketBackend {
+    fn execute(&self, query: &str) -> crate::osquery::Result<Vec<Value>> {
+        log::debug!(
+            "Executing '{}' via extension socket '{}'",
+            query,
+            self.socket_path.display()
+        );
+
+        let transport_error = |e: anyhow::Error| OsqueryError::Transport {
+            query: query.to_string(),
+            message: format!("{:#}", e),
+        };
+
+        let mut guard = self
+            .connection
+            .lock()
+            .map_err(|_| transport_error(anyhow!("extension socket connection lock poisoned")))?;
+
+        // A stale connection (osqueryd restarted) fails on first use, so retry once
+        // with a fresh one before giving up.
+        for attempt in 0..2 {
+            if guard.is_none() {
+                *guard = Some(self.connect().map_err(transport_error)?);
+            }
+            let connection = guard.as_mut().expect("connection was just established");
+            match connection.query(query) {
+                Ok(rows) => return Ok(rows),
+                Err(CallError::Status(message)) => {
+                    return Err(OsqueryError::from_osquery_message(query, &message));
+                }
+                Err(CallError::Transport(e)) => {
+                    *guard = None;
+                    if attempt == 1 {
+                        return Err(transport_error(e.context("osquery extension socket call failed")));
+                    }
+                }
+            }
+        }
+        unreachable!("retry loop always returns")
+    }
+}
+
+/// Distinguishes a broken connection (worth reconnecting) from an osquery-side error
+enum CallError {
+    Transport(anyhow::Error),
+    Status(String),
+}
+
+impl From<std::io::Error> for CallError {
+    fn from(e: std::io::Error) -> Self {
+        CallError::Transport(e.into())
+    }
+}
+
+impl Connection {
+    fn query(&mut self, sql: &str) -> std::result::Result<Vec<Value>, CallError> {
+        self.seqid = self.seqid.wrapping_add(1);
+        self.writer.write_all(&encode_query_call(sql, self.seqid))?;
+        self.writer.flush()?;
+
+        let mut protocol = BinaryReader { inner: &mut self.reader };
+        let response = protocol.read_query_reply(self.seqid).map_err(CallError::Transport)?;
+        match response {
+            Reply::Rows(rows) => Ok(rows),
+            Reply::Status { code, message } => {
+                Err(CallError::Status(format!("status {}: {}", code, message)))
+            }
+            Reply::Exception(message) => Err(CallError::Status(format!(
+                "extension manager raised an exception: {}",
+                message
+            ))),
+        }
+    }
+}
+
+/// Encodes `ExtensionManager.query(1: string sql)` as a strict binary-protocol call
+fn encode_query_call(sql: &str, seqid: i32) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(sql.len() + 32);
+    buf.extend_from_slice(&(VERSION_1 | MESSAGE_CALL).to_be_bytes());
+    write_string(&mut buf, "query");
+    buf.extend_from_slice(&seqid.to_be_bytes());
+
+    // query_args struct
+    buf.push(T_STRING);
+    buf.extend_from_slice(&1i16.to_be_bytes());
+    write_string(&mut buf, sql);
+    buf.push(T_STOP);
+    buf
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as i32).to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+enum Reply {
+    Rows(Vec<Value>),
+    Status { code: i32, message: String },
+    Exception(String),
+}
+
+struct BinaryReader<'a, R: Read> {
+    inner: &'a mut R,
+}
+
+impl<R: Read> BinaryReader<'_, R> {
+    fn read_query_reply(&mut self, seqid: i32) -> Result<Reply> {
+        let header = self.read_i32()?;
+        if header & VERSION_MASK != VERSION_1 {
+            bail!("unexpected Thrift message header: {:#010x}", header);
+        }
+        let name = self.read_string()?;
+        let reply_seqid = self.read_i32()?;
+        if name != "query" {
+            bail!("unexpected Thrift reply for method '{}'", name);
+        }
+        if reply_seqid != seqid {
+            bail!("Thrift reply has sequence id {}, expected {}", reply_seqid, seqid);
+        }
+
+        match header & 0xff {
+            MESSAGE_REPLY => self.read_query_result(),
+            MESSAGE_EXCEPTION => Ok(Reply::Exception(self.read_application_exception()?)),
+            other => bail!("unexpected Thrift message type {}", other),
+        }
+    }
+
+    /// Reads `query_result { 0: ExtensionResponse success }`
+    fn read_query_result(&mut self) -> Result<Reply> {
+        let mut reply = None;
+        loop {
+            let (field_type, id) = match self.read_field_header()? {
+                Some(header) => header,
+                None => break,
+            };
+            if id == 0 && field_type == T_STRUCT {
+                reply = Some(self.read_extension_response()?);
+            } else {
+                self.skip(field_type, 0)?;
+            }
+        }
+        reply.ok_or_else(|| anyhow!("Thrift reply did not contain a query result"))
+    }
+
+    /// Reads `ExtensionResponse { 1: ExtensionStatus status, 2: list<map<string,string>> response }`
+    fn read_extension_response(&mut self) -> Result<Reply> {
+        let mut status = (0, String::new());
+        let mut rows = Vec::new();
+        while let Some((field_type, id)) = self.read_field_header()? {
+            match (id, field_type) {
+                (1, T_STRUCT) => status = self.read_extension_status()?,
+                (2, T_LIST) => rows = self.read_rows()?,
+                _ => self.skip(field_type, 0)?,
+            }
+        }
+
+        let (code, message) = status;
+        if code != 0 {
+            return Ok(Reply::Status { code, message });
+        }
+        Ok(Reply::Rows(rows))
+    }
+
+    /// Reads `ExtensionStatus { 1: i32 code, 2: string message, 3: i64 uuid }`
+    fn read_extension_status(&mut self) -> Result<(i32, String)> {
+        let mut code = 0;
+        let mut message = String::new();
+        while let Some((field_type, id)) = self.read_field_header()? {
+            match (id, field_type) {
+                (1, T_I32) => code = self.read_i32()?,
+                (2, T_STRING) => message = self.read_string()?,
+                _ => self.skip(field_type, 0)?,
+            }
+        }
+        Ok((code, message))
+    }
+
+    fn read_rows(&mut self) -> Result<Vec<Value>> {
+        let (element_type, len) = self.read_list_header()?;
+        if element_type != T_MAP {
+            bail!("expected list<map<string,string>>, got element type {}", element_type);
+        }
+        let mut rows = Vec::with_capacity(len.min(MAX_PREALLOCATE));
+        for _ in 0..len {
+            let key_type = self.read_u8()?;
+            let value_type = self.read_u8()?;
+            let size = self.read_size()?;
+            if key_type != T_STRING || value_type != T_STRING {
+                bail!("expected map<string,string> row, got {}/{}", key_type, value_type);
+            }
+            let mut row = Map::with_capacity(size.min(MAX_PREALLOCATE));
+            for _ in 0..size {
+                let key = self.read_string()?;
+                let value = self.read_string()?;
+                row.insert(key, Value::String(value));
+            }
+            rows.push(Value::Object(row));
+        }
+        Ok(rows)
+    }
+
+    /// Reads `TApplicationException { 1: string message, 2: i32 type }`
+    fn read_application_exception(&mut self) -> Result<String> {
+        let mut message = String::from("unknown error");
+        while let Some((field_type, id)) = self.read_field_header()? {
+            match (id, field_type) {
+                (1, T_STRING) => message = self.read_string()?,
+                _ => self.skip(field_type, 0)?,
+            }
+        }
+        Ok(message)
+    }
+
+    fn read_field_header(&mut self) -> Result<Option<(u8, i16)>> {
+        let field_type = self.read_u8()?;
+        if field_type == T_STOP {
+            return Ok(None);
+        }
+        Ok(Some((field_type, self.read_i16()?)))
+    }
+
+    fn read_list_header(&mut self) -> Result<(u8, usize)> {
+        let element_type = self.read_u8()?;
+        Ok((element_type, self.read_size()?))
+    }
+
+    /// Skips a value of an unused field; `depth` counts the containers it is in
+    fn skip(&mut self, field_type: u8, depth: usize) -> Result<()> {
+        if depth > MAX_SKIP_DEPTH {
+            bail!("Thrift value nested more than {} levels deep", MAX_SKIP_DEPTH);
+        }
+        match field_type {
+            T_BOOL | T_BYTE => {
+                self.read_u8()?;
+            }
+            T_I16 => {
+                self.read_i16()?;
+            }
+            T_I32 => {
+                self.read_i32()?;
+            }
+            T_DOUBLE | T_I64 => {
+                self.read_bytes(8)?;
+            }
+            T_STRING => {
+                let len = self.read_size()?;
+                self.read_bytes(len)?;
+            }
+            T_STRUCT => {
+                while let Some((inner, _)) = self.read_field_header()? {
+                    self.skip(inner, depth + 1)?;
+                }
+            }
+            T_MAP => {
+                let key_type = self.read_u8()?;
+                let value_type = self.read_u8()?;
+                for _ in 0..self.read_size()? {
+                    self.skip(key_type, depth + 1)?;
+                    self.skip(value_type, depth + 1)?;
+                }
+            }
+            T_SET | T_LIST => {
+                let (element_type, len) = self.read_list_header()?;
+                for _ in 0..len {
+                    self.skip(element_type, depth + 1)?;
+                }
+            }
+            other => bail!("cannot skip unknown Thrift type {}", other),
+        }
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_i16(&mut self) -> Result<i16> {
+        let mut buf = [0u8; 2];
+        self.inner.read_exact(&mut buf)?;
+        Ok(i16::from_be_bytes(buf))
+    }
+
+    fn read_i32(&mut self) -> Result<i32> {
+        let mut buf = [0u8; 4];
+        self.inner.read_exact(&mut buf)?;
+        Ok(i32::from_be_bytes(buf))
+    }
+
+    fn read_size(&mut self) -> Result<usize> {
+        let size = self.read_i32()?;
+        usize::try_from(size).map_err(|_| anyhow!("negative Thrift container size {}", size))
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_size()?;
+        let bytes = self.read_bytes(len)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Reads as the bytes arrive, so a bogus length fails on EOF instead of
+    /// allocating it up front
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(len.min(MAX_PREALLOCATE));
+        (&mut *self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            bail!("Thrift frame truncated: expected {} bytes, got {}", len, buf.len());
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread::JoinHandle;
+
+    /// Serves one canned reply per connection, built from the call's seqid
+    fn serve(name: &str, replies: Vec<fn(i32) -> Vec<u8>>) -> (PathBuf, JoinHandle<Vec<String>>) {
+        let path = std::env::temp_dir().join(format!("agent-extension-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut queries = Vec::new();
+            for reply in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let (seqid, sql) = read_call(&mut stream);
+                queries.push(sql);
+                // The client may hang up before reading a bad reply to the end
+                let _ = stream.write_all(&reply(seqid));
+            }
+            queries
+        });
+        (path, handle)
+    }
+
+    fn read_call(stream: &mut UnixStream) -> (i32, String) {
+        let mut reader = BinaryReader { inner: stream };
+        assert_eq!(reader.read_i32().unwrap(), VERSION_1 | MESSAGE_CALL);
+        assert_eq!(reader.read_string().unwrap(), "query");
+        let seqid = reader.read_i32().unwrap();
+        assert_eq!(reader.read_field_header().unwrap(), Some((T_STRING, 1)));
+        let sql = reader.read_string().unwrap();
+        assert_eq!(reader.read_field_header().unwrap(), None);
+        (seqid, sql)
+    }
+
+    fn reply(seqid: i32, code: i32, message: &str, rows: &[&[(&str, &str)]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(VERSION_1 | MESSAGE_REPLY).to_be_bytes());
+        write_string(&mut buf, "query");
+        buf.extend_from_slice(&seqid.to_be_bytes());
+
+        // query_result.success: ExtensionResponse
+        buf.push(T_STRUCT);
+        buf.extend_from_slice(&0i16.to_be_bytes());
+
+        buf.push(T_STRUCT);
+        buf.extend_from_slice(&1i16.to_be_bytes());
+        buf.push(T_I32);
+        buf.extend_from_slice(&1i16.to_be_bytes());
+        buf.extend_from_slice(&code.to_be_bytes());
+        buf.push(T_STRING);
+        buf.extend_from_slice(&2i16.to_be_bytes());
+        write_string(&mut buf, message);
+        buf.push(T_STOP);
+
+        buf.push(T_LIST);
+        buf.extend_from_slice(&2i16.to_be_bytes());
+        buf.push(T_MAP);
+        buf.extend_from_slice(&(rows.len() as i32).to_be_bytes());
+        for row in rows {
+            buf.push(T_STRING);
+            buf.push(T_STRING);
+            buf.extend_from_slice(&(row.len() as i32).to_be_bytes());
+            for (key, value) in row.iter() {
+                write_string(&mut buf, key);
+                write_string(&mut buf, value);
+            }
+        }
+        buf.push(T_STOP);
+        buf.push(T_STOP);
+        buf
+    }
+
+    #[test]
+    fn query_returns_rows() {
+        let (path, server) = serve(
+            "rows",
+            vec![|seqid| reply(seqid, 0, "OK", &[&[("name", "init"), ("pid", "1")], &[("name", "sshd"), ("pid", "812")]])],
+        );
+        let rows = ExtensionSocketBackend::new(&path)
+            .with_timeout(Duration::from_secs(5))
+            .execute("SELECT name, pid FROM processes")
+            .unwrap();
+
+        assert_eq!(server.join().unwrap(), vec!["SELECT name, pid FROM processes"]);
+        assert_eq!(
+            rows,
+            vec![
+                serde_json::json!({"name": "init", "pid": "1"}),
+                serde_json::json!({"name": "sshd", "pid": "812"}),
+            ]
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn status_code_is_a_query_error() {
+        let (path, server) = serve("status", vec![|seqid| reply(seqid, 1, "no such table: bogus", &[])]);
+        let error = ExtensionSocketBackend::new(&path)
+            .with_timeout(Duration::from_secs(5))
+            .execute("SELECT * FROM bogus")
+            .unwrap_err();
+
+        server.join().unwrap();
+        match error {
+            OsqueryError::UnsupportedTable { table, .. } => assert_eq!(table, "bogus"),
+            other => panic!("expected UnsupportedTable, got {:?}", other),
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn truncated_frame_is_a_transport_error() {
+        fn truncated(seqid: i32) -> Vec<u8> {
+            let mut frame = reply(seqid, 0, "OK", &[&[("name", "init")]]);
+            frame.truncate(frame.len() - 6);
+            frame
+        }
+        // The backend reconnects once, so both connections get a short frame
+        let (path, server) = serve("truncated", vec![truncated, truncated]);
+        let error = ExtensionSocketBackend::new(&path)
+            .with_timeout(Duration::from_secs(5))
+            .execute("SELECT name FROM processes")
+            .unwrap_err();
+
+        assert_eq!(server.join().unwrap().len(), 2);
+        assert!(matches!(error, OsqueryError::Transport { .. }), "{:?}", error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn mismatched_seqid_is_rejected() {
+        let (path, server) = serve(
+            "seqid",
+            vec![|seqid| reply(seqid + 7, 0, "OK", &[]), |seqid| reply(seqid + 7, 0, "OK", &[])],
+        );
+        let error = ExtensionSocketBackend::new(&path)
+            .with_timeout(Duration::from_secs(5))
+            .execute("SELECT 1")
+            .unwrap_err();
+
+        server.join().unwrap();
+        assert!(error.to_string().contains("sequence id"), "{}", error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn deeply_nested_reply_is_rejected() {
+        /// An unknown field holding structs nested 100,000 levels deep
+        fn nested(seqid: i32) -> Vec<u8> {
+            let mut frame = Vec::new();
+            frame.extend_from_slice(&(VERSION_1 | MESSAGE_REPLY).to_be_bytes());
+            write_string(&mut frame, "query");
+            frame.extend_from_slice(&seqid.to_be_bytes());
+            for _ in 0..100_000 {
+                frame.push(T_STRUCT);
+                frame.extend_from_slice(&9i16.to_be_bytes());
+            }
+            frame
+        }
+        let (path, server) = serve("nested", vec![nested, nested]);
+        let error = ExtensionSocketBackend::new(&path)
+            .with_timeout(Duration::from_secs(5))
+            .execute("SELECT 1")
+            .unwrap_err();
+
+        server.join().unwrap();
+        assert!(error.to_string().contains("nested more than 64 levels"), "{}", error);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn huge_length_fails_without_allocating_it() {
+        let mut frame: &[u8] = &[0x7f, 0xff, 0xff, 0xff, b'x'];
+        let mut reader = BinaryReader { inner: &mut frame };
+        let error = reader.read_string().unwrap_err();
+        assert!(error.to_string().contains("truncated"), "{}", error);
+    }
+}
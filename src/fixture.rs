@@ -0,0 +1,187 @@
+// ============================================================================
+// Recorded Fixture Backends
+// ============================================================================
+//
+// `RecordingBackend` wraps another backend and writes every query and its
+// result to a fixture directory. `ReplayBackend` loads such a directory and
+// answers queries by their text, so `Agent::collect_system_info` can run
+// deterministically without osquery installed.
+//
+// Each fixture is one JSON file:
+//   { "query": "SELECT * FROM os_version;", "rows": [ ... ] }
+// or, for a query that failed when it was recorded:
+//   { "query": "SELECT * FROM portage_packages;", "error": "..." }
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::osquery::{OsqueryError, QueryBackend};
+
+/// A single recorded query result
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Fixture {
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Backend that forwards to another backend and records every result to disk
+pub struct RecordingBackend<B: QueryBackend> {
+    inner: B,
+    dir: PathBuf,
+    // query text -> file stem, so repeated queries overwrite their own fixture
+    files: Mutex<HashMap<String, String>>,
+}
+
+impl<B: QueryBackend> RecordingBackend<B> {
+    /// Creates the fixture directory if needed and records into it
+    pub fn new(inner: B, dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create fixture directory: {}", dir.display()))?;
+        Ok(RecordingBackend {
+            inner,
+            dir,
+            files: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn record(&self, fixture: &Fixture) -> Result<()> {
+        let stem = {
+            let mut files = self
+                .files
+                .lock()
+                .map_err(|_| anyhow!("fixture index lock poisoned"))?;
+            if let Some(stem) = files.get(&fixture.query) {
+                stem.clone()
+            } else {
+                let base = fixture_stem(&fixture.query);
+                let mut stem = base.clone();
+                let mut n = 2;
+                while files.values().any(|s| *s == stem) {
+                    stem = format!("{}_{}", base, n);
+                    n += 1;
+                }
+                files.insert(fixture.query.clone(), stem.clone());
+                stem
+            }
+        };
+
+        let path = self.dir.join(format!("{}.json", stem));
+        let json = serde_json::to_string_pretty(fixture)?;
+        fs::write(&path, json + "\n")
+            .with_context(|| format!("Failed to write fixture: {}", path.display()))
+    }
+}
+
+impl<B: QueryBackend> QueryBackend for RecordingBackend<B> {
+    fn execute(&self, query: &str) -> crate::osquery::Result<Vec<Value>> {
+        let result = self.inner.execute(query);
+        let fixture = match &result {
+            Ok(rows) => Fixture {
+                query: query.to_string(),
+                rows: Some(rows.clone()),
+                error: None,
+            },
+            Err(e) => Fixture {
+                query: query.to_string(),
+                rows: None,
+                error: Some(recorded_message(e)),
+            },
+        };
+        if let Err(e) = self.record(&fixture) {
+            log::warn!("Failed to record query '{}': {:#}", query, e);
+        }
+        result
+    }
+}
+
+/// Backend that answers queries from a directory of recorded fixtures
+#[derive(Debug, Clone)]
+pub struct ReplayBackend {
+    dir: PathBuf,
+    fixtures: HashMap<String, Fixture>,
+}
+
+impl ReplayBackend {
+    /// Loads every `*.json` fixture in `dir`
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let entries = fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read fixture directory: {}", dir.display()))?;
+
+        let mut fixtures = HashMap::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read fixture: {}", path.display()))?;
+            let fixture: Fixture = serde_json::from_str(&text)
+                .with_context(|| format!("Invalid fixture file: {}", path.display()))?;
+            fixtures.insert(fixture.query.trim().to_string(), fixture);
+        }
+
+        Ok(ReplayBackend { dir, fixtures })
+    }
+
+    /// Recorded query texts, for diagnostics
+    pub fn queries(&self) -> impl Iterator<Item = &str> {
+        self.fixtures.keys().map(String::as_str)
+    }
+}
+
+impl QueryBackend for ReplayBackend {
+    fn execute(&self, query: &str) -> crate::osquery::Result<Vec<Value>> {
+        let fixture = self
+            .fixtures
+            .get(query.trim())
+            .ok_or_else(|| OsqueryError::MissingFixture {
+                query: query.to_string(),
+                dir: self.dir.clone(),
+            })?;
+
+        match (&fixture.rows, &fixture.error) {
+            (_, Some(error)) => Err(OsqueryError::from_osquery_message(query, error)),
+            (Some(rows), None) => Ok(rows.clone()),
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+}
+
+/// What osquery itself said, so replay can classify the error the same way
+fn recorded_message(error: &OsqueryError) -> String {
+    match error {
+        OsqueryError::UnsupportedTable { stderr, .. } | OsqueryError::QueryFailed { stderr, .. } => {
+            stderr.clone()
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Turns `SELECT * FROM os_version;` into `select_from_os_version`
+fn fixture_stem(query: &str) -> String {
+    let mut stem = String::new();
+    for c in query.chars() {
+        if c.is_ascii_alphanumeric() {
+            stem.push(c.to_ascii_lowercase());
+        } else if !stem.is_empty() && !stem.ends_with('_') {
+            stem.push('_');
+        }
+    }
+    let stem = stem.trim_end_matches('_');
+    if stem.is_empty() {
+        "query".to_string()
+    } else {
+        stem.chars().take(80).collect()
+    }
+}
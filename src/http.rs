@@ -0,0 +1,464 @@
+// ============================================================================
+// HTTP(S) Batch Shipping
+// ============================================================================
+//
+// POSTs records to a collector endpoint in batches. A batch is sent as a
+// JSON-lines body (`application/x-ndjson`), optionally gzip-encoded.
+//
+// Response handling:
+// - 2xx: delivered
+// - 408, 429 and 5xx, or no response at all: retried with exponential
+//   backoff and full jitter (a `Retry-After` header is honored), then
+//   reported as `ShipError::Unavailable`
+// - any other 4xx: the collector refused the data and sending it again will
+//   not help, so it fails immediately with `ShipError::Rejected`
+//
+// With `spool_dir` set, records go to a disk spool first (see `spool.rs`) and
+// are sent from there in the background, retried until they are delivered.
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::spool::DEFAULT_MAX_SPOOL_BYTES;
+
+/// `[[outputs]]` entry with `type = "http"`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpOutput {
+    /// Collector endpoint, `http://` or `https://`
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>`; falls back to `AGENT_HTTP_TOKEN`
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "redact")]
+    pub bearer_token: Option<String>,
+    /// Extra request headers; values are redacted when serialized, since
+    /// they often carry credentials
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty", serialize_with = "redact_values")]
+    pub headers: BTreeMap<String, String>,
+    /// Records per request
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// gzip-encode request bodies
+    #[serde(default)]
+    pub gzip: bool,
+    /// Per-request timeout in seconds
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Retries after the first attempt for retryable failures
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// First backoff delay in milliseconds; doubles on every retry
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_initial_ms: u64,
+    /// Upper bound of a backoff delay in milliseconds
+    #[serde(default = "default_backoff_max_ms")]
+    pub backoff_max_ms: u64,
+    /// Queue records in an on-disk spool here and send them in the background
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spool_dir: Option<PathBuf>,
+    /// Cap on the spool size in MiB; the oldest records are dropped beyond it
+    #[serde(default = "default_spool_max_mb")]
+    pub spool_max_mb: u64,
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_backoff_max_ms() -> u64 {
+    30_000
+}
+
+fn default_spool_max_mb() -> u64 {
+    DEFAULT_MAX_SPOOL_BYTES / (1024 * 1024)
+}
+
+/// Keeps secrets out of `--check-config` output and reload logs
+pub(crate) fn redact<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// `redact()` for every value of a map, keeping the keys
+fn redact_values<S: Serializer>(map: &BTreeMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.keys().map(|key| (key, "<redacted>")))
+}
+
+impl HttpOutput {
+    /// Problems with the settings, prefixed with `path` (e.g. `outputs[2]`)
+    pub fn validate(&self, path: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            errors.push(format!("{}.url: \"{}\" is not an http:// or https:// URL", path, self.url));
+        }
+        if self.batch_size == 0 {
+            errors.push(format!("{}.batch_size: must be at least 1", path));
+        }
+        if self.timeout_secs == 0 {
+            errors.push(format!("{}.timeout_secs: must be at least 1", path));
+        }
+        if self.backoff_max_ms < self.backoff_initial_ms {
+            errors.push(format!("{}.backoff_max_ms: must not be less than backoff_initial_ms", path));
+        }
+        if self.spool_dir.is_some() && self.spool_max_mb == 0 {
+            errors.push(format!("{}.spool_max_mb: must be at least 1", path));
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ShipError {
+    /// The collector refused the batch; resending it will fail the same way
+    #[error("{url} rejected {records} record(s) with HTTP {status}: {body}")]
+    Rejected {
+        url: String,
+        status: u16,
+        records: usize,
+        body: String,
+    },
+
+    /// The collector could not be reached or kept failing
+    #[error("{url} unavailable after {attempts} attempt(s), {records} record(s) not delivered: {reason}")]
+    Unavailable {
+        url: String,
+        attempts: u32,
+        records: usize,
+        reason: String,
+        /// Delay the collector asked for with `Retry-After`
+        retry_after: Option<Duration>,
+    },
+}
+
+/// Sends batches of JSON lines to one endpoint
+pub struct HttpShipper {
+    config: HttpOutput,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl HttpShipper {
+    pub fn new(config: HttpOutput) -> HttpShipper {
+        let token = config
+            .bearer_token
+            .clone()
+            .or_else(|| std::env::var("AGENT_HTTP_TOKEN").ok().filter(|token| !token.is_empty()));
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+            .build();
+        HttpShipper { config, token, agent }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.config.url
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.config.batch_size.max(1)
+    }
+
+    /// Sends `lines` in batches of `batch_size`, stopping at the first batch
+    /// that cannot be delivered
+    pub fn send(&self, lines: &[String]) -> Result<(), ShipError> {
+        for batch in lines.chunks(self.batch_size()) {
+            self.send_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Sends one request, retrying retryable failures with backoff
+    pub fn send_batch(&self, lines: &[String]) -> Result<(), ShipError> {
+        let body = self.encode(lines).map_err(|e| self.unavailable(0, lines.len(), e.to_string(), None))?;
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let (reason, retry_after) = match self.post(&body, lines.len()) {
+                Err(ShipError::Unavailable { reason, retry_after, .. }) => (reason, retry_after),
+                result => return result,
+            };
+
+            if attempt > self.config.max_retries {
+                return Err(self.unavailable(attempt, lines.len(), reason, retry_after));
+            }
+            let delay = retry_after.unwrap_or_else(|| self.backoff(attempt));
+            log::warn!(
+                "Sending to {} failed ({}); retry {} of {} in {:.1}s",
+                self.config.url,
+                reason,
+                attempt,
+                self.config.max_retries,
+                delay.as_secs_f64()
+            );
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Sends one request without retrying, for callers that schedule their
+    /// own retries; blocks for at most `timeout_secs`
+    pub fn send_once(&self, lines: &[String]) -> Result<(), ShipError> {
+        let body = self.encode(lines).map_err(|e| self.unavailable(0, lines.len(), e.to_string(), None))?;
+        self.post(&body, lines.len())
+    }
+
+    fn post(&self, body: &[u8], records: usize) -> Result<(), ShipError> {
+        match self.request().send_bytes(body) {
+            Ok(_) => {
+                log::debug!("Sent {} record(s) to {}", records, self.config.url);
+                Ok(())
+            }
+            Err(ureq::Error::Status(status, response)) if !retryable(status) => {
+                let body = response.into_string().unwrap_or_default();
+                Err(ShipError::Rejected {
+                    url: self.config.url.clone(),
+                    status,
+                    records,
+                    body: body.chars().take(200).collect(),
+                })
+            }
+            Err(ureq::Error::Status(status, response)) => {
+                let retry_after = response
+                    .header("Retry-After")
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+                    .map(Duration::from_secs);
+                Err(self.unavailable(1, records, format!("HTTP {}", status), retry_after))
+            }
+            Err(ureq::Error::Transport(transport)) => {
+                Err(self.unavailable(1, records, transport_reason(&transport), None))
+            }
+        }
+    }
+
+    fn request(&self) -> ureq::Request {
+        let mut request = self
+            .agent
+            .post(&self.config.url)
+            .set("Content-Type", "application/x-ndjson");
+        if self.config.gzip {
+            request = request.set("Content-Encoding", "gzip");
+        }
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        for (name, value) in &self.config.headers {
+            request = request.set(name, value);
+        }
+        request
+    }
+
+    fn encode(&self, lines: &[String]) -> std::io::Result<Vec<u8>> {
+        let mut body = Vec::with_capacity(lines.iter().map(|line| line.len() + 1).sum());
+        for line in lines {
+            body.extend_from_slice(line.as_bytes());
+            body.push(b'\n');
+        }
+        if !self.config.gzip {
+            return Ok(body);
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&body)?;
+        encoder.finish()
+    }
+
+    /// Full jitter: a random delay up to `initial * 2^(attempt - 1)`, capped at the maximum
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .config
+            .backoff_initial_ms
+            .saturating_mul(1u64 << (attempt - 1).min(32))
+            .min(self.config.backoff_max_ms);
+        Duration::from_millis(random_u64() % (ceiling + 1))
+    }
+
+    fn unavailable(&self, attempts: u32, records: usize, reason: String, retry_after: Option<Duration>) -> ShipError {
+        ShipError::Unavailable {
+            url: self.config.url.clone(),
+            attempts,
+            records,
+            reason,
+            retry_after,
+        }
+    }
+}
+
+/// What went wrong without the URL, which `Transport`'s `Display` repeats
+pub(crate) fn transport_reason(transport: &ureq::Transport) -> String {
+    match transport.message() {
+        Some(message) => format!("{}: {}", transport.kind(), message),
+        None => transport.kind().to_string(),
+    }
+}
+
+/// Request timeouts, rate limiting and server errors may succeed later
+fn retryable(status: u16) -> bool {
+    status == 408 || status == 429 || status >= 500
+}
+
+/// Randomly seeded per call, which is all backoff jitter needs
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use std::time::Instant;
+
+    struct Request {
+        head: String,
+        body: Vec<u8>,
+    }
+
+    impl Request {
+        fn lines(&self) -> Vec<&str> {
+            std::str::from_utf8(&self.body).unwrap().lines().collect()
+        }
+    }
+
+    /// Answers one request per connection with each status line (plus any
+    /// headers) in turn, then stops listening
+    fn serve(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ingest", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let length = head
+                    .lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|n| n.trim().parse().unwrap()))
+                    .unwrap_or(0);
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push(Request { head, body });
+
+                let mut stream = reader.into_inner();
+                write!(stream, "{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", response).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn output(url: &str, settings: serde_json::Value) -> HttpOutput {
+        let mut config = serde_json::json!({"url": url, "bearer_token": "s3cret", "timeout_secs": 5});
+        config.as_object_mut().unwrap().extend(settings.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn records(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{{\"n\":{}}}", i)).collect()
+    }
+
+    #[test]
+    fn sends_batches_of_batch_size_with_auth_headers() {
+        let (url, server) = serve(vec!["HTTP/1.1 200 OK"; 3]);
+        let shipper = HttpShipper::new(output(&url, serde_json::json!({"batch_size": 2, "headers": {"X-Tenant": "t1"}})));
+        shipper.send(&records(5)).unwrap();
+
+        let requests = server.join().unwrap();
+        let sizes: Vec<usize> = requests.iter().map(|r| r.lines().len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert_eq!(requests[2].lines(), vec!["{\"n\":4}"]);
+        for request in &requests {
+            assert!(request.head.starts_with("POST /ingest "), "{}", request.head);
+            assert!(request.head.contains("Authorization: Bearer s3cret\r\n"), "{}", request.head);
+            assert!(request.head.contains("X-Tenant: t1\r\n"), "{}", request.head);
+            assert!(request.head.contains("Content-Type: application/x-ndjson\r\n"), "{}", request.head);
+        }
+    }
+
+    #[test]
+    fn retries_server_errors_and_rate_limits_then_succeeds() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1",
+            "HTTP/1.1 429 Too Many Requests",
+            "HTTP/1.1 200 OK",
+        ]);
+        let shipper = HttpShipper::new(output(&url, serde_json::json!({"backoff_initial_ms": 1, "backoff_max_ms": 1})));
+        let started = Instant::now();
+        shipper.send(&records(3)).unwrap();
+
+        // Retry-After overrides the 1ms backoff
+        assert!(started.elapsed() >= Duration::from_secs(1), "{:?}", started.elapsed());
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.body == requests[0].body));
+    }
+
+    #[test]
+    fn does_not_retry_other_client_errors() {
+        let (url, server) = serve(vec!["HTTP/1.1 400 Bad Request"]);
+        let shipper = HttpShipper::new(output(&url, serde_json::json!({"backoff_initial_ms": 1, "backoff_max_ms": 1})));
+        let error = shipper.send(&records(2)).unwrap_err();
+
+        assert_eq!(server.join().unwrap().len(), 1);
+        match error {
+            ShipError::Rejected { status, records, .. } => assert_eq!((status, records), (400, 2)),
+            other => panic!("expected Rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let (url, server) = serve(vec!["HTTP/1.1 500 Internal Server Error"; 3]);
+        let shipper = HttpShipper::new(output(
+            &url,
+            serde_json::json!({"max_retries": 2, "backoff_initial_ms": 1, "backoff_max_ms": 1}),
+        ));
+        let error = shipper.send(&records(1)).unwrap_err();
+
+        assert_eq!(server.join().unwrap().len(), 3);
+        match error {
+            ShipError::Unavailable { attempts, reason, .. } => assert_eq!((attempts, reason.as_str()), (3, "HTTP 500")),
+            other => panic!("expected Unavailable, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn gzip_bodies_decode_to_json_lines() {
+        let (url, server) = serve(vec!["HTTP/1.1 200 OK"]);
+        let shipper = HttpShipper::new(output(&url, serde_json::json!({"gzip": true})));
+        shipper.send(&records(2)).unwrap();
+
+        let request = server.join().unwrap().remove(0);
+        assert!(request.head.contains("Content-Encoding: gzip\r\n"), "{}", request.head);
+        let mut body = String::new();
+        flate2::read::GzDecoder::new(&request.body[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "{\"n\":0}\n{\"n\":1}\n");
+    }
+}
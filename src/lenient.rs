@@ -0,0 +1,133 @@
+// ============================================================================
+// Lenient Deserializers for OSquery Columns
+// ============================================================================
+//
+// osqueryi prints every column as a string ("1234", "" for NULL), while the
+// extension socket and hand-written JSON may use real numbers and booleans.
+// These helpers accept any of those forms for an `Option<T>` field:
+//
+//   #[serde(default, deserialize_with = "lenient::u64")]
+//   pub port: Option<u64>,
+//
+// Missing, null and empty-string values become `None`. So does a value that
+// cannot be read as the field's type (logged at debug level), so one odd
+// column never costs the whole row. Values serialize as plain JSON
+// numbers/booleans, which these helpers read back unchanged.
+
+use serde::de::Deserializer;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Unsigned integer from a number or numeric string
+pub fn u64<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(raw(deserializer)?.and_then(|value| {
+        let parsed = match &value {
+            Value::Number(n) => n.as_u64(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        parsed.or_else(|| malformed("an unsigned integer", &value))
+    }))
+}
+
+/// Signed integer from a number or numeric string
+pub fn i64<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(raw(deserializer)?.and_then(|value| {
+        let parsed = match &value {
+            Value::Number(n) => n.as_i64(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        parsed.or_else(|| malformed("an integer", &value))
+    }))
+}
+
+/// Boolean from `true`/`false`, `1`/`0` or their string forms (also `yes`/`no`)
+pub fn bool<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(raw(deserializer)?.and_then(|value| {
+        let parsed = match &value {
+            Value::Bool(b) => Some(*b),
+            Value::Number(n) => match n.as_i64() {
+                Some(0) => Some(false),
+                Some(1) => Some(true),
+                _ => None,
+            },
+            Value::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" => Some(true),
+                "0" | "false" | "no" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        };
+        parsed.or_else(|| malformed("a boolean", &value))
+    }))
+}
+
+/// Logs a value that could not be read and drops it
+fn malformed<T>(expected: &str, value: &Value) -> Option<T> {
+    log::debug!("Ignoring column value {}: expected {}", value, expected);
+    None
+}
+
+/// The column value, with null and blank strings mapped to `None`
+fn raw<'de, D>(deserializer: D) -> Result<Option<Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(s)) if s.trim().is_empty() => None,
+        Some(Value::Null) | None => None,
+        other => other,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    struct Row {
+        name: String,
+        #[serde(default, deserialize_with = "super::u64")]
+        pid: Option<u64>,
+        #[serde(default, deserialize_with = "super::i64")]
+        uid: Option<i64>,
+        #[serde(default, deserialize_with = "super::bool")]
+        enabled: Option<bool>,
+    }
+
+    #[test]
+    fn reads_numbers_strings_and_blanks() {
+        let row: Row = serde_json::from_value(json!({"name": "sshd", "pid": "812", "uid": -1, "enabled": "yes"})).unwrap();
+        assert_eq!((row.pid, row.uid, row.enabled), (Some(812), Some(-1), Some(true)));
+
+        let row: Row = serde_json::from_value(json!({"name": "sshd", "pid": "", "uid": null})).unwrap();
+        assert_eq!((row.pid, row.uid, row.enabled), (None, None, None));
+    }
+
+    #[test]
+    fn malformed_values_become_none_and_keep_the_row() {
+        let row: Row = serde_json::from_value(json!({
+            "name": "sshd",
+            "pid": "-3",
+            "uid": "12abc",
+            "enabled": 2,
+        }))
+        .unwrap();
+        assert_eq!(row.name, "sshd");
+        assert_eq!((row.pid, row.uid, row.enabled), (None, None, None));
+
+        let row: Row = serde_json::from_value(json!({"name": "sshd", "pid": [1], "uid": 1.5, "enabled": "maybe"})).unwrap();
+        assert_eq!((row.pid, row.uid, row.enabled), (None, None, None));
+    }
+}
@@ -1,8 +1,22 @@
 // Library crate that exports all modules
 
+pub mod lenient;
 pub mod models;
 pub mod osquery;
 pub mod agent;
+#[cfg(unix)]
+pub mod extension;
+pub mod fixture;
+pub mod snapshot;
+pub mod diff;
+pub mod events;
+pub mod state;
+pub mod config;
+pub mod output;
+pub mod rotation;
+pub mod http;
+pub mod spool;
+pub mod remote;
 
 // Re-export commonly used items
 pub use agent::{Agent, get_agent};
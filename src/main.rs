@@ -1,10 +1,12 @@
 // === Cross-Platform Security Agent - Test Version ===
 
 // Use the library crate
-use security_agent::models::SystemInfo;
-use security_agent::agent::get_agent;
+use security_agent::agent::{get_agent, CollectOptions};
+use security_agent::snapshot::Snapshot;
 
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
     println!("=== Security Agent OSquery Test ===\n");
     println!("Collecting system information via OSquery...\n");
     
@@ -13,7 +15,8 @@ fn main() {
     
     // Collect system info
     println!("Querying OSquery...");
-    let system_info = agent.collect_system_info();
+    let snapshot = Snapshot::collect(agent.as_ref(), &CollectOptions::default());
+    let system_info = &snapshot.system_info;
     
     // Display results
     println!("\n=== Collection Results ===\n");
@@ -36,18 +39,13 @@ fn main() {
         println!("  Hostname: {:?}", sys_info.hostname);
         println!("  CPU Brand: {:?}", sys_info.cpu_brand);
 
-        if let Some(mem_str) = &sys_info.physical_memory {
-            if let Ok(bytes) = mem_str.parse::<f64>() {
-                println!(
-                    "  Physical Memory: {} bytes ({:.2} GB)",
-                    mem_str,
-                    bytes / 1_000_000_000.0
-                );
-            } else {
-                println!("  Physical Memory: {} bytes (parse error)", mem_str);
-            }
-        } else {
-            println!("  Physical Memory: Not available");
+        match sys_info.physical_memory {
+            Some(bytes) => println!(
+                "  Physical Memory: {} bytes ({:.2} GB)",
+                bytes,
+                bytes as f64 / 1_000_000_000.0
+            ),
+            None => println!("  Physical Memory: Not available"),
         }
 
         println!(
@@ -71,9 +69,13 @@ fn main() {
     println!("  Interface Addresses: {}", system_info.interface_addresses.len());
     println!();
     
+    println!("Host ID: {} (from {:?})", snapshot.host_id, snapshot.host_id_source);
+    println!("Collected in {} ms", snapshot.duration_ms());
+    println!();
+
     // Print full JSON output
     println!("=== Full JSON Output ===\n");
-    match serde_json::to_string_pretty(&system_info) {
+    match serde_json::to_string_pretty(&snapshot) {
         Ok(json) => println!("{}", json),
         Err(e) => println!("Error serializing to JSON: {}", e),
     }
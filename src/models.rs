@@ -2,16 +2,23 @@
 // Data Structures for OSquery Tables
 // ============================================================================
 
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::lenient;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OsVersion {
     pub name: Option<String>,
     pub version: Option<String>,
-    // On Windows these are returned as strings, so keep them as String for compatibility
-    pub major: Option<String>,
-    pub minor: Option<String>,
-    pub patch: Option<String>,
+    // osqueryi prints numbers as strings (and "" for NULL); see `lenient`
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub major: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub minor: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub patch: Option<u64>,
     pub build: Option<String>,
     pub platform: Option<String>,
     #[serde(rename = "platform_like")]
@@ -30,16 +37,15 @@ pub struct SystemDetails {
     pub cpu_subtype: Option<String>,
     #[serde(rename = "cpu_brand")]
     pub cpu_brand: Option<String>,
-    // On Windows these core counts are strings in osquery JSON
-    #[serde(rename = "cpu_physical_cores")]
-    pub cpu_physical_cores: Option<String>,
-    #[serde(rename = "cpu_logical_cores")]
-    pub cpu_logical_cores: Option<String>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub cpu_physical_cores: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub cpu_logical_cores: Option<u64>,
     #[serde(rename = "cpu_microcode")]
     pub cpu_microcode: Option<String>,
-    // On Windows this is also a string representing bytes
-    #[serde(rename = "physical_memory")]
-    pub physical_memory: Option<String>,
+    /// Bytes of RAM
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub physical_memory: Option<u64>,
     #[serde(rename = "hardware_vendor")]
     pub hardware_vendor: Option<String>,
     #[serde(rename = "hardware_model")]
@@ -54,63 +60,78 @@ pub struct SystemDetails {
     pub local_hostname: Option<String>,
 }
 
-// NOTE: Windows `processes` table returns most numeric-looking fields as strings.
-// To maximize compatibility and avoid deserialization failures, we only model
-// a small, schema-tolerant subset as strings.
+// NOTE: the `processes` table has many more columns; only a small, stable
+// subset is modeled.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProcessInfo {
-    /// Process ID (often returned as a string by osquery on Windows)
-    pub pid: Option<String>,
+    /// Process ID
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub pid: Option<i64>,
     pub name: Option<String>,
     pub path: Option<String>,
     pub cmdline: Option<String>,
     pub state: Option<String>,
     /// Parent PID
-    pub parent: Option<String>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub parent: Option<i64>,
+    /// Unix time the process started; with `pid` it identifies a process
+    /// across snapshots even when PIDs are reused
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub start_time: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NetworkConnection {
-    // On Windows, OSquery returns these as strings
-    pub pid: Option<String>,
-    pub fd: Option<String>,
-    pub socket: Option<String>,
-    pub family: Option<String>,
-    pub protocol: Option<String>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub pid: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub fd: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub socket: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub family: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub protocol: Option<u64>,
     #[serde(rename = "local_address")]
     pub local_address: Option<String>,
-    #[serde(rename = "local_port")]
-    pub local_port: Option<String>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub local_port: Option<u64>,
     #[serde(rename = "remote_address")]
     pub remote_address: Option<String>,
-    #[serde(rename = "remote_port")]
-    pub remote_port: Option<String>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub remote_port: Option<u64>,
     pub state: Option<String>,
     pub path: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ListeningPort {
-    // On Windows, OSquery returns these as strings
-    pub pid: Option<String>,
-    pub port: Option<String>,
-    pub protocol: Option<String>,
-    pub family: Option<String>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub pid: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub port: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub protocol: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub family: Option<u64>,
     pub address: Option<String>,
-    pub fd: Option<String>,
-    pub socket: Option<String>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub fd: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub socket: Option<i64>,
     pub path: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserInfo {
-    // On Windows, OSquery returns these as strings
-    pub uid: Option<String>,
-    pub gid: Option<String>,
-    #[serde(rename = "uid_signed")]
-    pub uid_signed: Option<String>,
-    #[serde(rename = "gid_signed")]
-    pub gid_signed: Option<String>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub uid: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub gid: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub uid_signed: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub gid_signed: Option<i64>,
     pub username: Option<String>,
     pub description: Option<String>,
     pub directory: Option<String>,
@@ -121,22 +142,63 @@ pub struct UserInfo {
     // Note: is_hidden field may not always be present on Windows
 }
 
+/// A service, daemon or agent with a platform-neutral summary. The native
+/// osquery row is kept in `details`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ServiceInfo {
+    pub name: Option<String>,
+    pub state: Option<ServiceState>,
+    /// Starts automatically (at boot or login)
+    pub enabled: Option<bool>,
+    /// Executable the service runs, without arguments
+    pub binary_path: Option<String>,
+    /// Account the service runs as, when the platform reports it
+    pub run_as: Option<String>,
+    pub details: ServiceDetails,
+}
+
+/// Normalized runtime state of a service
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Running,
+    Stopped,
+    Starting,
+    Stopping,
+    Paused,
+    Failed,
+}
+
+/// Native service row for each platform's osquery table
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ServiceDetails {
+    /// Windows `services`
+    Windows(WindowsService),
+    /// Linux `systemd_units`
+    Systemd(SystemdUnit),
+    /// macOS `launchd`
+    Launchd(LaunchdJob),
+    /// `startup_items`, used where the native table is missing
+    StartupItem(StartupItem),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WindowsService {
     pub name: Option<String>,
     #[serde(rename = "service_type")]
     pub service_type: Option<String>,
     #[serde(rename = "display_name")]
     pub display_name: Option<String>,
     pub status: Option<String>,
-    // On Windows, OSquery returns pid as string
-    pub pid: Option<String>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub pid: Option<i64>,
     #[serde(rename = "start_type")]
     pub start_type: Option<String>,
-    #[serde(rename = "win32_exit_code")]
-    pub win32_exit_code: Option<String>,
-    #[serde(rename = "service_exit_code")]
-    pub service_exit_code: Option<String>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub win32_exit_code: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub service_exit_code: Option<i64>,
     pub path: Option<String>,
     #[serde(rename = "module_path")]
     pub module_path: Option<String>,
@@ -145,33 +207,428 @@ pub struct ServiceInfo {
     pub user_account: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SystemdUnit {
+    /// Unit name, e.g. `ssh.service`
+    pub id: Option<String>,
+    pub description: Option<String>,
+    pub load_state: Option<String>,
+    pub active_state: Option<String>,
+    pub sub_state: Option<String>,
+    /// Unit file the unit was loaded from
+    pub fragment_path: Option<String>,
+    pub source_path: Option<String>,
+    pub unit_file_state: Option<String>,
+    /// `User=` of the unit, if set
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LaunchdJob {
+    /// Path of the job's plist
+    pub path: Option<String>,
+    pub name: Option<String>,
+    pub label: Option<String>,
+    pub program: Option<String>,
+    pub program_arguments: Option<String>,
+    #[serde(default, deserialize_with = "lenient::bool")]
+    pub run_at_load: Option<bool>,
+    pub keep_alive: Option<String>,
+    #[serde(default, deserialize_with = "lenient::bool")]
+    pub disabled: Option<bool>,
+    pub username: Option<String>,
+    pub groupname: Option<String>,
+    /// Seconds between runs (`StartInterval`)
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub start_interval: Option<u64>,
+    pub stdout_path: Option<String>,
+    pub stderr_path: Option<String>,
+    pub working_directory: Option<String>,
+    pub process_type: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StartupItem {
+    pub name: Option<String>,
+    pub path: Option<String>,
+    pub args: Option<String>,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub source: Option<String>,
+    pub status: Option<String>,
+    pub username: Option<String>,
+}
+
+impl SystemdUnit {
+    /// Whether the unit starts on its own, from `unit_file_state`. `static`,
+    /// `indirect` and `generated` units only start when another unit pulls
+    /// them in, so they are `None`.
+    pub fn enabled(&self) -> Option<bool> {
+        match self.unit_file_state.as_deref()? {
+            "enabled" | "enabled-runtime" => Some(true),
+            "disabled" | "masked" | "masked-runtime" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl From<ServiceDetails> for ServiceInfo {
+    fn from(details: ServiceDetails) -> Self {
+        let (name, state, enabled, binary_path, run_as) = match &details {
+            ServiceDetails::Windows(service) => (
+                non_empty(&service.name),
+                service.status.as_deref().and_then(|status| match status {
+                    "RUNNING" => Some(ServiceState::Running),
+                    "STOPPED" => Some(ServiceState::Stopped),
+                    "START_PENDING" | "CONTINUE_PENDING" => Some(ServiceState::Starting),
+                    "STOP_PENDING" | "PAUSE_PENDING" => Some(ServiceState::Stopping),
+                    "PAUSED" => Some(ServiceState::Paused),
+                    _ => None,
+                }),
+                service.start_type.as_deref().and_then(|start| match start {
+                    "AUTO_START" | "BOOT_START" | "SYSTEM_START" => Some(true),
+                    "DEMAND_START" | "DISABLED" => Some(false),
+                    _ => None,
+                }),
+                non_empty(&service.path).map(|path| windows_executable(&path)),
+                non_empty(&service.user_account),
+            ),
+            ServiceDetails::Systemd(unit) => (
+                non_empty(&unit.id),
+                match (unit.active_state.as_deref(), unit.sub_state.as_deref()) {
+                    (Some("active"), Some("exited")) | (Some("inactive"), _) => Some(ServiceState::Stopped),
+                    (Some("active"), _) | (Some("reloading"), _) => Some(ServiceState::Running),
+                    (Some("activating"), _) => Some(ServiceState::Starting),
+                    (Some("deactivating"), _) => Some(ServiceState::Stopping),
+                    (Some("failed"), _) => Some(ServiceState::Failed),
+                    _ => None,
+                },
+                unit.enabled(),
+                // systemd_units does not report ExecStart
+                None,
+                non_empty(&unit.user),
+            ),
+            ServiceDetails::Launchd(job) => (
+                non_empty(&job.label).or_else(|| non_empty(&job.name)),
+                // launchd only reports the job definition, not whether it is running
+                None,
+                Some(job.disabled != Some(true)),
+                non_empty(&job.program).or_else(|| {
+                    non_empty(&job.program_arguments)
+                        .and_then(|args| args.split_whitespace().next().map(String::from))
+                }),
+                non_empty(&job.username),
+            ),
+            ServiceDetails::StartupItem(item) => (
+                non_empty(&item.name),
+                item.status.as_deref().and_then(|status| match status.to_ascii_lowercase().as_str() {
+                    "running" | "active" => Some(ServiceState::Running),
+                    "stopped" | "inactive" => Some(ServiceState::Stopped),
+                    _ => None,
+                }),
+                item.status.as_deref().and_then(|status| match status.to_ascii_lowercase().as_str() {
+                    "enabled" => Some(true),
+                    "disabled" => Some(false),
+                    _ => None,
+                }),
+                non_empty(&item.path),
+                non_empty(&item.username),
+            ),
+        };
+        ServiceInfo {
+            name,
+            state,
+            enabled,
+            binary_path,
+            run_as,
+            details,
+        }
+    }
+}
+
+/// osqueryi reports NULL text columns as ""
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(String::from)
+}
+
+/// Executable part of a Windows service command line, which may be quoted
+/// (`"C:\Program Files\x.exe" -k`) or not (`C:\Windows\system32\svchost.exe -k netsvcs`)
+fn windows_executable(command: &str) -> String {
+    let command = command.trim();
+    if let Some(rest) = command.strip_prefix('"') {
+        return rest.split('"').next().unwrap_or(rest).to_string();
+    }
+    match command.to_ascii_lowercase().find(".exe") {
+        Some(end) => command[..end + 4].to_string(),
+        None => command.split_whitespace().next().unwrap_or(command).to_string(),
+    }
+}
+
+/// A scheduled job reduced to what runs, when, and as whom. The native
+/// osquery row is kept in `details`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ScheduledTask {
+    pub name: Option<String>,
+    /// Command line, program or unit that is run
+    pub command: Option<String>,
+    /// When it runs: a cron expression (`*/5 * * * *`), a cron keyword
+    /// (`@reboot`) or `every <N>s`
+    pub schedule: Option<String>,
+    pub run_as: Option<String>,
+    pub enabled: Option<bool>,
+    pub details: ScheduledTaskDetails,
+}
+
+/// Native scheduled-job row for each source
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ScheduledTaskDetails {
+    /// Windows `scheduled_tasks`
+    Windows(WindowsScheduledTask),
+    /// `crontab` on Linux and macOS
+    Cron(CronEntry),
+    /// Linux `systemd_units` ending in `.timer`
+    SystemdTimer(SystemdUnit),
+    /// macOS `launchd` jobs with `StartInterval` or `StartCalendarInterval`
+    Launchd(LaunchdSchedule),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WindowsScheduledTask {
     pub name: Option<String>,
     pub action: Option<String>,
     pub path: Option<String>,
-    // On Windows, OSquery returns these as strings
-    pub enabled: Option<String>,
+    #[serde(default, deserialize_with = "lenient::bool")]
+    pub enabled: Option<bool>,
     pub state: Option<String>,
-    pub hidden: Option<String>,
-    #[serde(rename = "last_run_time")]
-    pub last_run_time: Option<String>,
-    #[serde(rename = "next_run_time")]
-    pub next_run_time: Option<String>,
+    #[serde(default, deserialize_with = "lenient::bool")]
+    pub hidden: Option<bool>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub last_run_time: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub next_run_time: Option<i64>,
     #[serde(rename = "last_run_message")]
     pub last_run_message: Option<String>,
     #[serde(rename = "last_run_code")]
     pub last_run_code: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CronEntry {
+    /// Cron keyword such as `@reboot`, used instead of the time fields
+    pub event: Option<String>,
+    pub minute: Option<String>,
+    pub hour: Option<String>,
+    pub day_of_month: Option<String>,
+    pub month: Option<String>,
+    pub day_of_week: Option<String>,
+    /// Rest of the line; in system crontabs this starts with the user
+    pub command: Option<String>,
+    /// Crontab file the entry came from
+    pub path: Option<String>,
+}
+
+/// A launchd job's schedule, from `launchd` joined with its plist's
+/// `StartCalendarInterval`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LaunchdSchedule {
+    pub label: Option<String>,
+    pub path: Option<String>,
+    pub program: Option<String>,
+    pub program_arguments: Option<String>,
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "lenient::bool")]
+    pub disabled: Option<bool>,
+    /// Seconds between runs (`StartInterval`)
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub start_interval: Option<u64>,
+    /// `StartCalendarInterval` entries, e.g. `Hour` -> `7`; keys of an array of
+    /// intervals are prefixed with their index (`0/Hour`)
+    #[serde(default)]
+    pub start_calendar_interval: BTreeMap<String, String>,
+}
+
+impl From<ScheduledTaskDetails> for ScheduledTask {
+    fn from(details: ScheduledTaskDetails) -> Self {
+        let (name, command, schedule, run_as, enabled) = match &details {
+            ScheduledTaskDetails::Windows(task) => (
+                non_empty(&task.name),
+                non_empty(&task.action),
+                // scheduled_tasks does not report triggers
+                None,
+                None,
+                task.enabled,
+            ),
+            ScheduledTaskDetails::Cron(entry) => {
+                let (run_as, command) = cron_user_and_command(entry);
+                let schedule = non_empty(&entry.event).or_else(|| {
+                    let fields = [
+                        &entry.minute,
+                        &entry.hour,
+                        &entry.day_of_month,
+                        &entry.month,
+                        &entry.day_of_week,
+                    ];
+                    let fields: Vec<String> = fields
+                        .iter()
+                        .map(|field| non_empty(field).unwrap_or_else(|| "*".to_string()))
+                        .collect();
+                    Some(fields.join(" "))
+                });
+                let name = non_empty(&entry.path);
+                (name, command, schedule, run_as, Some(true))
+            }
+            ScheduledTaskDetails::SystemdTimer(unit) => (
+                non_empty(&unit.id),
+                // A timer activates the service of the same name unless Unit= says otherwise
+                non_empty(&unit.id).map(|id| format!("{}.service", id.trim_end_matches(".timer"))),
+                // systemd_units does not report OnCalendar= or OnUnitActiveSec=
+                None,
+                non_empty(&unit.user),
+                unit.enabled(),
+            ),
+            ScheduledTaskDetails::Launchd(job) => (
+                non_empty(&job.label),
+                non_empty(&job.program_arguments).or_else(|| non_empty(&job.program)),
+                launchd_schedule(job),
+                non_empty(&job.username),
+                Some(job.disabled != Some(true)),
+            ),
+        };
+        ScheduledTask {
+            name,
+            command,
+            schedule,
+            run_as,
+            enabled,
+            details,
+        }
+    }
+}
+
+/// System crontabs (`/etc/crontab`, `/etc/cron.d/*`) have a user column before
+/// the command; user crontabs are named after their owner
+fn cron_user_and_command(entry: &CronEntry) -> (Option<String>, Option<String>) {
+    let command = non_empty(&entry.command);
+    let path = non_empty(&entry.path).unwrap_or_default();
+    if path == "/etc/crontab" || path.starts_with("/etc/cron.d/") {
+        if let Some(line) = command {
+            return match line.split_once(char::is_whitespace) {
+                Some((user, rest)) => (Some(user.to_string()), Some(rest.trim().to_string())),
+                None => (None, Some(line)),
+            };
+        }
+        return (None, None);
+    }
+    let owner = path.rsplit('/').next().filter(|owner| !owner.is_empty()).map(String::from);
+    (owner, command)
+}
+
+/// `every <N>s`, or calendar intervals written as cron expressions
+fn launchd_schedule(job: &LaunchdSchedule) -> Option<String> {
+    let mut schedules = Vec::new();
+    if let Some(interval) = job.start_interval {
+        schedules.push(format!("every {}s", interval));
+    }
+
+    // Group `0/Hour`-style keys by array index; plain keys form a single interval
+    let mut intervals: BTreeMap<&str, BTreeMap<&str, &str>> = BTreeMap::new();
+    for (key, value) in &job.start_calendar_interval {
+        let (index, field) = key.split_once('/').unwrap_or(("", key.as_str()));
+        intervals.entry(index).or_default().insert(field, value.as_str());
+    }
+    for fields in intervals.values() {
+        let field = |name| fields.get(name).copied().unwrap_or("*");
+        schedules.push(format!(
+            "{} {} {} {} {}",
+            field("Minute"),
+            field("Hour"),
+            field("Day"),
+            field("Month"),
+            field("Weekday")
+        ));
+    }
+
+    if schedules.is_empty() {
+        None
+    } else {
+        Some(schedules.join("; "))
+    }
+}
+
+/// An installed package with a normalized version and its package manager.
+/// The native osquery row is kept in `details`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PackageInfo {
     pub name: Option<String>,
+    pub ecosystem: PackageEcosystem,
+    /// Full version as the package manager compares it: `[epoch:]version[-release]`
     pub version: Option<String>,
+    pub epoch: Option<u64>,
     pub release: Option<String>,
+    pub arch: Option<String>,
+    /// Unix time the package was installed, when the package manager records it
+    pub install_time: Option<i64>,
+    /// Package URL (https://github.com/package-url/purl-spec)
+    pub purl: Option<String>,
+    pub details: PackageDetails,
+}
+
+/// Package manager a package was installed with
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageEcosystem {
+    Rpm,
+    Deb,
+    Portage,
+    Pkg,
+    Homebrew,
+    Macports,
+    Programs,
+}
+
+/// Native package row for each package manager's osquery table
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PackageDetails {
+    Rpm(RpmPackage),
+    Deb(DebPackage),
+    Portage(PortagePackage),
+    Pkg(PkgPackage),
+    Homebrew(HomebrewPackage),
+    Macports(MacportsPackage),
+    Programs(WindowsProgram),
+}
+
+/// Linux `rpm_packages`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RpmPackage {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub release: Option<String>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub epoch: Option<u64>,
+    pub arch: Option<String>,
     pub source: Option<String>,
-    pub size: Option<i64>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub size: Option<u64>,
     pub sha1: Option<String>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub install_time: Option<i64>,
+    pub vendor: Option<String>,
+    pub package_group: Option<String>,
+}
+
+/// Linux `deb_packages`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DebPackage {
+    pub name: Option<String>,
+    /// Full Debian version, e.g. `1:8.9p1-3ubuntu0.10`
+    pub version: Option<String>,
+    pub source: Option<String>,
+    /// Installed size in KiB
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub size: Option<u64>,
     pub arch: Option<String>,
     pub revision: Option<String>,
     pub status: Option<String>,
@@ -180,6 +637,268 @@ pub struct PackageInfo {
     pub priority: Option<String>,
 }
 
+/// Gentoo `portage_packages`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortagePackage {
+    /// Category and name, e.g. `dev-libs/openssl`
+    pub package: Option<String>,
+    pub version: Option<String>,
+    pub slot: Option<String>,
+    #[serde(default, deserialize_with = "lenient::i64")]
+    pub build_time: Option<i64>,
+    pub repository: Option<String>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub size: Option<u64>,
+    #[serde(default, deserialize_with = "lenient::bool")]
+    pub world: Option<bool>,
+}
+
+/// FreeBSD `pkg_packages`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PkgPackage {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    #[serde(default, deserialize_with = "lenient::u64")]
+    pub flatsize: Option<u64>,
+    pub arch: Option<String>,
+}
+
+/// macOS `homebrew_packages`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HomebrewPackage {
+    pub name: Option<String>,
+    pub path: Option<String>,
+    pub version: Option<String>,
+    /// `formula` or `cask`
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub prefix: Option<String>,
+}
+
+/// macOS `macports_packages`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MacportsPackage {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub platform: Option<String>,
+    pub variant: Option<String>,
+    pub state: Option<String>,
+    #[serde(default, deserialize_with = "lenient::bool")]
+    pub requested: Option<bool>,
+}
+
+/// Windows `programs`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WindowsProgram {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub install_location: Option<String>,
+    pub install_source: Option<String>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub uninstall_string: Option<String>,
+    /// `YYYYMMDD`
+    pub install_date: Option<String>,
+    pub identifying_number: Option<String>,
+}
+
+impl PackageInfo {
+    /// Normalizes a native row. `distro` is the `os_version` platform (e.g.
+    /// `ubuntu`), used as the purl namespace for rpm and deb packages.
+    pub fn new(details: PackageDetails, distro: Option<&str>) -> Self {
+        let mut package = PackageInfo {
+            name: None,
+            ecosystem: details.ecosystem(),
+            version: None,
+            epoch: None,
+            release: None,
+            arch: None,
+            install_time: None,
+            purl: None,
+            details,
+        };
+        match &package.details {
+            PackageDetails::Rpm(rpm) => {
+                package.name = non_empty(&rpm.name);
+                package.epoch = rpm.epoch.filter(|epoch| *epoch > 0);
+                package.release = non_empty(&rpm.release);
+                package.arch = non_empty(&rpm.arch);
+                package.install_time = rpm.install_time.filter(|time| *time > 0);
+                package.version = non_empty(&rpm.version).map(|version| {
+                    let mut full = version;
+                    if let Some(release) = &package.release {
+                        full = format!("{}-{}", full, release);
+                    }
+                    match package.epoch {
+                        Some(epoch) => format!("{}:{}", epoch, full),
+                        None => full,
+                    }
+                });
+            }
+            PackageDetails::Deb(deb) => {
+                package.name = non_empty(&deb.name);
+                package.arch = non_empty(&deb.arch);
+                package.version = non_empty(&deb.version);
+                if let Some(version) = &package.version {
+                    // [epoch:]upstream_version[-debian_revision]
+                    let rest = match version.split_once(':') {
+                        Some((epoch, rest)) => {
+                            package.epoch = epoch.parse().ok();
+                            rest
+                        }
+                        None => version.as_str(),
+                    };
+                    package.release = rest.rsplit_once('-').map(|(_, release)| release.to_string());
+                }
+            }
+            PackageDetails::Portage(portage) => {
+                // `package` is `category/name`
+                package.name = non_empty(&portage.package)
+                    .map(|full| full.rsplit('/').next().unwrap_or(&full).to_string());
+                package.version = non_empty(&portage.version);
+            }
+            PackageDetails::Pkg(pkg) => {
+                package.name = non_empty(&pkg.name);
+                package.version = non_empty(&pkg.version);
+                package.arch = non_empty(&pkg.arch);
+            }
+            PackageDetails::Homebrew(brew) => {
+                package.name = non_empty(&brew.name);
+                package.version = non_empty(&brew.version);
+            }
+            PackageDetails::Macports(port) => {
+                package.name = non_empty(&port.name);
+                package.version = non_empty(&port.version);
+            }
+            PackageDetails::Programs(program) => {
+                package.name = non_empty(&program.name);
+                package.version = non_empty(&program.version);
+                package.install_time = program.install_date.as_deref().and_then(parse_yyyymmdd);
+            }
+        }
+        package.purl = package_url(&package, distro);
+        package
+    }
+}
+
+impl PackageDetails {
+    pub fn ecosystem(&self) -> PackageEcosystem {
+        match self {
+            PackageDetails::Rpm(_) => PackageEcosystem::Rpm,
+            PackageDetails::Deb(_) => PackageEcosystem::Deb,
+            PackageDetails::Portage(_) => PackageEcosystem::Portage,
+            PackageDetails::Pkg(_) => PackageEcosystem::Pkg,
+            PackageDetails::Homebrew(_) => PackageEcosystem::Homebrew,
+            PackageDetails::Macports(_) => PackageEcosystem::Macports,
+            PackageDetails::Programs(_) => PackageEcosystem::Programs,
+        }
+    }
+}
+
+/// `pkg:<type>/<namespace>/<name>@<version>?<qualifiers>`. rpm and deb use
+/// their registered purl types with the distro as namespace, portage uses
+/// `ebuild` with the category, and the rest fall back to `generic`.
+fn package_url(package: &PackageInfo, distro: Option<&str>) -> Option<String> {
+    let name = package.name.as_deref()?;
+    let mut qualifiers = Vec::new();
+    let (kind, namespace, version) = match &package.details {
+        PackageDetails::Rpm(rpm) => {
+            if let Some(epoch) = package.epoch {
+                qualifiers.push(("epoch", epoch.to_string()));
+            }
+            // The epoch goes in a qualifier, so the version is version-release
+            let version = non_empty(&rpm.version).map(|version| match &package.release {
+                Some(release) => format!("{}-{}", version, release),
+                None => version,
+            });
+            ("rpm", distro.map(str::to_ascii_lowercase), version)
+        }
+        PackageDetails::Deb(_) => ("deb", distro.map(str::to_ascii_lowercase), package.version.clone()),
+        PackageDetails::Portage(portage) => (
+            "ebuild",
+            portage
+                .package
+                .as_deref()
+                .and_then(|full| full.rsplit_once('/'))
+                .map(|(category, _)| category.to_string()),
+            package.version.clone(),
+        ),
+        PackageDetails::Pkg(_) => ("generic", Some("freebsd".to_string()), package.version.clone()),
+        PackageDetails::Homebrew(_) => ("generic", Some("homebrew".to_string()), package.version.clone()),
+        PackageDetails::Macports(_) => ("generic", Some("macports".to_string()), package.version.clone()),
+        PackageDetails::Programs(program) => (
+            "generic",
+            non_empty(&program.publisher),
+            package.version.clone(),
+        ),
+    };
+    if let Some(arch) = &package.arch {
+        qualifiers.insert(0, ("arch", arch.clone()));
+    }
+
+    let mut purl = format!("pkg:{}/", kind);
+    if let Some(namespace) = namespace {
+        purl.push_str(&purl_encode(&namespace));
+        purl.push('/');
+    }
+    purl.push_str(&purl_encode(name));
+    if let Some(version) = version {
+        purl.push('@');
+        purl.push_str(&purl_encode(&version));
+    }
+    let qualifiers: Vec<String> = qualifiers
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, purl_encode(value)))
+        .collect();
+    if !qualifiers.is_empty() {
+        purl.push('?');
+        purl.push_str(&qualifiers.join("&"));
+    }
+    Some(purl)
+}
+
+/// Percent-encodes everything but unreserved characters
+fn purl_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b".-_~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Midnight UTC of a `YYYYMMDD` date as Unix time
+fn parse_yyyymmdd(date: &str) -> Option<i64> {
+    let date = date.trim();
+    if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let year: i64 = date[..4].parse().ok()?;
+    let month: i64 = date[4..6].parse().ok()?;
+    let day: i64 = date[6..].parse().ok()?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month) || !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+    // Days since 1970-01-01 (Howard Hinnant's days_from_civil)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some((era * 146_097 + doe - 719_468) * 86_400)
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InterfaceAddress {
     pub interface: Option<String>,
@@ -195,7 +914,7 @@ pub struct InterfaceAddress {
 }
 
 /// Comprehensive system information structure
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct SystemInfo {
     pub os_version: Option<OsVersion>,
     pub system_info: Option<SystemDetails>,
@@ -207,22 +926,402 @@ pub struct SystemInfo {
     pub scheduled_tasks: Vec<ScheduledTask>,
     pub installed_packages: Vec<PackageInfo>,
     pub interface_addresses: Vec<InterfaceAddress>,
+    /// How each section's collection went, keyed by `Section::name()`
+    #[serde(default)]
+    pub collection_status: BTreeMap<String, SectionStatus>,
 }
 
-impl Default for SystemInfo {
-    fn default() -> Self {
-        SystemInfo {
-            os_version: None,
-            system_info: None,
-            processes: Vec::new(),
-            network_connections: Vec::new(),
-            listening_ports: Vec::new(),
-            users: Vec::new(),
-            services: Vec::new(),
-            scheduled_tasks: Vec::new(),
-            installed_packages: Vec::new(),
-            interface_addresses: Vec::new(),
+impl SystemInfo {
+    /// Status recorded for a section, if it was collected
+    pub fn status(&self, section: Section) -> Option<&SectionStatus> {
+        self.collection_status.get(section.name())
+    }
+}
+
+/// The parts of `SystemInfo` that are collected independently
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Section {
+    OsVersion,
+    SystemInfo,
+    Processes,
+    NetworkConnections,
+    ListeningPorts,
+    Users,
+    Services,
+    ScheduledTasks,
+    InstalledPackages,
+    InterfaceAddresses,
+}
+
+impl Section {
+    pub const ALL: [Section; 10] = [
+        Section::OsVersion,
+        Section::SystemInfo,
+        Section::Processes,
+        Section::NetworkConnections,
+        Section::ListeningPorts,
+        Section::Users,
+        Section::Services,
+        Section::ScheduledTasks,
+        Section::InstalledPackages,
+        Section::InterfaceAddresses,
+    ];
+
+    /// Field name of the section in `SystemInfo`, also used as its status key
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::OsVersion => "os_version",
+            Section::SystemInfo => "system_info",
+            Section::Processes => "processes",
+            Section::NetworkConnections => "network_connections",
+            Section::ListeningPorts => "listening_ports",
+            Section::Users => "users",
+            Section::Services => "services",
+            Section::ScheduledTasks => "scheduled_tasks",
+            Section::InstalledPackages => "installed_packages",
+            Section::InterfaceAddresses => "interface_addresses",
         }
     }
 }
 
+impl std::fmt::Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Section {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Section::ALL
+            .iter()
+            .copied()
+            .find(|section| section.name() == s)
+            .ok_or_else(|| format!("unknown section '{}'", s))
+    }
+}
+
+/// Outcome of collecting one section
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SectionState {
+    /// Rows were collected
+    #[default]
+    Ok,
+    /// Every query succeeded but returned no rows
+    Empty,
+    /// None of the section's tables exist on this host
+    Unsupported,
+    /// At least one query failed; `error` says why
+    Failed,
+    /// Not collected because the configuration leaves the section out
+    Disabled,
+}
+
+/// Per-section collection report, so "no cron jobs" can be told apart from
+/// "crontab query failed"
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SectionStatus {
+    pub state: SectionState,
+    /// Rows stored in `SystemInfo`
+    pub rows: usize,
+    /// Rows osquery returned that did not match the model and were dropped
+    pub rows_skipped: usize,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Tables that were skipped because they don't exist on this host
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsupported_tables: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn package<T: serde::de::DeserializeOwned>(details: fn(T) -> PackageDetails, row: Value, distro: Option<&str>) -> PackageInfo {
+        PackageInfo::new(details(serde_json::from_value(row).unwrap()), distro)
+    }
+
+    #[test]
+    fn rpm_versions_and_purls() {
+        let curl = package(
+            PackageDetails::Rpm,
+            json!({"name": "curl", "version": "2.3", "release": "4", "epoch": "1", "arch": "x86_64", "install_time": "1700000000"}),
+            Some("Fedora"),
+        );
+        assert_eq!(curl.version.as_deref(), Some("1:2.3-4"));
+        assert_eq!((curl.epoch, curl.release.as_deref()), (Some(1), Some("4")));
+        assert_eq!(curl.install_time, Some(1_700_000_000));
+        // The epoch is a purl qualifier, not part of the version
+        assert_eq!(curl.purl.as_deref(), Some("pkg:rpm/fedora/curl@2.3-4?arch=x86_64&epoch=1"));
+
+        let bash = package(
+            PackageDetails::Rpm,
+            json!({"name": "bash", "version": "5.2.26", "release": "3.fc40", "epoch": "0", "arch": "x86_64", "install_time": "0"}),
+            None,
+        );
+        assert_eq!((bash.version.as_deref(), bash.epoch, bash.install_time), (Some("5.2.26-3.fc40"), None, None));
+        assert_eq!(bash.purl.as_deref(), Some("pkg:rpm/bash@5.2.26-3.fc40?arch=x86_64"));
+    }
+
+    #[test]
+    fn deb_versions_and_purls() {
+        let ssh = package(
+            PackageDetails::Deb,
+            json!({"name": "openssh-server", "version": "1:8.9p1-3ubuntu0.10", "arch": "amd64"}),
+            Some("ubuntu"),
+        );
+        assert_eq!((ssh.epoch, ssh.release.as_deref()), (Some(1), Some("3ubuntu0.10")));
+        assert_eq!(ssh.version.as_deref(), Some("1:8.9p1-3ubuntu0.10"));
+        assert_eq!(
+            ssh.purl.as_deref(),
+            Some("pkg:deb/ubuntu/openssh-server@1%3A8.9p1-3ubuntu0.10?arch=amd64")
+        );
+
+        let openssl = package(
+            PackageDetails::Deb,
+            json!({"name": "openssl", "version": "1.1.1n-0+deb11u3", "arch": "arm64"}),
+            Some("debian"),
+        );
+        assert_eq!((openssl.epoch, openssl.release.as_deref()), (None, Some("0+deb11u3")));
+        assert_eq!(openssl.purl.as_deref(), Some("pkg:deb/debian/openssl@1.1.1n-0%2Bdeb11u3?arch=arm64"));
+
+        // Native packages have no Debian revision
+        let native = package(PackageDetails::Deb, json!({"name": "tzdata", "version": "2024a"}), Some("debian"));
+        assert_eq!(native.release, None);
+        assert_eq!(native.purl.as_deref(), Some("pkg:deb/debian/tzdata@2024a"));
+    }
+
+    #[test]
+    fn other_ecosystems_purls() {
+        let portage = package(
+            PackageDetails::Portage,
+            json!({"package": "dev-libs/openssl", "version": "3.0.13"}),
+            None,
+        );
+        assert_eq!(portage.name.as_deref(), Some("openssl"));
+        assert_eq!(portage.purl.as_deref(), Some("pkg:ebuild/dev-libs/openssl@3.0.13"));
+
+        let brew = package(PackageDetails::Homebrew, json!({"name": "git", "version": "2.45.1"}), None);
+        assert_eq!(brew.purl.as_deref(), Some("pkg:generic/homebrew/git@2.45.1"));
+
+        let program = package(
+            PackageDetails::Programs,
+            json!({"name": "Visual Studio Code", "version": "1.90.0", "publisher": "Microsoft Corporation", "install_date": "20240229"}),
+            None,
+        );
+        assert_eq!(
+            program.purl.as_deref(),
+            Some("pkg:generic/Microsoft%20Corporation/Visual%20Studio%20Code@1.90.0")
+        );
+        assert_eq!(program.install_time, Some(1_709_164_800));
+
+        let nameless = package(PackageDetails::Pkg, json!({"name": "", "version": "1.0"}), None);
+        assert_eq!(nameless.purl, None);
+    }
+
+    #[test]
+    fn purl_encoding_keeps_only_unreserved_characters() {
+        assert_eq!(purl_encode("a-b_c.d~e"), "a-b_c.d~e");
+        assert_eq!(purl_encode("a b/c:d@e?f&g"), "a%20b%2Fc%3Ad%40e%3Ff%26g");
+        assert_eq!(purl_encode("caf\u{e9}"), "caf%C3%A9");
+    }
+
+    #[test]
+    fn install_dates() {
+        assert_eq!(parse_yyyymmdd("19700101"), Some(0));
+        assert_eq!(parse_yyyymmdd(" 20240229 "), Some(1_709_164_800));
+        assert_eq!(parse_yyyymmdd("20001231"), Some(978_220_800));
+        for invalid in ["", "2024011", "202401011", "2024-1-1", "2024O101", "20241301", "20240001", "20240100", "20240132", "20230229", "19000229", "20240431"] {
+            assert_eq!(parse_yyyymmdd(invalid), None, "{}", invalid);
+        }
+    }
+
+    fn service(details: Value) -> ServiceInfo {
+        ServiceInfo::from(serde_json::from_value::<ServiceDetails>(details).unwrap())
+    }
+
+    #[test]
+    fn windows_service_states() {
+        let cases = [
+            ("RUNNING", "AUTO_START", Some(ServiceState::Running), Some(true)),
+            ("STOPPED", "DEMAND_START", Some(ServiceState::Stopped), Some(false)),
+            ("START_PENDING", "BOOT_START", Some(ServiceState::Starting), Some(true)),
+            ("CONTINUE_PENDING", "SYSTEM_START", Some(ServiceState::Starting), Some(true)),
+            ("STOP_PENDING", "DISABLED", Some(ServiceState::Stopping), Some(false)),
+            ("PAUSE_PENDING", "", Some(ServiceState::Stopping), None),
+            ("PAUSED", "AUTO_START", Some(ServiceState::Paused), Some(true)),
+            ("", "", None, None),
+        ];
+        for (status, start_type, state, enabled) in cases {
+            let info = service(json!({"kind": "windows", "name": "Spooler", "status": status, "start_type": start_type}));
+            assert_eq!((info.state, info.enabled), (state, enabled), "{} {}", status, start_type);
+        }
+    }
+
+    #[test]
+    fn windows_service_executables() {
+        let cases = [
+            (r#""C:\Program Files\Foo\foo.exe" -k arg"#, r"C:\Program Files\Foo\foo.exe"),
+            (r#""C:\Program Files\Foo\foo.exe""#, r"C:\Program Files\Foo\foo.exe"),
+            (r"C:\Windows\system32\svchost.exe -k netsvcs -p", r"C:\Windows\system32\svchost.exe"),
+            (r"C:\Program Files\Bar\bar.EXE /service", r"C:\Program Files\Bar\bar.EXE"),
+            (r"\SystemRoot\System32\drivers\acpi.sys", r"\SystemRoot\System32\drivers\acpi.sys"),
+            (r"  C:\tools\agent.exe  ", r"C:\tools\agent.exe"),
+        ];
+        for (command, executable) in cases {
+            assert_eq!(windows_executable(command), executable, "{}", command);
+            let info = service(json!({"kind": "windows", "name": "svc", "path": command}));
+            assert_eq!(info.binary_path.as_deref(), Some(executable));
+        }
+        let info = service(json!({"kind": "windows", "name": "svc", "path": "", "user_account": "LocalSystem"}));
+        assert_eq!((info.binary_path, info.run_as.as_deref()), (None, Some("LocalSystem")));
+    }
+
+    #[test]
+    fn systemd_unit_states() {
+        let cases = [
+            ("active", "running", Some(ServiceState::Running)),
+            ("active", "exited", Some(ServiceState::Stopped)),
+            ("reloading", "reload", Some(ServiceState::Running)),
+            ("inactive", "dead", Some(ServiceState::Stopped)),
+            ("activating", "start", Some(ServiceState::Starting)),
+            ("deactivating", "stop-sigterm", Some(ServiceState::Stopping)),
+            ("failed", "failed", Some(ServiceState::Failed)),
+            ("maintenance", "", None),
+        ];
+        for (active_state, sub_state, state) in cases {
+            let info = service(json!({"kind": "systemd", "id": "ssh.service", "active_state": active_state, "sub_state": sub_state}));
+            assert_eq!(info.state, state, "{} {}", active_state, sub_state);
+        }
+
+        let cases = [
+            ("enabled", Some(true)),
+            ("enabled-runtime", Some(true)),
+            ("disabled", Some(false)),
+            ("masked", Some(false)),
+            ("static", None),
+            ("generated", None),
+            ("", None),
+        ];
+        for (unit_file_state, enabled) in cases {
+            let info = service(json!({"kind": "systemd", "id": "ssh.service", "unit_file_state": unit_file_state, "user": "sshd"}));
+            assert_eq!(info.enabled, enabled, "{}", unit_file_state);
+            assert_eq!((info.name.as_deref(), info.run_as.as_deref()), (Some("ssh.service"), Some("sshd")));
+        }
+    }
+
+    #[test]
+    fn launchd_jobs() {
+        // (row, name, enabled, binary_path)
+        let cases = [
+            (
+                json!({"label": "com.example.agent", "program": "/usr/local/bin/agent", "disabled": "0"}),
+                Some("com.example.agent"),
+                Some(true),
+                Some("/usr/local/bin/agent"),
+            ),
+            (
+                json!({"name": "com.example.helper.plist", "program_arguments": "/usr/libexec/helper --daemon", "disabled": "1"}),
+                Some("com.example.helper.plist"),
+                Some(false),
+                Some("/usr/libexec/helper"),
+            ),
+            (json!({"label": "com.example.empty", "program": "", "program_arguments": ""}), Some("com.example.empty"), Some(true), None),
+        ];
+        for (mut row, name, enabled, binary_path) in cases {
+            row["kind"] = json!("launchd");
+            let info = service(row);
+            assert_eq!(info.name.as_deref(), name);
+            assert_eq!(info.enabled, enabled, "{:?}", name);
+            assert_eq!(info.binary_path.as_deref(), binary_path, "{:?}", name);
+            // launchd does not say whether a job is running
+            assert_eq!(info.state, None);
+        }
+    }
+
+    fn task(details: Value) -> ScheduledTask {
+        ScheduledTask::from(serde_json::from_value::<ScheduledTaskDetails>(details).unwrap())
+    }
+
+    #[test]
+    fn cron_users_commands_and_schedules() {
+        // (row, run_as, command, schedule)
+        let cases = [
+            (
+                json!({"path": "/etc/crontab", "minute": "17", "hour": "*", "day_of_month": "*", "month": "*", "day_of_week": "*",
+                       "command": "root    cd / && run-parts --report /etc/cron.hourly"}),
+                Some("root"),
+                Some("cd / && run-parts --report /etc/cron.hourly"),
+                Some("17 * * * *"),
+            ),
+            (
+                json!({"path": "/etc/cron.d/certbot", "minute": "0", "hour": "*/12", "command": "root\ttest -x /usr/bin/certbot"}),
+                Some("root"),
+                Some("test -x /usr/bin/certbot"),
+                Some("0 */12 * * *"),
+            ),
+            (
+                json!({"path": "/etc/cron.d/broken", "event": "@daily", "command": "lonely"}),
+                None,
+                Some("lonely"),
+                Some("@daily"),
+            ),
+            (
+                json!({"path": "/var/spool/cron/crontabs/alice", "event": "@reboot", "minute": "", "command": "/home/alice/bin/sync --all"}),
+                Some("alice"),
+                Some("/home/alice/bin/sync --all"),
+                Some("@reboot"),
+            ),
+            (
+                json!({"path": "/var/at/tabs/bob", "minute": "*/5", "day_of_week": "1-5", "command": "backup"}),
+                Some("bob"),
+                Some("backup"),
+                Some("*/5 * * * 1-5"),
+            ),
+        ];
+        for (mut row, run_as, command, schedule) in cases {
+            row["kind"] = json!("cron");
+            let path = row["path"].clone();
+            let task = task(row);
+            assert_eq!(task.run_as.as_deref(), run_as, "{}", path);
+            assert_eq!(task.command.as_deref(), command, "{}", path);
+            assert_eq!(task.schedule.as_deref(), schedule, "{}", path);
+            assert_eq!(task.name.as_deref(), path.as_str());
+        }
+    }
+
+    #[test]
+    fn launchd_schedules() {
+        // (StartInterval, StartCalendarInterval, schedule)
+        let cases = [
+            (json!("3600"), json!({}), Some("every 3600s")),
+            (json!(""), json!({"Hour": "7", "Minute": "30"}), Some("30 7 * * *")),
+            (json!(""), json!({"Weekday": "0", "Hour": "3", "Minute": "15", "Day": "1", "Month": "6"}), Some("15 3 1 6 0")),
+            (
+                json!(""),
+                json!({"0/Hour": "7", "0/Minute": "0", "1/Hour": "19", "1/Minute": "0", "1/Weekday": "5"}),
+                Some("0 7 * * *; 0 19 * * 5"),
+            ),
+            (json!("300"), json!({"Minute": "0"}), Some("every 300s; 0 * * * *")),
+            (json!(""), json!({}), None),
+        ];
+        for (start_interval, calendar, schedule) in cases {
+            let task = task(json!({
+                "kind": "launchd",
+                "label": "com.example.job",
+                "program_arguments": "/usr/local/bin/job --quiet",
+                "username": "root",
+                "start_interval": start_interval,
+                "start_calendar_interval": calendar,
+            }));
+            assert_eq!(task.schedule.as_deref(), schedule, "{} {}", start_interval, calendar);
+            assert_eq!(task.command.as_deref(), Some("/usr/local/bin/job --quiet"));
+            assert_eq!(task.run_as.as_deref(), Some("root"));
+            assert_eq!(task.enabled, Some(true));
+        }
+    }
+}
@@ -2,11 +2,163 @@
 // OSquery Integration Module
 // ============================================================================
 
-use std::process::Command;
-use anyhow::{Result, Context};
-use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+/// Default per-query deadline for `osqueryi`
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Result type used throughout the osquery module
+pub type Result<T, E = OsqueryError> = std::result::Result<T, E>;
+
+/// Everything that can go wrong running a query, split so callers can react
+/// differently (e.g. stop querying a table this platform does not have)
+#[derive(Debug, thiserror::Error)]
+pub enum OsqueryError {
+    /// The osquery binary could not be started
+    #[error("Failed to execute OSquery. Is OSquery installed? Tried: {binary}")]
+    BinaryNotFound {
+        binary: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// osquery does not know the table (not built for this platform or version)
+    #[error("Table '{table}' does not exist on this platform: {query}")]
+    UnsupportedTable {
+        query: String,
+        table: String,
+        stderr: String,
+    },
+
+    /// osquery ran but reported an error
+    #[error("OSquery query failed: {query}: {}", stderr.trim())]
+    QueryFailed { query: String, stderr: String },
+
+    /// The query ran past its deadline and was abandoned
+    #[error(
+        "OSquery query timed out after {:.1}s on table '{}': {query}",
+        timeout.as_secs_f64(),
+        table.as_deref().unwrap_or("unknown")
+    )]
+    Timeout {
+        query: String,
+        /// Table the query was reading from, if it could be determined
+        table: Option<String>,
+        timeout: Duration,
+    },
+
+    /// osquery's output was not a JSON array of rows
+    #[error("Failed to parse OSquery JSON output for query '{query}': {source}")]
+    JsonParse {
+        query: String,
+        output: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// A row did not deserialize into the requested struct
+    #[error("OSquery row does not match the expected schema for query '{query}': {source}")]
+    RowSchemaMismatch {
+        query: String,
+        row: Value,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// The connection to osquery (process pipes, extension socket) failed
+    #[error("OSquery transport error for query '{query}': {message}")]
+    Transport { query: String, message: String },
+
+    /// A replay backend has no recorded result for the query
+    #[error("No recorded fixture for query '{query}' in {}", dir.display())]
+    MissingFixture { query: String, dir: PathBuf },
+}
+
+impl OsqueryError {
+    /// Classifies an error message reported by osquery for `query`
+    pub fn from_osquery_message(query: &str, stderr: &str) -> OsqueryError {
+        match missing_table(stderr) {
+            Some(table) => OsqueryError::UnsupportedTable {
+                query: query.to_string(),
+                table,
+                stderr: stderr.to_string(),
+            },
+            None => OsqueryError::QueryFailed {
+                query: query.to_string(),
+                stderr: stderr.to_string(),
+            },
+        }
+    }
+
+    /// The query that failed
+    pub fn query(&self) -> &str {
+        match self {
+            OsqueryError::BinaryNotFound { .. } => "",
+            OsqueryError::UnsupportedTable { query, .. }
+            | OsqueryError::QueryFailed { query, .. }
+            | OsqueryError::Timeout { query, .. }
+            | OsqueryError::JsonParse { query, .. }
+            | OsqueryError::RowSchemaMismatch { query, .. }
+            | OsqueryError::Transport { query, .. }
+            | OsqueryError::MissingFixture { query, .. } => query,
+        }
+    }
+
+    /// Name of the missing table, if this error means the table does not exist
+    pub fn unsupported_table(&self) -> Option<&str> {
+        match self {
+            OsqueryError::UnsupportedTable { table, .. } => Some(table),
+            _ => None,
+        }
+    }
+
+    /// Whether running the same query again could plausibly succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            OsqueryError::Timeout { .. } | OsqueryError::Transport { .. } | OsqueryError::QueryFailed { .. }
+        )
+    }
+}
+
+/// Finds the table name in osquery's "no such table: foo" error
+fn missing_table(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once("no such table:")?;
+    let table: String = rest
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect();
+    if table.is_empty() {
+        None
+    } else {
+        Some(table)
+    }
+}
+
+/// Extracts the first table name after `FROM` in a query, e.g. `rpm_packages`
+pub fn table_from_query(query: &str) -> Option<String> {
+    let mut words = query.split_whitespace();
+    while let Some(word) = words.next() {
+        if word.eq_ignore_ascii_case("from") {
+            let table: String = words
+                .next()?
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect();
+            return if table.is_empty() { None } else { Some(table) };
+        }
+    }
+    None
+}
+
 /// Finds the OSquery binary path based on platform
 fn find_osquery_binary() -> String {
     #[cfg(target_os = "windows")]
@@ -64,62 +216,683 @@ fn find_osquery_binary() -> String {
     }
 }
 
+/// Searches `PATH` for an executable, returning its full path
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// How to find and invoke `osqueryi`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OsqueryConfig {
+    /// Explicit binary path; when unset, common install locations and `PATH` are searched
+    pub binary: Option<PathBuf>,
+    /// Passed as `--config_path`
+    pub config_path: Option<PathBuf>,
+    /// Passed as `--database_path`
+    pub database_path: Option<PathBuf>,
+    /// Passes `--disable_events`
+    pub disable_events: bool,
+    /// Passed as `--extensions_autoload`
+    pub extensions_autoload: Option<PathBuf>,
+    /// Additional flags passed verbatim, e.g. `--verbose`
+    #[serde(rename = "flags")]
+    pub extra_flags: Vec<String>,
+    /// Extra environment variables for the `osqueryi` process
+    #[serde(skip)]
+    pub env: Vec<(String, String)>,
+}
+
+impl OsqueryConfig {
+    /// Reads `OSQUERY_BINARY`, `OSQUERY_CONFIG_PATH`, `OSQUERY_DATABASE_PATH`,
+    /// `OSQUERY_DISABLE_EVENTS`, `OSQUERY_EXTENSIONS_AUTOLOAD` and
+    /// `OSQUERY_FLAGS` (whitespace separated)
+    pub fn from_env() -> Self {
+        let mut config = OsqueryConfig::default();
+        config.apply_env();
+        config
+    }
+
+    /// Overrides settings with the `OSQUERY_*` variables that are set (see
+    /// `from_env()`); `OSQUERY_FLAGS` is appended to the existing flags
+    pub fn apply_env(&mut self) {
+        let path = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        if let Some(binary) = path("OSQUERY_BINARY") {
+            self.binary = Some(binary);
+        }
+        if let Some(config_path) = path("OSQUERY_CONFIG_PATH") {
+            self.config_path = Some(config_path);
+        }
+        if let Some(database_path) = path("OSQUERY_DATABASE_PATH") {
+            self.database_path = Some(database_path);
+        }
+        if let Ok(value) = std::env::var("OSQUERY_DISABLE_EVENTS") {
+            self.disable_events = matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes");
+        }
+        if let Some(autoload) = path("OSQUERY_EXTENSIONS_AUTOLOAD") {
+            self.extensions_autoload = Some(autoload);
+        }
+        if let Ok(flags) = std::env::var("OSQUERY_FLAGS") {
+            self.extra_flags.extend(flags.split_whitespace().map(String::from));
+        }
+    }
+
+    /// Binary that will be run: the configured path, a known install location,
+    /// or the full path of `osqueryi` found on `PATH`
+    pub fn resolve_binary(&self) -> String {
+        if let Some(binary) = &self.binary {
+            return binary.display().to_string();
+        }
+        let found = find_osquery_binary();
+        match find_in_path(&found) {
+            Some(full) if !std::path::Path::new(&found).is_absolute() => full.display().to_string(),
+            _ => found,
+        }
+    }
+
+    /// Command-line flags passed to `osqueryi` (besides `--json`)
+    pub fn flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let Some(path) = &self.config_path {
+            flags.push(format!("--config_path={}", path.display()));
+        }
+        if let Some(path) = &self.database_path {
+            flags.push(format!("--database_path={}", path.display()));
+        }
+        if self.disable_events {
+            flags.push("--disable_events".to_string());
+        }
+        if let Some(path) = &self.extensions_autoload {
+            flags.push(format!("--extensions_autoload={}", path.display()));
+        }
+        flags.extend(self.extra_flags.iter().cloned());
+        flags
+    }
+
+    fn command(&self, binary: &str) -> Command {
+        let mut command = Command::new(binary);
+        command
+            .arg("--json")
+            .args(self.flags())
+            .envs(self.env.iter().map(|(k, v)| (k, v)));
+        command
+    }
+}
+
+/// Runs `<binary> --version` and returns the version number, e.g. `5.12.1`
+pub fn osquery_version(binary: &str) -> Result<String> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|source| OsqueryError::BinaryNotFound {
+            binary: binary.to_string(),
+            source,
+        })?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    // "osqueryi version 5.12.1"
+    match text.split_whitespace().last() {
+        Some(version) if output.status.success() => Ok(version.to_string()),
+        _ => Err(OsqueryError::QueryFailed {
+            query: "--version".to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }),
+    }
+}
+
 /// Executes an OSquery query and returns JSON result
 pub fn execute_osquery_query(query: &str) -> Result<Vec<Value>> {
-    let osquery_path = find_osquery_binary();
+    execute_osquery_query_with_timeout(query, None)
+}
+
+/// Executes an OSquery query, killing `osqueryi` if it runs longer than `timeout`.
+/// A timeout is reported as [`OsqueryError::Timeout`].
+pub fn execute_osquery_query_with_timeout(query: &str, timeout: Option<Duration>) -> Result<Vec<Value>> {
+    OsqueryiBackend::new().with_timeout(timeout).execute(query)
+}
 
+fn run_query(config: &OsqueryConfig, osquery_path: &str, query: &str, timeout: Option<Duration>) -> Result<Vec<Value>> {
     // Basic debug output so we can see which binary and query are used
-    eprintln!("[osquery] Executing '{}' using binary '{}'", query, osquery_path);
+    log::debug!("Executing '{}' using binary '{}'", query, osquery_path);
 
-    let output = Command::new(&osquery_path)
-        .arg("--json")
+    let mut child = config
+        .command(osquery_path)
         .arg(query)
-        .output()
-        .with_context(|| format!("Failed to execute OSquery. Is OSquery installed? Tried: {}", osquery_path))?;
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| OsqueryError::BinaryNotFound {
+            binary: osquery_path.to_string(),
+            source,
+        })?;
+
+    // Drain both pipes on their own threads so a chatty osqueryi can't block on a full pipe
+    let stdout_reader = drain_pipe(child.stdout.take());
+    let stderr_reader = drain_pipe(child.stderr.take());
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let status = loop {
+        let exited = child.try_wait().map_err(|e| OsqueryError::Transport {
+            query: query.to_string(),
+            message: format!("failed to wait for osqueryi: {}", e),
+        })?;
+        if let Some(status) = exited {
+            break status;
+        }
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                let error = OsqueryError::Timeout {
+                    query: query.to_string(),
+                    table: table_from_query(query),
+                    timeout: timeout.unwrap_or_default(),
+                };
+                log::warn!("{}", error);
+                return Err(error);
+            }
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    let stdout = stdout_reader.join().unwrap_or_default();
+    let stderr = stderr_reader.join().unwrap_or_default();
 
-    if !output.status.success() {
-        let error_msg = String::from_utf8_lossy(&output.stderr);
-        eprintln!("[osquery] Query failed. stderr: {}", error_msg);
-        return Err(anyhow::anyhow!("OSquery query failed: {}", error_msg));
+    if !status.success() {
+        let error_msg = String::from_utf8_lossy(&stderr);
+        log::warn!("Query failed. stderr: {}", error_msg);
+        return Err(OsqueryError::from_osquery_message(query, &error_msg));
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let json: Vec<Value> = serde_json::from_str(&stdout)
-        .with_context(|| format!("Failed to parse OSquery JSON output: {}", stdout))?;
+    let stdout = String::from_utf8_lossy(&stdout);
+    serde_json::from_str(&stdout).map_err(|source| OsqueryError::JsonParse {
+        query: query.to_string(),
+        output: stdout.into_owned(),
+        source,
+    })
+}
+
+fn drain_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Column name of the marker rows that separate queries in a batch
+const BATCH_MARKER: &str = "osquery_batch_marker";
+
+/// Progress of a batch, updated as `osqueryi` streams results
+struct BatchProgress {
+    /// Index of the last marker seen; every query before it has all its rows
+    current: Option<usize>,
+    rows: Vec<Vec<Value>>,
+    parse_error: Option<String>,
+}
 
-    Ok(json)
+/// Runs several queries in a single `osqueryi` session and returns one result per
+/// query, in the same order.
+///
+/// The queries are fed to `osqueryi --json` on stdin, each preceded by a marker
+/// query (`SELECT <n> AS osquery_batch_marker;`). The marker rows in the output
+/// tell us which query the following result arrays belong to.
+///
+/// `osqueryi` block-buffers its output to a pipe, so markers arrive in bursts
+/// and say nothing about which query is slow. The session as a whole therefore
+/// gets `timeout` once per query. If it runs past that, it is killed and every
+/// query whose output was not complete is run again on its own with `timeout`,
+/// so a timeout is still reported on the query that actually hangs.
+pub fn execute_osquery_batch(queries: &[&str], timeout: Option<Duration>) -> Vec<Result<Vec<Value>>> {
+    OsqueryiBackend::new().with_timeout(timeout).execute_batch(queries)
 }
 
-/// Executes a query and attempts to deserialize to a specific type
-pub fn query_to_struct<T>(query: &str) -> Result<Vec<T>>
+fn run_batch(
+    config: &OsqueryConfig,
+    osquery_path: &str,
+    queries: &[&str],
+    timeout: Option<Duration>,
+) -> Vec<Result<Vec<Value>>> {
+    if queries.is_empty() {
+        return Vec::new();
+    }
+    log::debug!(
+        "Executing batch of {} queries using binary '{}'",
+        queries.len(),
+        osquery_path
+    );
+
+    // One statement per line, so "near line N" errors map back to a query
+    let mut script = String::new();
+    for (i, query) in queries.iter().enumerate() {
+        script.push_str(&format!("SELECT {} AS {};\n", i, BATCH_MARKER));
+        let query = query.replace(['\r', '\n'], " ");
+        let query = query.trim();
+        script.push_str(query);
+        if !query.ends_with(';') {
+            script.push(';');
+        }
+        script.push('\n');
+    }
+
+    let mut child = match config
+        .command(osquery_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(source) => {
+            let message = format!("failed to start {}: {}", osquery_path, source);
+            let mut results = vec![Err(OsqueryError::BinaryNotFound {
+                binary: osquery_path.to_string(),
+                source,
+            })];
+            results.extend(queries[1..].iter().map(|query| {
+                Err(OsqueryError::Transport {
+                    query: query.to_string(),
+                    message: message.clone(),
+                })
+            }));
+            return results;
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(script.as_bytes());
+            // Dropping stdin closes it so osqueryi exits after the last query
+        });
+    }
+    let stderr_reader = drain_pipe(child.stderr.take());
+
+    let deadline = timeout.map(|t| Instant::now() + t.saturating_mul(queries.len() as u32));
+    let progress = Arc::new(Mutex::new(BatchProgress {
+        current: None,
+        rows: vec![Vec::new(); queries.len()],
+        parse_error: None,
+    }));
+    let stdout_reader = {
+        let progress = progress.clone();
+        let stdout = child.stdout.take();
+        std::thread::spawn(move || {
+            let Some(stdout) = stdout else { return };
+            let stream = serde_json::Deserializer::from_reader(stdout).into_iter::<Value>();
+            for value in stream {
+                let mut p = progress.lock().unwrap();
+                match value {
+                    Ok(Value::Array(rows)) => {
+                        if let Some(i) = batch_marker(&rows) {
+                            p.current = Some(i);
+                        } else if let Some(i) = p.current.filter(|i| *i < p.rows.len()) {
+                            p.rows[i].extend(rows);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        p.parse_error = Some(e.to_string());
+                        break;
+                    }
+                }
+            }
+        })
+    };
+
+    // Wait for osqueryi, killing it if the batch overruns its deadline
+    let mut timed_out = false;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) => {}
+            Err(_) => {
+                let _ = child.kill();
+                break;
+            }
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            timed_out = true;
+            let _ = child.kill();
+            let _ = child.wait();
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let _ = stdout_reader.join();
+    let stderr = stderr_reader.join().unwrap_or_default();
+    let stderr = String::from_utf8_lossy(&stderr);
+    let mut progress = progress.lock().unwrap();
+    let reached = progress.current;
+
+    let mut results: Vec<Result<Vec<Value>>> = std::mem::take(&mut progress.rows)
+        .into_iter()
+        .map(Ok)
+        .collect();
+
+    // Attribute error lines on stderr to the query they belong to
+    for line in stderr.lines().filter(|line| line.contains("Error")) {
+        let index = batch_error_line(line)
+            .and_then(|line_no| line_no.checked_sub(1).map(|l| l / 2))
+            .filter(|i| *i < queries.len())
+            .or_else(|| {
+                let table = missing_table(line)?;
+                queries
+                    .iter()
+                    .position(|query| table_from_query(query).as_deref() == Some(table.as_str()))
+            });
+        match index {
+            Some(i) => results[i] = Err(OsqueryError::from_osquery_message(queries[i], line)),
+            None => log::warn!("Unattributed batch error: {}", line),
+        }
+    }
+
+    if timed_out {
+        // Queries up to the last marker seen are complete; the rest run one by one
+        let first = reached.unwrap_or(0);
+        log::warn!(
+            "Batch timed out after {:.1}s; running the {} unfinished queries separately",
+            timeout.unwrap_or_default().saturating_mul(queries.len() as u32).as_secs_f64(),
+            queries.len() - first
+        );
+        for (i, query) in queries.iter().enumerate().skip(first) {
+            results[i] = run_query(config, osquery_path, query, timeout);
+        }
+        return results;
+    }
+
+    for (i, result) in results.iter_mut().enumerate() {
+        let not_run = |message: String| OsqueryError::Transport {
+            query: queries[i].to_string(),
+            message,
+        };
+        if let Some(error) = &progress.parse_error {
+            if reached.is_none_or(|reached| i >= reached) {
+                *result = Err(not_run(format!("failed to parse osqueryi batch output: {}", error)));
+            }
+        } else if reached.is_none_or(|reached| i > reached) {
+            *result = Err(not_run(format!("osqueryi exited before running this query: {}", stderr.trim())));
+        }
+    }
+    results
+}
+
+/// Index carried by a marker result (`[{"osquery_batch_marker": "3"}]`)
+fn batch_marker(rows: &[Value]) -> Option<usize> {
+    let [row] = rows else { return None };
+    match row.get(BATCH_MARKER)? {
+        Value::String(s) => s.parse().ok(),
+        Value::Number(n) => n.as_u64().map(|n| n as usize),
+        _ => None,
+    }
+}
+
+/// Script line number from shell errors such as "Error: near line 4: no such table: foo"
+fn batch_error_line(line: &str) -> Option<usize> {
+    let (_, rest) = line.split_once("near line ")?;
+    rest.chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+/// Source of osquery rows: anything that can run SQL and return JSON rows
+pub trait QueryBackend: Send + Sync {
+    /// Executes a single query and returns its rows
+    fn execute(&self, query: &str) -> Result<Vec<Value>>;
+
+    /// Executes several queries and returns one result per query, in the same order.
+    /// Backends that can share work between queries override this.
+    fn execute_batch(&self, queries: &[&str]) -> Vec<Result<Vec<Value>>> {
+        queries.iter().map(|query| self.execute(query)).collect()
+    }
+}
+
+/// Reports the running osquery version
+pub const VERSION_QUERY: &str = "SELECT version FROM osquery_info;";
+
+/// Lists the tables osquery can answer
+pub const TABLES_QUERY: &str = "SELECT name FROM osquery_registry WHERE registry = 'table' AND active = 1;";
+
+/// What the osquery behind a backend offers, read from `osquery_info` and `osquery_registry`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OsqueryCapabilities {
+    pub version: Option<String>,
+    pub tables: BTreeSet<String>,
+}
+
+impl OsqueryCapabilities {
+    /// Asks `backend` for its version and table list. Fails only if the table
+    /// list can't be read; a missing version is tolerated.
+    pub fn probe(backend: &dyn QueryBackend) -> Result<Self> {
+        let tables = backend
+            .execute(TABLES_QUERY)?
+            .iter()
+            .filter_map(|row| row.get("name").and_then(Value::as_str))
+            .map(String::from)
+            .collect::<BTreeSet<_>>();
+        if tables.is_empty() {
+            // Every osquery build registers tables; treat this as a broken probe
+            return Err(OsqueryError::QueryFailed {
+                query: TABLES_QUERY.to_string(),
+                stderr: "osquery_registry returned no tables".to_string(),
+            });
+        }
+        let version = match backend.execute(VERSION_QUERY) {
+            Ok(rows) => rows
+                .first()
+                .and_then(|row| row.get("version"))
+                .and_then(Value::as_str)
+                .map(String::from),
+            Err(e) => {
+                log::warn!("Could not read osquery_info: {}", e);
+                None
+            }
+        };
+        Ok(OsqueryCapabilities { version, tables })
+    }
+
+    pub fn has_table(&self, table: &str) -> bool {
+        self.tables.contains(table)
+    }
+}
+
+/// Default backend that spawns `osqueryi --json` for every query
+#[derive(Debug, Clone)]
+pub struct OsqueryiBackend {
+    config: OsqueryConfig,
+    binary: String,
+    timeout: Option<Duration>,
+    version: std::sync::OnceLock<Option<String>>,
+}
+
+impl OsqueryiBackend {
+    /// Creates a backend configured from the environment (see [`OsqueryConfig::from_env`])
+    /// using [`DEFAULT_QUERY_TIMEOUT`] per query
+    pub fn new() -> Self {
+        OsqueryiBackend::with_config(OsqueryConfig::from_env())
+    }
+
+    /// Creates a backend with an explicit configuration
+    pub fn with_config(config: OsqueryConfig) -> Self {
+        let binary = config.resolve_binary();
+        OsqueryiBackend {
+            config,
+            binary,
+            timeout: Some(DEFAULT_QUERY_TIMEOUT),
+            version: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// Sets the per-query deadline; `None` waits forever
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn config(&self) -> &OsqueryConfig {
+        &self.config
+    }
+
+    /// Resolved path of the `osqueryi` binary
+    pub fn binary(&self) -> &str {
+        &self.binary
+    }
+
+    /// osquery version reported by `osqueryi --version`, looked up once
+    pub fn version(&self) -> Option<&str> {
+        self.version
+            .get_or_init(|| match osquery_version(&self.binary) {
+                Ok(version) => Some(version),
+                Err(e) => {
+                    log::warn!("Could not determine osquery version: {}", e);
+                    None
+                }
+            })
+            .as_deref()
+    }
+}
+
+impl Default for OsqueryiBackend {
+    fn default() -> Self {
+        OsqueryiBackend::new()
+    }
+}
+
+impl QueryBackend for OsqueryiBackend {
+    fn execute(&self, query: &str) -> Result<Vec<Value>> {
+        run_query(&self.config, &self.binary, query, self.timeout)
+    }
+
+    /// Runs all queries in one `osqueryi` session
+    fn execute_batch(&self, queries: &[&str]) -> Vec<Result<Vec<Value>>> {
+        run_batch(&self.config, &self.binary, queries, self.timeout)
+    }
+}
+
+/// Deserializes a single row, reporting a schema mismatch with the offending row
+pub fn row_to_struct<T>(query: &str, row: Value) -> Result<T>
 where
     T: for<'de> Deserialize<'de>,
 {
-    let json_values = match execute_osquery_query(query) {
-        Ok(values) => values,
-        Err(e) => {
-            eprintln!("[osquery] Error executing query '{}': {:?}", query, e);
-            return Err(e);
-        }
-    };
+    // Deserialize from a reference so the row is still available for the error
+    T::deserialize(&row).map_err(|source| OsqueryError::RowSchemaMismatch {
+        query: query.to_string(),
+        row,
+        source,
+    })
+}
 
-    let mut results = Vec::new();
-    
-    for value in json_values {
-        match serde_json::from_value::<T>(value.clone()) {
+/// Deserializes rows into `T`, logging and skipping rows that don't match.
+/// Returns the parsed rows and the number of rows skipped.
+pub fn parse_rows<T>(query: &str, values: Vec<Value>) -> (Vec<T>, usize)
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let mut results = Vec::with_capacity(values.len());
+    let mut skipped = 0;
+
+    for value in values {
+        match row_to_struct::<T>(query, value) {
             Ok(parsed) => results.push(parsed),
             Err(e) => {
-                eprintln!(
-                    "[osquery] Failed to deserialize OSquery result for query '{}': {:?}\n  Value: {}",
-                    query,
-                    e,
-                    value
-                );
+                if let OsqueryError::RowSchemaMismatch { row, .. } = &e {
+                    log::warn!("{}\n  Value: {}", e, row);
+                }
                 // Skip this row but continue with others
+                skipped += 1;
             }
         }
     }
-    
-    Ok(results)
+
+    (results, skipped)
+}
+
+/// Executes a query on the given backend and attempts to deserialize to a specific type.
+/// Rows that don't match `T` are logged and skipped.
+pub fn query_to_struct<T>(backend: &dyn QueryBackend, query: &str) -> Result<Vec<T>>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let json_values = match backend.execute(query) {
+        Ok(values) => values,
+        Err(e) => {
+            log::warn!("Error executing query '{}': {}", query, e);
+            return Err(e);
+        }
+    };
+
+    Ok(parse_rows(query, json_values).0)
 }
 
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Stand-in `osqueryi` that, like the real one writing to a pipe, only
+    /// flushes its batch output at exit, and hangs on queries mentioning `slow`
+    const FAKE_OSQUERYI: &str = r#"#!/bin/sh
+if [ $# -gt 1 ]; then
+    case "$2" in *slow*) sleep 3 ;; esac
+    echo '[{"n":"alone"}]'
+    exit 0
+fi
+out=""
+n=""
+while IFS= read -r line; do
+    case "$line" in
+        *osquery_batch_marker*) n=$(echo "$line" | cut -d' ' -f2); out="$out[{\"osquery_batch_marker\":\"$n\"}]" ;;
+        *slow*) sleep 3 ;;
+        *) out="$out[{\"n\":\"$n\"}]" ;;
+    esac
+done
+printf '%s\n' "$out"
+"#;
+
+    fn fake_osqueryi(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("agent-osqueryi-{}-{}", std::process::id(), name));
+        std::fs::write(&path, FAKE_OSQUERYI).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn batch_splits_results_per_query() {
+        let binary = fake_osqueryi("split");
+        let config = OsqueryConfig::default();
+        let queries = ["SELECT * FROM users", "SELECT * FROM groups"];
+        let results = run_batch(&config, binary.to_str().unwrap(), &queries, Some(Duration::from_secs(5)));
+        let _ = std::fs::remove_file(&binary);
+
+        assert_eq!(results[0].as_ref().unwrap(), &vec![serde_json::json!({"n": "0"})]);
+        assert_eq!(results[1].as_ref().unwrap(), &vec![serde_json::json!({"n": "1"})]);
+    }
+
+    #[test]
+    fn batch_timeout_reruns_unfinished_queries_separately() {
+        let binary = fake_osqueryi("timeout");
+        let config = OsqueryConfig::default();
+        let queries = ["SELECT * FROM users", "SELECT * FROM slow_table", "SELECT * FROM groups"];
+        let results = run_batch(&config, binary.to_str().unwrap(), &queries, Some(Duration::from_millis(500)));
+        let _ = std::fs::remove_file(&binary);
+
+        // No marker got through the buffered pipe, so every query ran again on its own
+        assert_eq!(results[0].as_ref().unwrap(), &vec![serde_json::json!({"n": "alone"})]);
+        match &results[1] {
+            Err(OsqueryError::Timeout { table, .. }) => assert_eq!(table.as_deref(), Some("slow_table")),
+            other => panic!("expected a timeout on the slow query, got {:?}", other),
+        }
+        assert_eq!(results[2].as_ref().unwrap(), &vec![serde_json::json!({"n": "alone"})]);
+    }
+}
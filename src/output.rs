@@ -0,0 +1,288 @@
+// ============================================================================
+// Output Sinks
+// ============================================================================
+//
+// Destinations for the records `agent-daemon` produces each cycle (whole
+// snapshots, or events in differential mode). Records arrive already
+// serialized as JSON lines so every sink writes exactly the same bytes.
+// Sinks are built from the `[[outputs]]` entries of the configuration.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::config::OutputConfig;
+use crate::http::{HttpOutput, HttpShipper};
+use crate::rotation::{RotatingWriter, RotationPolicy};
+use crate::spool::{Spool, SpoolSender};
+
+/// A destination for JSON-lines records
+pub trait OutputSink: Send {
+    /// Short description for log messages, e.g. `file /var/log/agent.jsonl`
+    fn describe(&self) -> String;
+
+    /// Writes one cycle's records, each a single line of JSON without the
+    /// trailing newline. Records are durable once this returns `Ok`.
+    fn write(&mut self, lines: &[String]) -> Result<()>;
+}
+
+/// Opens the sink for every configured output
+pub fn open_sinks(outputs: &[OutputConfig]) -> Result<Vec<Box<dyn OutputSink>>> {
+    outputs.iter().map(open_sink).collect()
+}
+
+pub fn open_sink(output: &OutputConfig) -> Result<Box<dyn OutputSink>> {
+    Ok(match output {
+        OutputConfig::Stdout => Box::new(StdoutSink),
+        OutputConfig::File { path } => Box::new(FileSink::open(path)?),
+        OutputConfig::RotatingFile { path, .. } => {
+            let policy = output.rotation_policy().unwrap_or_default();
+            Box::new(RotatingFileSink::open(path, policy)?)
+        }
+        OutputConfig::Http(http) => match &http.spool_dir {
+            Some(dir) => Box::new(SpooledHttpSink::open(dir, http.clone())?),
+            None => Box::new(HttpSink::new(http.clone())),
+        },
+    })
+}
+
+/// Writes records to standard output
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn describe(&self) -> String {
+        "stdout".to_string()
+    }
+
+    fn write(&mut self, lines: &[String]) -> Result<()> {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        for line in lines {
+            writeln!(out, "{}", line).context("Failed to write to stdout")?;
+        }
+        out.flush().context("Failed to flush stdout")
+    }
+}
+
+/// Appends records to a single file, syncing after every write
+pub struct FileSink {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileSink {
+    pub fn open(path: impl AsRef<Path>) -> Result<FileSink> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+        Ok(FileSink { path, file })
+    }
+}
+
+impl OutputSink for FileSink {
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn write(&mut self, lines: &[String]) -> Result<()> {
+        append_lines(&mut self.file, lines).with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+/// Appends records to a file that is rotated by size or age, with optional
+/// compression and retention of the rotated segments (see `RotatingWriter`).
+/// A cycle's records are never split across segments.
+pub struct RotatingFileSink {
+    writer: RotatingWriter,
+}
+
+impl RotatingFileSink {
+    pub fn open(path: impl AsRef<Path>, policy: RotationPolicy) -> Result<RotatingFileSink> {
+        Ok(RotatingFileSink {
+            writer: RotatingWriter::open(path, policy)?,
+        })
+    }
+}
+
+impl OutputSink for RotatingFileSink {
+    fn describe(&self) -> String {
+        format!("rotating file {}", self.writer.path().display())
+    }
+
+    fn write(&mut self, lines: &[String]) -> Result<()> {
+        self.writer.write_lines(lines)
+    }
+}
+
+/// POSTs records to a collector in batches
+pub struct HttpSink {
+    shipper: HttpShipper,
+}
+
+impl HttpSink {
+    pub fn new(config: HttpOutput) -> HttpSink {
+        HttpSink {
+            shipper: HttpShipper::new(config),
+        }
+    }
+}
+
+impl OutputSink for HttpSink {
+    fn describe(&self) -> String {
+        format!("http {}", self.shipper.url())
+    }
+
+    fn write(&mut self, lines: &[String]) -> Result<()> {
+        Ok(self.shipper.send(lines)?)
+    }
+}
+
+/// Spools records on disk; a background sender POSTs them to the collector
+/// whenever it is reachable
+pub struct SpooledHttpSink {
+    url: String,
+    sender: SpoolSender,
+}
+
+impl SpooledHttpSink {
+    pub fn open(dir: impl AsRef<Path>, config: HttpOutput) -> Result<SpooledHttpSink> {
+        let spool = Spool::open(dir, config.spool_max_mb.saturating_mul(1024 * 1024))?;
+        if spool.pending() > 0 || spool.dropped() > 0 {
+            log::info!(
+                "Spool {}: {} record(s) to send, {} dropped so far",
+                spool.dir().display(),
+                spool.pending(),
+                spool.dropped()
+            );
+        }
+        // The sender retries on its own schedule; records are never given up on
+        let shipper = HttpShipper::new(HttpOutput { max_retries: 0, ..config });
+        Ok(SpooledHttpSink {
+            url: shipper.url().to_string(),
+            sender: SpoolSender::start(spool, shipper)?,
+        })
+    }
+}
+
+impl OutputSink for SpooledHttpSink {
+    fn describe(&self) -> String {
+        let spool = self.sender.spool().lock().unwrap();
+        format!("http {} (spool {})", self.url, spool.dir().display())
+    }
+
+    fn write(&mut self, lines: &[String]) -> Result<()> {
+        self.sender.enqueue(lines)
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+/// Writes all lines in one buffer so a cycle's records land together, then syncs
+fn append_lines(file: &mut File, lines: &[String]) -> std::io::Result<()> {
+    let mut buffer = Vec::with_capacity(lines.iter().map(|line| line.len() + 1).sum());
+    for line in lines {
+        buffer.extend_from_slice(line.as_bytes());
+        buffer.push(b'\n');
+    }
+    file.write_all(&buffer)?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-output-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn lines(records: &[&str]) -> Vec<String> {
+        records.iter().map(|record| record.to_string()).collect()
+    }
+
+    #[test]
+    fn open_sinks_builds_one_sink_per_output() {
+        let dir = scratch("open");
+        let outputs: Vec<OutputConfig> = serde_json::from_value(json!([
+            {"type": "stdout"},
+            {"type": "file", "path": dir.join("plain.jsonl")},
+            {"type": "rotating_file", "path": dir.join("rotated.jsonl"), "max_mb": 1},
+            {"type": "http", "url": "http://127.0.0.1:9/ingest"},
+            {"type": "http", "url": "http://127.0.0.1:9/spooled", "spool_dir": dir.join("spool")},
+        ]))
+        .unwrap();
+        let sinks = open_sinks(&outputs).unwrap();
+        let described: Vec<String> = sinks.iter().map(|sink| sink.describe()).collect();
+        drop(sinks);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            described,
+            vec![
+                "stdout".to_string(),
+                format!("file {}", dir.join("plain.jsonl").display()),
+                format!("rotating file {}", dir.join("rotated.jsonl").display()),
+                "http http://127.0.0.1:9/ingest".to_string(),
+                format!("http http://127.0.0.1:9/spooled (spool {})", dir.join("spool").display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn file_sinks_append_newline_delimited_json() {
+        let dir = scratch("ndjson");
+        let plain = dir.join("logs").join("plain.jsonl");
+        let rotated = dir.join("logs").join("rotated.jsonl");
+        let mut sinks: Vec<Box<dyn OutputSink>> = vec![
+            Box::new(FileSink::open(&plain).unwrap()),
+            Box::new(RotatingFileSink::open(&rotated, RotationPolicy::default()).unwrap()),
+        ];
+        for sink in &mut sinks {
+            sink.write(&lines(&[r#"{"cycle":1,"n":1}"#, r#"{"cycle":1,"n":2}"#])).unwrap();
+            sink.write(&[]).unwrap();
+            sink.write(&lines(&[r#"{"cycle":2,"n":1}"#])).unwrap();
+        }
+        drop(sinks);
+        // Reopening appends instead of truncating
+        FileSink::open(&plain).unwrap().write(&lines(&[r#"{"cycle":3,"n":1}"#])).unwrap();
+
+        let expected = "{\"cycle\":1,\"n\":1}\n{\"cycle\":1,\"n\":2}\n{\"cycle\":2,\"n\":1}\n";
+        assert_eq!(fs::read_to_string(&plain).unwrap(), format!("{}{{\"cycle\":3,\"n\":1}}\n", expected));
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unwritable_paths_are_errors() {
+        let dir = scratch("unwritable");
+        fs::create_dir_all(&dir).unwrap();
+        let blocker = dir.join("not-a-dir");
+        fs::write(&blocker, "").unwrap();
+
+        let outputs: Vec<OutputConfig> = serde_json::from_value(json!([
+            {"type": "file", "path": blocker.join("plain.jsonl")},
+            {"type": "rotating_file", "path": blocker.join("rotated.jsonl")},
+            {"type": "http", "url": "http://127.0.0.1:9/ingest", "spool_dir": blocker.join("spool")},
+        ]))
+        .unwrap();
+        for output in &outputs {
+            assert!(open_sink(output).is_err(), "{:?}", output);
+        }
+        assert!(open_sinks(&outputs).is_err());
+        assert!(FileSink::open(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,922 @@
+// ============================================================================
+// osquery TLS Remote API Client
+// ============================================================================
+//
+// Talks to a fleet server that implements osquery's TLS remote API:
+// - `/enroll`: trade the enroll secret for a node key, kept in the daemon state
+// - `/config`: the query schedule (`schedule` and inline `packs`)
+// - `/log`: scheduled query results in osquery's event format
+// - `/distributed/read` and `/distributed/write`: ad hoc queries and their results
+//
+// Every request but enrollment carries the node key. A response with
+// `"node_invalid": true` drops the key, re-enrolls and retries the request
+// once. Results wait in the state's pending queue until `/log` accepts them,
+// so they survive restarts and outages up to the state size cap. The last
+// rows of every scheduled query are kept in the state too, so a restart or
+// reload carries on with `added`/`removed` records instead of re-adding
+// everything.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use crate::agent::{Agent, Platform};
+use crate::http::{redact, transport_reason};
+use crate::state::AgentState;
+
+/// Result records per `/log` request (osquery's `logger_tls_max_lines`)
+const MAX_LOG_LINES: usize = 1024;
+
+/// Longest wait between attempts while the server keeps failing
+const MAX_RETRY_SECS: u64 = 300;
+
+/// `[remote]` section of the configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteConfig {
+    /// Server base URL, e.g. `https://fleet.example.com`
+    pub url: String,
+    /// Falls back to `AGENT_ENROLL_SECRET`
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "redact")]
+    pub enroll_secret: Option<String>,
+    /// File holding the enroll secret, like osquery's `--enroll_secret_path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enroll_secret_path: Option<PathBuf>,
+    /// Identifier sent when enrolling; defaults to the snapshot's host ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_identifier: Option<String>,
+    /// PEM file of the CA certificates to trust instead of the system roots,
+    /// like osquery's `--tls_server_certs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_certs: Option<PathBuf>,
+    #[serde(default = "default_enroll_endpoint")]
+    pub enroll_endpoint: String,
+    #[serde(default = "default_config_endpoint")]
+    pub config_endpoint: String,
+    #[serde(default = "default_logger_endpoint")]
+    pub logger_endpoint: String,
+    #[serde(default = "default_distributed_read_endpoint")]
+    pub distributed_read_endpoint: String,
+    #[serde(default = "default_distributed_write_endpoint")]
+    pub distributed_write_endpoint: String,
+    /// Seconds between `/config` requests
+    #[serde(default = "default_config_refresh_secs")]
+    pub config_refresh_secs: u64,
+    /// Seconds between `/distributed/read` requests; 0 disables distributed queries
+    #[serde(default = "default_distributed_interval_secs")]
+    pub distributed_interval_secs: u64,
+    /// Per-request timeout in seconds
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_enroll_endpoint() -> String {
+    "/enroll".to_string()
+}
+
+fn default_config_endpoint() -> String {
+    "/config".to_string()
+}
+
+fn default_logger_endpoint() -> String {
+    "/log".to_string()
+}
+
+fn default_distributed_read_endpoint() -> String {
+    "/distributed/read".to_string()
+}
+
+fn default_distributed_write_endpoint() -> String {
+    "/distributed/write".to_string()
+}
+
+fn default_config_refresh_secs() -> u64 {
+    300
+}
+
+fn default_distributed_interval_secs() -> u64 {
+    60
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+impl RemoteConfig {
+    /// Problems with the settings, prefixed with `remote.`
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            errors.push(format!("remote.url: \"{}\" is not an http:// or https:// URL", self.url));
+        }
+        if self.enroll_secret.is_none() && self.enroll_secret_path.is_none() {
+            errors.push("remote: enroll_secret, enroll_secret_path or AGENT_ENROLL_SECRET is required".to_string());
+        }
+        let endpoints = [
+            ("enroll_endpoint", &self.enroll_endpoint),
+            ("config_endpoint", &self.config_endpoint),
+            ("logger_endpoint", &self.logger_endpoint),
+            ("distributed_read_endpoint", &self.distributed_read_endpoint),
+            ("distributed_write_endpoint", &self.distributed_write_endpoint),
+        ];
+        for (name, endpoint) in endpoints {
+            if !endpoint.starts_with('/') {
+                errors.push(format!("remote.{}: \"{}\" must start with /", name, endpoint));
+            }
+        }
+        if self.config_refresh_secs == 0 {
+            errors.push("remote.config_refresh_secs: must be at least 1".to_string());
+        }
+        if self.timeout_secs == 0 {
+            errors.push("remote.timeout_secs: must be at least 1".to_string());
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    #[error("{url}: {reason}")]
+    Transport { url: String, reason: String },
+
+    #[error("{url} answered HTTP {status}: {body}")]
+    Status { url: String, status: u16, body: String },
+
+    #[error("{url} returned an unusable response: {reason}")]
+    Response { url: String, reason: String },
+
+    /// The server no longer accepts the node key
+    #[error("{url} rejected the node key")]
+    NodeInvalid { url: String },
+
+    #[error("Enrollment with {url} was refused: {reason}")]
+    Enroll { url: String, reason: String },
+}
+
+/// One query of the server's schedule
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ScheduledQuery {
+    pub query: String,
+    /// Seconds between runs
+    pub interval: u64,
+    /// Log all rows on every run instead of added/removed rows
+    #[serde(default)]
+    pub snapshot: bool,
+    /// Log removed rows; only added rows otherwise
+    #[serde(default = "default_true")]
+    pub removed: bool,
+    /// Comma-separated platforms the query runs on, e.g. `linux,darwin`
+    #[serde(default)]
+    pub platform: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A result record in osquery's event format
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResultLog<'a> {
+    name: &'a str,
+    host_identifier: &'a str,
+    calendar_time: &'a str,
+    unix_time: u64,
+    epoch: u64,
+    counter: u64,
+    numerics: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    columns: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<&'a [Value]>,
+    action: &'a str,
+}
+
+/// Last run of a scheduled query, which the next run is compared against.
+/// Persisted by the daemon's state store, keyed by query name.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct QueryResults {
+    /// SQL the rows came from; results of a changed query are discarded
+    pub query: String,
+    pub counter: u64,
+    /// Rows by their JSON text; empty for snapshot queries
+    #[serde(default)]
+    pub rows: BTreeMap<String, Value>,
+}
+
+pub struct RemoteClient {
+    config: RemoteConfig,
+    http: ureq::Agent,
+    secret: String,
+    schedule: BTreeMap<String, ScheduledQuery>,
+    next_run: BTreeMap<String, Instant>,
+    next_config: Instant,
+    next_distributed: Instant,
+    failures: u32,
+    retry_at: Option<Instant>,
+    /// Whether the current poll changed the state
+    state_changed: bool,
+}
+
+impl RemoteClient {
+    /// Reads the enroll secret and CA certificates; no request is made yet
+    pub fn new(config: RemoteConfig) -> Result<RemoteClient> {
+        let secret = match (&config.enroll_secret, &config.enroll_secret_path) {
+            (Some(secret), _) => secret.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read enroll secret {}", path.display()))?
+                .trim()
+                .to_string(),
+            (None, None) => bail!("No enroll secret configured for {}", config.url),
+        };
+        if secret.is_empty() {
+            bail!("The enroll secret for {} is empty", config.url);
+        }
+
+        let mut http = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")));
+        if let Some(path) = &config.server_certs {
+            http = http.tls_config(tls_config(path)?);
+        }
+
+        let now = Instant::now();
+        Ok(RemoteClient {
+            http: http.build(),
+            secret,
+            schedule: BTreeMap::new(),
+            next_run: BTreeMap::new(),
+            next_config: now,
+            next_distributed: now,
+            failures: 0,
+            retry_at: None,
+            state_changed: false,
+            config,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.config.url
+    }
+
+    pub fn schedule(&self) -> &BTreeMap<String, ScheduledQuery> {
+        &self.schedule
+    }
+
+    /// Does whatever is due: enrollment, a config refresh, scheduled queries,
+    /// sending pending results and distributed queries. Failures are logged
+    /// and retried with a growing delay. Returns whether the state changed
+    /// (node key, pending results or query results), so the caller can save it.
+    pub fn poll(&mut self, agent: &dyn Agent, state: &mut AgentState) -> bool {
+        let now = Instant::now();
+        if self.retry_at.is_some_and(|at| now < at) {
+            return false;
+        }
+        self.state_changed = false;
+        match self.run_due(agent, state, now) {
+            Ok(()) => {
+                if self.failures > 0 {
+                    log::info!("Remote server {} reachable again", self.config.url);
+                }
+                self.failures = 0;
+                self.retry_at = None;
+            }
+            Err(e) => {
+                self.failures = self.failures.saturating_add(1);
+                let delay = 1u64.checked_shl(self.failures.min(16)).unwrap_or(u64::MAX).min(MAX_RETRY_SECS);
+                log::warn!("Remote server: {}; retrying in {}s", e, delay);
+                self.retry_at = Some(now + Duration::from_secs(delay));
+            }
+        }
+        self.state_changed
+    }
+
+    fn run_due(&mut self, agent: &dyn Agent, state: &mut AgentState, now: Instant) -> Result<(), RemoteError> {
+        if state.node_key.is_none() {
+            self.enroll(state)?;
+        }
+        if now >= self.next_config {
+            self.refresh_config(state)?;
+            self.next_config = now + Duration::from_secs(self.config.config_refresh_secs);
+        }
+        self.run_schedule(agent, state, now);
+        self.send_results(state)?;
+        if self.config.distributed_interval_secs > 0 && now >= self.next_distributed {
+            self.run_distributed(agent, state)?;
+            self.next_distributed = now + Duration::from_secs(self.config.distributed_interval_secs);
+        }
+        Ok(())
+    }
+
+    fn enroll(&mut self, state: &mut AgentState) -> Result<(), RemoteError> {
+        let host_identifier = self.host_identifier(state);
+        let mut host_details = Map::new();
+        if let Some(snapshot) = &state.last_snapshot {
+            let info = &snapshot.system_info;
+            host_details.insert("os_version".to_string(), serde_json::to_value(&info.os_version).unwrap_or_default());
+            host_details.insert("system_info".to_string(), serde_json::to_value(&info.system_info).unwrap_or_default());
+            host_details.insert("osquery_info".to_string(), json!({ "version": snapshot.osquery_version }));
+        }
+        let body = json!({
+            "enroll_secret": self.secret,
+            "host_identifier": host_identifier,
+            "platform_type": platform_type(Platform::current()),
+            "host_details": host_details,
+        });
+
+        let url = self.endpoint_url(&self.config.enroll_endpoint);
+        let response = match self.post(&self.config.enroll_endpoint, &body) {
+            Err(RemoteError::NodeInvalid { .. }) => {
+                return Err(RemoteError::Enroll {
+                    url,
+                    reason: "node_invalid (wrong enroll secret?)".to_string(),
+                })
+            }
+            other => other?,
+        };
+        match response.get("node_key").and_then(Value::as_str) {
+            Some(node_key) if !node_key.is_empty() => {
+                log::info!("Enrolled with {} as {}", self.config.url, host_identifier);
+                state.node_key = Some(node_key.to_string());
+                self.state_changed = true;
+                Ok(())
+            }
+            _ => Err(RemoteError::Response {
+                url,
+                reason: "no node_key in the enroll response".to_string(),
+            }),
+        }
+    }
+
+    fn refresh_config(&mut self, state: &mut AgentState) -> Result<(), RemoteError> {
+        let endpoint = self.config.config_endpoint.clone();
+        let config = self.call(state, &endpoint, Map::new())?;
+        let schedule = parse_schedule(&config);
+        if schedule == self.schedule {
+            return Ok(());
+        }
+
+        log::info!("Schedule from {}: {} query(ies)", self.config.url, schedule.len());
+        // New and changed queries run right away; results of changed SQL start over
+        let before = state.schedule_results.len();
+        state
+            .schedule_results
+            .retain(|name, results| schedule.get(name).is_some_and(|query| query.query == results.query));
+        self.state_changed |= state.schedule_results.len() != before;
+        self.next_run.retain(|name, _| self.schedule.get(name) == schedule.get(name));
+        self.schedule = schedule;
+        Ok(())
+    }
+
+    fn run_schedule(&mut self, agent: &dyn Agent, state: &mut AgentState, now: Instant) {
+        let host_identifier = self.host_identifier(state);
+        for (name, query) in &self.schedule {
+            if self.next_run.get(name).is_some_and(|at| now < *at) {
+                continue;
+            }
+            self.next_run.insert(name.clone(), now + Duration::from_secs(query.interval));
+
+            let rows = match agent.query(&query.query) {
+                Ok(rows) => rows,
+                Err(e) => {
+                    log::warn!("Scheduled query '{}' failed: {}", name, e);
+                    continue;
+                }
+            };
+            let (records, results) = result_logs(name, query, &host_identifier, state.schedule_results.get(name), rows);
+            log::debug!("Scheduled query '{}': {} result record(s)", name, records.len());
+            state.schedule_results.insert(name.clone(), results);
+            state.pending.extend(records);
+            self.state_changed = true;
+        }
+    }
+
+    /// Sends pending results to `/log`, oldest first; whatever is not
+    /// accepted stays pending
+    fn send_results(&mut self, state: &mut AgentState) -> Result<(), RemoteError> {
+        let endpoint = self.config.logger_endpoint.clone();
+        while !state.pending.is_empty() {
+            let count = state.pending.len().min(MAX_LOG_LINES);
+            let data: Vec<Value> = state.pending.iter().take(count).cloned().collect();
+            let mut body = Map::new();
+            body.insert("log_type".to_string(), json!("result"));
+            body.insert("data".to_string(), Value::Array(data));
+            self.call(state, &endpoint, body)?;
+            state.pending.drain(..count);
+            self.state_changed = true;
+            log::debug!("Sent {} result record(s) to {}", count, self.config.url);
+        }
+        Ok(())
+    }
+
+    fn run_distributed(&mut self, agent: &dyn Agent, state: &mut AgentState) -> Result<(), RemoteError> {
+        let endpoint = self.config.distributed_read_endpoint.clone();
+        let response = self.call(state, &endpoint, Map::new())?;
+        let Some(queries) = response.get("queries").and_then(Value::as_object).filter(|q| !q.is_empty()) else {
+            return Ok(());
+        };
+
+        let mut results = Map::new();
+        let mut statuses = Map::new();
+        let mut messages = Map::new();
+        for (id, sql) in queries {
+            let outcome = match sql.as_str() {
+                Some(sql) => agent.query(sql).map_err(|e| e.to_string()),
+                None => Err("query is not a string".to_string()),
+            };
+            match outcome {
+                Ok(rows) => {
+                    results.insert(id.clone(), Value::Array(rows));
+                    statuses.insert(id.clone(), json!(0));
+                }
+                Err(message) => {
+                    results.insert(id.clone(), json!([]));
+                    statuses.insert(id.clone(), json!(1));
+                    messages.insert(id.clone(), json!(message));
+                }
+            }
+        }
+        log::info!("Ran {} distributed query(ies) from {}", queries.len(), self.config.url);
+
+        let mut body = Map::new();
+        body.insert("queries".to_string(), Value::Object(results));
+        body.insert("statuses".to_string(), Value::Object(statuses));
+        body.insert("messages".to_string(), Value::Object(messages));
+        let endpoint = self.config.distributed_write_endpoint.clone();
+        self.call(state, &endpoint, body)?;
+        Ok(())
+    }
+
+    /// POSTs `body` with the node key, re-enrolling once if the key is rejected
+    fn call(&mut self, state: &mut AgentState, endpoint: &str, mut body: Map<String, Value>) -> Result<Value, RemoteError> {
+        let mut reenrolled = false;
+        loop {
+            if state.node_key.is_none() {
+                self.enroll(state)?;
+            }
+            let node_key = state.node_key.clone().unwrap_or_default();
+            body.insert("node_key".to_string(), Value::String(node_key));
+            match self.post(endpoint, &Value::Object(body.clone())) {
+                Err(RemoteError::NodeInvalid { .. }) if !reenrolled => {
+                    log::warn!("{} reports the node key as invalid; enrolling again", self.config.url);
+                    state.node_key = None;
+                    self.state_changed = true;
+                    reenrolled = true;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn post(&self, endpoint: &str, body: &Value) -> Result<Value, RemoteError> {
+        let url = self.endpoint_url(endpoint);
+        let result = self
+            .http
+            .post(&url)
+            .set("Content-Type", "application/json")
+            .set("Accept", "application/json")
+            .send_string(&body.to_string());
+        let (status, text) = match result {
+            Ok(response) => (response.status(), response.into_string()),
+            Err(ureq::Error::Status(status, response)) => (status, response.into_string()),
+            Err(ureq::Error::Transport(transport)) => {
+                return Err(RemoteError::Transport {
+                    url,
+                    reason: transport_reason(&transport),
+                })
+            }
+        };
+        let text = text.map_err(|e| RemoteError::Transport {
+            url: url.clone(),
+            reason: e.to_string(),
+        })?;
+
+        let json = if text.trim().is_empty() { Ok(json!({})) } else { serde_json::from_str::<Value>(&text) };
+        // Servers answer node_invalid with 200 or with 401
+        if let Ok(json) = &json {
+            if json.get("node_invalid").and_then(Value::as_bool) == Some(true) {
+                return Err(RemoteError::NodeInvalid { url });
+            }
+        }
+        if !(200..300).contains(&status) {
+            return Err(RemoteError::Status {
+                url,
+                status,
+                body: text.chars().take(200).collect(),
+            });
+        }
+        json.map_err(|e| RemoteError::Response {
+            url,
+            reason: e.to_string(),
+        })
+    }
+
+    fn endpoint_url(&self, endpoint: &str) -> String {
+        format!("{}{}", self.config.url.trim_end_matches('/'), endpoint)
+    }
+
+    fn host_identifier(&self, state: &AgentState) -> String {
+        self.config
+            .host_identifier
+            .clone()
+            .or_else(|| state.last_snapshot.as_ref().map(|snapshot| snapshot.host_id.clone()))
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+/// Queries of the `schedule` and of inline `packs` (named
+/// `pack_<pack>_<query>` like osquery does) that run on this platform
+fn parse_schedule(config: &Value) -> BTreeMap<String, ScheduledQuery> {
+    let mut schedule = BTreeMap::new();
+    let mut add = |name: String, entry: &Value, pack_platform: Option<&str>| {
+        let query: ScheduledQuery = match serde_json::from_value(entry.clone()) {
+            Ok(query) => query,
+            Err(e) => {
+                log::warn!("Ignoring scheduled query '{}': {}", name, e);
+                return;
+            }
+        };
+        if query.interval == 0 {
+            log::warn!("Ignoring scheduled query '{}': interval must be at least 1", name);
+            return;
+        }
+        let platform = query.platform.as_deref().or(pack_platform);
+        if platform.is_none_or(runs_on_this_platform) {
+            schedule.insert(name, query);
+        }
+    };
+
+    if let Some(queries) = config.get("schedule").and_then(Value::as_object) {
+        for (name, entry) in queries {
+            add(name.clone(), entry, None);
+        }
+    }
+    if let Some(packs) = config.get("packs").and_then(Value::as_object) {
+        for (pack, body) in packs {
+            let Some(queries) = body.get("queries").and_then(Value::as_object) else {
+                log::warn!("Ignoring pack '{}': only inline packs are supported", pack);
+                continue;
+            };
+            let platform = body.get("platform").and_then(Value::as_str);
+            for (name, entry) in queries {
+                add(format!("pack_{}_{}", pack, name), entry, platform);
+            }
+        }
+    }
+    schedule
+}
+
+/// Compares `rows` with the previous run and returns the records to log (one
+/// `snapshot` record, or an `added`/`removed` record per changed row) and
+/// the results to compare the next run against
+fn result_logs(
+    name: &str,
+    query: &ScheduledQuery,
+    host_identifier: &str,
+    previous: Option<&QueryResults>,
+    rows: Vec<Value>,
+) -> (Vec<Value>, QueryResults) {
+    let unix_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let calendar_time = calendar_time(unix_time);
+    let record = |columns: Option<&Value>, snapshot: Option<&[Value]>, action: &str, counter: u64| {
+        serde_json::to_value(ResultLog {
+            name,
+            host_identifier,
+            calendar_time: &calendar_time,
+            unix_time,
+            epoch: 0,
+            counter,
+            numerics: false,
+            columns,
+            snapshot,
+            action,
+        })
+        .unwrap_or_default()
+    };
+
+    let counter = previous.map_or(0, |previous| previous.counter + 1);
+    let mut results = QueryResults {
+        query: query.query.clone(),
+        counter,
+        rows: BTreeMap::new(),
+    };
+    if query.snapshot {
+        return (vec![record(None, Some(&rows), "snapshot", counter)], results);
+    }
+
+    results.rows = rows.into_iter().map(|row| (row.to_string(), row)).collect();
+    let empty = BTreeMap::new();
+    let old = previous.map_or(&empty, |previous| &previous.rows);
+    let mut records = Vec::new();
+    for (key, row) in &results.rows {
+        if !old.contains_key(key) {
+            records.push(record(Some(row), None, "added", counter));
+        }
+    }
+    if query.removed {
+        for (key, row) in old {
+            if !results.rows.contains_key(key) {
+                records.push(record(Some(row), None, "removed", counter));
+            }
+        }
+    }
+    (records, results)
+}
+
+/// osquery's `platform` values: `any`/`all`, `posix`, `linux`, `darwin`, `windows`
+fn runs_on_this_platform(platforms: &str) -> bool {
+    let current = Platform::current();
+    platforms.split(',').map(str::trim).any(|platform| match platform {
+        "" | "any" | "all" => true,
+        "posix" => current != Platform::Windows,
+        "linux" => current == Platform::Linux,
+        "darwin" => current == Platform::MacOs,
+        "windows" => current == Platform::Windows,
+        _ => false,
+    })
+}
+
+/// osquery's platform bitmask as sent when enrolling
+fn platform_type(platform: Platform) -> String {
+    match platform {
+        Platform::Windows => "2",
+        Platform::Linux => "9",
+        Platform::MacOs => "21",
+    }
+    .to_string()
+}
+
+/// `asctime()`-style UTC time as in osquery's `calendarTime`, e.g. `Sat Oct 17 02:31:00 2026 UTC`
+fn calendar_time(unix_time: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let days = (unix_time / 86_400) as i64;
+    let secs = unix_time % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's civil_from_days)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {} UTC",
+        DAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        year
+    )
+}
+
+/// Trusts only the CA certificates in the PEM file at `path`
+fn tls_config(path: &Path) -> Result<Arc<rustls::ClientConfig>> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+
+    let mut roots = rustls::RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to read server certificates {}", path.display()))?;
+    for cert in certs {
+        let cert = cert.with_context(|| format!("Invalid certificate in {}", path.display()))?;
+        roots
+            .add(cert)
+            .with_context(|| format!("Unusable certificate in {}", path.display()))?;
+    }
+    if roots.is_empty() {
+        bail!("No certificates in {}", path.display());
+    }
+    Ok(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    use crate::models::SystemInfo;
+
+    type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// Stand-in remote API server: `handler` answers each request's path and
+    /// JSON body (`Null` for a server error), and every request is recorded
+    fn serve(mut handler: impl FnMut(&str, &Value) -> Value + Send + 'static) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                let body: Value = serde_json::from_slice(&body).unwrap();
+
+                let response = handler(&path, &body);
+                let status = if response.is_null() { "500 Internal Server Error" } else { "200 OK" };
+                let response = response.to_string();
+                recorded.lock().unwrap().push((path, body));
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    /// Answers queries with whatever rows the test set for their SQL
+    #[derive(Default)]
+    struct FakeAgent {
+        rows: Mutex<BTreeMap<String, Vec<Value>>>,
+    }
+
+    impl FakeAgent {
+        fn set(&self, sql: &str, rows: Vec<Value>) {
+            self.rows.lock().unwrap().insert(sql.to_string(), rows);
+        }
+    }
+
+    impl Agent for FakeAgent {
+        fn collect_with(&self, _options: &crate::agent::CollectOptions) -> SystemInfo {
+            SystemInfo::default()
+        }
+
+        fn query(&self, sql: &str) -> Result<Vec<Value>, crate::osquery::OsqueryError> {
+            Ok(self.rows.lock().unwrap().get(sql).cloned().unwrap_or_default())
+        }
+    }
+
+    fn config(url: &str) -> RemoteConfig {
+        serde_json::from_value(json!({
+            "url": url,
+            "enroll_secret": "s3cret",
+            "host_identifier": "test-host",
+            "distributed_interval_secs": 0,
+            "timeout_secs": 5,
+        }))
+        .unwrap()
+    }
+
+    const PROCESSES: &str = "SELECT name FROM processes";
+    const UPTIME: &str = "SELECT total_seconds FROM uptime";
+
+    fn paths(requests: &Requests) -> Vec<String> {
+        requests.lock().unwrap().drain(..).map(|(path, _)| path).collect()
+    }
+
+    /// `(action, columns or snapshot)` of every record posted to `/log`
+    fn logged(requests: &Requests) -> Vec<(String, Value)> {
+        let requests = requests.lock().unwrap();
+        let mut records = Vec::new();
+        for (_, body) in requests.iter().filter(|(path, _)| path == "/log") {
+            assert_eq!(body["log_type"], "result");
+            for record in body["data"].as_array().unwrap() {
+                let data = record.get("columns").or(record.get("snapshot")).unwrap().clone();
+                records.push((record["action"].as_str().unwrap().to_string(), data));
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn enrolls_runs_the_schedule_and_logs_results_across_restarts() {
+        let (url, requests) = serve(|path, body| match path {
+            "/enroll" => {
+                assert_eq!(body["enroll_secret"], "s3cret");
+                assert_eq!(body["host_identifier"], "test-host");
+                json!({"node_key": "key-1"})
+            }
+            "/config" => {
+                assert_eq!(body["node_key"], "key-1");
+                json!({"schedule": {
+                    "procs": {"query": PROCESSES, "interval": 60},
+                    "uptime": {"query": UPTIME, "interval": 60, "snapshot": true},
+                }})
+            }
+            _ => json!({}),
+        });
+        let agent = FakeAgent::default();
+        agent.set(PROCESSES, vec![json!({"name": "init"}), json!({"name": "sshd"})]);
+        agent.set(UPTIME, vec![json!({"total_seconds": "10"})]);
+
+        let mut state = AgentState::default();
+        let mut client = RemoteClient::new(config(&url)).unwrap();
+        assert!(client.poll(&agent, &mut state));
+        assert_eq!(state.node_key.as_deref(), Some("key-1"));
+        assert!(state.pending.is_empty());
+        assert_eq!(
+            logged(&requests),
+            vec![
+                ("added".to_string(), json!({"name": "init"})),
+                ("added".to_string(), json!({"name": "sshd"})),
+                ("snapshot".to_string(), json!([{"total_seconds": "10"}])),
+            ]
+        );
+        assert_eq!(paths(&requests), vec!["/enroll", "/config", "/log"]);
+
+        // A restart keeps the node key and compares with the saved results
+        let mut state: AgentState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        agent.set(PROCESSES, vec![json!({"name": "init"}), json!({"name": "cron"})]);
+        let mut client = RemoteClient::new(config(&url)).unwrap();
+        assert!(client.poll(&agent, &mut state));
+        assert_eq!(
+            logged(&requests),
+            vec![
+                ("added".to_string(), json!({"name": "cron"})),
+                ("removed".to_string(), json!({"name": "sshd"})),
+                ("snapshot".to_string(), json!([{"total_seconds": "10"}])),
+            ]
+        );
+        assert_eq!(paths(&requests), vec!["/config", "/log"]);
+        assert_eq!(state.schedule_results["procs"].counter, 1);
+    }
+
+    #[test]
+    fn enrolls_again_when_the_node_key_is_invalid() {
+        let (url, requests) = serve(|path, body| match (path, body["node_key"].as_str()) {
+            ("/enroll", _) => json!({"node_key": "key-2"}),
+            (_, Some("key-2")) => json!({"schedule": {}}),
+            _ => json!({"node_invalid": true}),
+        });
+        let mut state = AgentState {
+            node_key: Some("revoked".to_string()),
+            ..AgentState::default()
+        };
+        let mut client = RemoteClient::new(config(&url)).unwrap();
+        assert!(client.poll(&FakeAgent::default(), &mut state));
+
+        assert_eq!(state.node_key.as_deref(), Some("key-2"));
+        assert_eq!(paths(&requests), vec!["/config", "/enroll", "/config"]);
+    }
+
+    #[test]
+    fn keeps_results_pending_while_the_log_endpoint_fails() {
+        let failing = Arc::new(Mutex::new(true));
+        let fail = failing.clone();
+        let (url, requests) = serve(move |path, _| match path {
+            "/enroll" => json!({"node_key": "key-1"}),
+            "/config" => json!({"schedule": {"procs": {"query": PROCESSES, "interval": 60}}}),
+            "/log" if *fail.lock().unwrap() => Value::Null,
+            _ => json!({}),
+        });
+        let agent = FakeAgent::default();
+        agent.set(PROCESSES, vec![json!({"name": "init"})]);
+
+        let mut state = AgentState::default();
+        let mut client = RemoteClient::new(config(&url)).unwrap();
+        assert!(client.poll(&agent, &mut state));
+        assert_eq!(state.pending.len(), 1);
+        assert_eq!(state.schedule_results["procs"].counter, 0);
+        requests.lock().unwrap().clear();
+
+        // Backing off, then a restart sends the queued record once the server recovers
+        assert!(!client.poll(&agent, &mut state));
+        *failing.lock().unwrap() = false;
+        let mut client = RemoteClient::new(config(&url)).unwrap();
+        assert!(client.poll(&agent, &mut state));
+        assert!(state.pending.is_empty());
+        assert_eq!(logged(&requests), vec![("added".to_string(), json!({"name": "init"}))]);
+    }
+}
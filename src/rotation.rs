@@ -0,0 +1,571 @@
+// ============================================================================
+// Rotating JSON-Lines Writer
+// ============================================================================
+//
+// Appends JSON lines to an active file and turns it into a closed segment
+// once it grows too large or too old. Segments are named after the active
+// file plus the Unix time in milliseconds they were closed at, and can be
+// compressed:
+//
+//   events.jsonl                      active file
+//   events.jsonl.1792203300821.gz     closed segments, oldest first
+//   events.jsonl.1792206900133.gz
+//
+// Crash safety:
+// - every write is one buffer of whole lines followed by fsync; a torn
+//   trailing line left by a crash is cut off when the file is reopened, so a
+//   segment never contains a partial record
+// - rotation renames the active file (atomic), then compresses it into a
+//   temporary file that is synced and renamed into place before the
+//   uncompressed segment is removed; leftovers of an interrupted rotation are
+//   finished or cleaned up on the next open
+// - retention removes whole segments only, oldest first
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::state::sync_dir;
+
+/// Compression applied to closed segments
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+}
+
+/// When to rotate and which segments to keep
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotationPolicy {
+    /// Rotate before the active file would grow past this size (0: never)
+    pub max_bytes: u64,
+    /// Rotate once the active file is this old
+    pub max_age: Option<Duration>,
+    pub compression: Compression,
+    /// Closed segments kept, newest first
+    pub max_files: Option<usize>,
+    /// Total size of closed segments kept
+    pub retain_bytes: Option<u64>,
+    /// Closed segments older than this are removed
+    pub retain_age: Option<Duration>,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy {
+            max_bytes: 100 * 1024 * 1024,
+            max_age: None,
+            compression: Compression::None,
+            max_files: Some(5),
+            retain_bytes: None,
+            retain_age: None,
+        }
+    }
+}
+
+/// A closed segment on disk
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub path: PathBuf,
+    /// Unix time in milliseconds the segment was closed at
+    pub closed_at_ms: u64,
+    pub size: u64,
+}
+
+pub struct RotatingWriter {
+    path: PathBuf,
+    policy: RotationPolicy,
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+impl RotatingWriter {
+    /// Opens (or creates) the active file at `path`, repairing a torn last
+    /// line and finishing any rotation a crash interrupted
+    pub fn open(path: impl AsRef<Path>, policy: RotationPolicy) -> Result<RotatingWriter> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let size = repair_torn_line(&mut file, &path)?;
+
+        let mut writer = RotatingWriter {
+            path,
+            policy,
+            file,
+            size,
+            opened_at: SystemTime::now(),
+        };
+        writer.recover()?;
+        // Before retention, which may remove the newest segment
+        writer.opened_at = writer.started_at()?;
+        writer.apply_retention()?;
+        Ok(writer)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends whole lines (without trailing newlines) and syncs them,
+    /// rotating first when the policy says so
+    pub fn write_lines(&mut self, lines: &[String]) -> Result<()> {
+        let mut buffer = Vec::with_capacity(lines.iter().map(|line| line.len() + 1).sum());
+        for line in lines {
+            buffer.extend_from_slice(line.as_bytes());
+            buffer.push(b'\n');
+        }
+        if self.size > 0 && self.due(buffer.len() as u64) {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(&buffer)
+            .and_then(|_| self.file.sync_data())
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        self.size += buffer.len() as u64;
+        Ok(())
+    }
+
+    /// When the first record of the active file was written, so a restart
+    /// does not restart the `max_age` clock: the newest segment was closed
+    /// just before, and before the first rotation the file's birth time (or
+    /// its last write, where the filesystem keeps no birth time) stands in
+    fn started_at(&self) -> Result<SystemTime> {
+        if self.size == 0 {
+            return Ok(SystemTime::now());
+        }
+        if let Some(newest) = self.segments()?.last() {
+            return Ok(UNIX_EPOCH + Duration::from_millis(newest.closed_at_ms));
+        }
+        let metadata = self
+            .file
+            .metadata()
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        Ok(metadata.created().or_else(|_| metadata.modified()).unwrap_or_else(|_| SystemTime::now()))
+    }
+
+    fn due(&self, incoming: u64) -> bool {
+        let too_big = self.policy.max_bytes > 0 && self.size + incoming > self.policy.max_bytes;
+        let too_old = self
+            .policy
+            .max_age
+            .is_some_and(|max_age| self.opened_at.elapsed().unwrap_or_default() >= max_age);
+        too_big || too_old
+    }
+
+    /// Closes the active file as a new segment and starts an empty one
+    pub fn rotate(&mut self) -> Result<()> {
+        // Segment names must increase even for rotations within the same
+        // millisecond, or retention would take a new segment for the oldest
+        let newest = self.segment_files()?.into_iter().map(|(_, closed_at_ms, _)| closed_at_ms).max();
+        let closed_at_ms = newest.map_or(now_ms(), |newest| now_ms().max(newest + 1));
+        let segment = self.segment_path(closed_at_ms, None);
+        fs::rename(&self.path, &segment).with_context(|| format!("Failed to rotate {}", self.path.display()))?;
+        sync_dir(&self.path);
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        self.size = 0;
+        self.opened_at = SystemTime::now();
+
+        // The new active file is in place; compression and retention problems
+        // must not cost the records being written, and are retried on the next open
+        let segment = self.compress(&segment).unwrap_or_else(|e| {
+            log::error!("{:#}", e);
+            segment
+        });
+        log::info!("Rotated {} to {}", self.path.display(), segment.display());
+        if let Err(e) = self.apply_retention() {
+            log::error!("{:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Closed segments, oldest first
+    pub fn segments(&self) -> Result<Vec<Segment>> {
+        let mut segments: Vec<Segment> = self
+            .segment_files()?
+            .into_iter()
+            .filter(|(_, _, suffix)| !suffix.ends_with(".tmp"))
+            .map(|(path, closed_at_ms, _)| {
+                let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                Segment {
+                    path,
+                    closed_at_ms,
+                    size,
+                }
+            })
+            .collect();
+        segments.sort_by_key(|segment| segment.closed_at_ms);
+        Ok(segments)
+    }
+
+    /// Removes closed segments beyond the retention limits, oldest first
+    fn apply_retention(&self) -> Result<()> {
+        let segments = self.segments()?;
+        let now = now_ms();
+        let mut total: u64 = segments.iter().map(|segment| segment.size).sum();
+
+        for (i, segment) in segments.iter().enumerate() {
+            let count_left = segments.len() - i;
+            let too_old = self
+                .policy
+                .retain_age
+                .is_some_and(|age| now.saturating_sub(segment.closed_at_ms) > age.as_millis() as u64);
+            let too_many = self.policy.max_files.is_some_and(|max| count_left > max);
+            let too_big = self.policy.retain_bytes.is_some_and(|max| total > max);
+            if !(too_old || too_many || too_big) {
+                break;
+            }
+            fs::remove_file(&segment.path).with_context(|| format!("Failed to remove {}", segment.path.display()))?;
+            log::info!("Removed old segment {}", segment.path.display());
+            total -= segment.size;
+        }
+        Ok(())
+    }
+
+    /// Finishes rotations a crash interrupted: stray temporary files are
+    /// removed and segments that were not compressed yet are compressed
+    fn recover(&self) -> Result<()> {
+        let files = self.segment_files()?;
+        for (path, closed_at_ms, suffix) in &files {
+            if suffix.ends_with(".tmp") {
+                log::warn!("Removing incomplete {}", path.display());
+                fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            } else if suffix.is_empty() && self.policy.compression != Compression::None {
+                let compressed = self.segment_path(*closed_at_ms, self.policy.compression.extension());
+                if compressed.exists() {
+                    // Compressed copy was already synced and renamed into place
+                    fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+                } else {
+                    self.compress(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compresses an uncompressed segment in place, returning its final path
+    fn compress(&self, segment: &Path) -> Result<PathBuf> {
+        let Some(extension) = self.policy.compression.extension() else {
+            return Ok(segment.to_path_buf());
+        };
+        let mut target = segment.as_os_str().to_owned();
+        target.push(format!(".{}", extension));
+        let target = PathBuf::from(target);
+        let mut tmp = target.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut input = File::open(segment).with_context(|| format!("Failed to open {}", segment.display()))?;
+        let output = File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+        let output = match self.policy.compression {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+                io::copy(&mut input, &mut encoder).and_then(|_| encoder.finish())
+            }
+            Compression::Zstd => zstd::stream::Encoder::new(output, 0)
+                .and_then(|mut encoder| io::copy(&mut input, &mut encoder).and_then(|_| encoder.finish())),
+            Compression::None => unreachable!("checked above"),
+        }
+        .with_context(|| format!("Failed to compress {}", segment.display()))?;
+        output.sync_all().with_context(|| format!("Failed to sync {}", tmp.display()))?;
+        drop(output);
+
+        fs::rename(&tmp, &target).with_context(|| format!("Failed to rename {}", tmp.display()))?;
+        sync_dir(&target);
+        fs::remove_file(segment).with_context(|| format!("Failed to remove {}", segment.display()))?;
+        Ok(target)
+    }
+
+    fn segment_path(&self, closed_at_ms: u64, extension: Option<&str>) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", closed_at_ms));
+        if let Some(extension) = extension {
+            name.push(format!(".{}", extension));
+        }
+        PathBuf::from(name)
+    }
+
+    /// `(path, closed_at_ms, suffix)` of every file named `<active>.<digits><suffix>`
+    fn segment_files(&self) -> Result<Vec<(PathBuf, u64, String)>> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = format!(
+            "{}.",
+            self.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+        );
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("Failed to list {}", dir.display()))? {
+            let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(rest) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let Ok(closed_at_ms) = rest[..digits].parse::<u64>() else {
+                continue;
+            };
+            files.push((entry.path(), closed_at_ms, rest[digits..].to_string()));
+        }
+        Ok(files)
+    }
+}
+
+/// Cuts the file back to its last complete line; returns the resulting size
+fn repair_torn_line(file: &mut File, path: &Path) -> Result<u64> {
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    if size == 0 {
+        return Ok(0);
+    }
+
+    // Walk back from the end in chunks to find the last newline
+    let mut end = size;
+    let mut chunk = vec![0u8; 8192];
+    let keep = loop {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let len = (end - start) as usize;
+        file.seek(SeekFrom::Start(start))
+            .and_then(|_| file.read_exact(&mut chunk[..len]))
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if let Some(pos) = chunk[..len].iter().rposition(|&b| b == b'\n') {
+            break start + pos as u64 + 1;
+        }
+        if start == 0 {
+            break 0;
+        }
+        end = start;
+    };
+
+    if keep < size {
+        log::warn!(
+            "{} ends with an incomplete record ({} bytes); removing it",
+            path.display(),
+            size - keep
+        );
+        file.set_len(keep)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed to truncate {}", path.display()))?;
+    }
+    Ok(keep)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-rotation-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn policy(change: impl FnOnce(&mut RotationPolicy)) -> RotationPolicy {
+        let mut policy = RotationPolicy {
+            max_files: None,
+            ..RotationPolicy::default()
+        };
+        change(&mut policy);
+        policy
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    fn decode(segment: &Path) -> String {
+        let file = File::open(segment).unwrap();
+        let mut text = String::new();
+        match segment.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => flate2::read::GzDecoder::new(file).read_to_string(&mut text).unwrap(),
+            Some("zst") => zstd::stream::Decoder::new(file).unwrap().read_to_string(&mut text).unwrap(),
+            _ => { file }.read_to_string(&mut text).unwrap(),
+        };
+        text
+    }
+
+    /// Creates a closed segment as if it was rotated `age` ago
+    fn old_segment(active: &Path, age: Duration, text: &str) -> PathBuf {
+        let closed_at_ms = now_ms() - age.as_millis() as u64;
+        let path = PathBuf::from(format!("{}.{}", active.display(), closed_at_ms));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn rotates_before_the_file_grows_past_max_bytes() {
+        let dir = scratch("size");
+        let path = dir.join("events.jsonl");
+        let mut writer = RotatingWriter::open(&path, policy(|p| p.max_bytes = 25)).unwrap();
+        writer.write_lines(&lines(&["123456789", "abcdefghi"])).unwrap();
+        assert!(writer.segments().unwrap().is_empty());
+
+        writer.write_lines(&lines(&["ABCDEFGHI"])).unwrap();
+        let segments = writer.segments().unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(decode(&segments[0].path), "123456789\nabcdefghi\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ABCDEFGHI\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compressed_segments_decode_to_the_written_lines() {
+        for (compression, extension) in [(Compression::Gzip, "gz"), (Compression::Zstd, "zst")] {
+            let dir = scratch(extension);
+            let path = dir.join("events.jsonl");
+            let mut writer = RotatingWriter::open(&path, policy(|p| p.compression = compression)).unwrap();
+            writer.write_lines(&lines(&[r#"{"a":1}"#, r#"{"b":2}"#])).unwrap();
+            writer.rotate().unwrap();
+
+            let segments = writer.segments().unwrap();
+            assert_eq!(segments.len(), 1);
+            assert_eq!(segments[0].path.extension().unwrap(), extension);
+            assert_eq!(decode(&segments[0].path), "{\"a\":1}\n{\"b\":2}\n");
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn retention_removes_the_oldest_segments() {
+        let dir = scratch("max-files");
+        let path = dir.join("events.jsonl");
+        let mut writer = RotatingWriter::open(&path, policy(|p| p.max_files = Some(2))).unwrap();
+        for line in ["1", "2", "3", "4"] {
+            writer.write_lines(&lines(&[line])).unwrap();
+            writer.rotate().unwrap();
+        }
+        let kept: Vec<String> = writer.segments().unwrap().iter().map(|s| decode(&s.path)).collect();
+        assert_eq!(kept, ["3\n", "4\n"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let dir = scratch("retain-bytes");
+        let path = dir.join("events.jsonl");
+        let mut writer = RotatingWriter::open(&path, policy(|p| p.retain_bytes = Some(7))).unwrap();
+        for line in ["1", "22", "333"] {
+            writer.write_lines(&lines(&[line])).unwrap();
+            writer.rotate().unwrap();
+        }
+        let kept: Vec<String> = writer.segments().unwrap().iter().map(|s| decode(&s.path)).collect();
+        assert_eq!(kept, ["22\n", "333\n"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let dir = scratch("retain-age");
+        let path = dir.join("events.jsonl");
+        let old = old_segment(&path, Duration::from_secs(7200), "old\n");
+        let recent = old_segment(&path, Duration::from_secs(60), "recent\n");
+        let writer = RotatingWriter::open(&path, policy(|p| p.retain_age = Some(Duration::from_secs(3600)))).unwrap();
+        assert!(!old.exists());
+        assert_eq!(writer.segments().unwrap()[0].path, recent);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cuts_off_a_torn_last_line() {
+        let dir = scratch("torn");
+        let path = dir.join("events.jsonl");
+        fs::write(&path, "one\ntwo\nthr").unwrap();
+        let mut file = OpenOptions::new().read(true).append(true).open(&path).unwrap();
+        assert_eq!(repair_torn_line(&mut file, &path).unwrap(), 8);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+
+        // Without any newline nothing is complete
+        fs::write(&path, "x".repeat(20_000)).unwrap();
+        let mut file = OpenOptions::new().read(true).append(true).open(&path).unwrap();
+        assert_eq!(repair_torn_line(&mut file, &path).unwrap(), 0);
+
+        // Writes after reopening start on a line boundary
+        fs::write(&path, "one\ntw").unwrap();
+        let mut writer = RotatingWriter::open(&path, policy(|_| {})).unwrap();
+        writer.write_lines(&lines(&["two"])).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn finishes_an_interrupted_rotation_on_open() {
+        let dir = scratch("recover");
+        let path = dir.join("events.jsonl");
+        let stray = PathBuf::from(format!("{}.1000.gz.tmp", path.display()));
+        fs::write(&stray, "partial").unwrap();
+        let uncompressed = old_segment(&path, Duration::from_secs(60), "a\nb\n");
+        // Compressed copy already in place: only the uncompressed original is left to remove
+        let done = old_segment(&path, Duration::from_secs(30), "c\n");
+        let mut done_gz = done.as_os_str().to_owned();
+        done_gz.push(".gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&done_gz).unwrap(), flate2::Compression::default());
+        encoder.write_all(b"c\n").unwrap();
+        encoder.finish().unwrap();
+
+        let writer = RotatingWriter::open(&path, policy(|p| p.compression = Compression::Gzip)).unwrap();
+        assert!(!stray.exists());
+        assert!(!uncompressed.exists());
+        assert!(!done.exists());
+        let kept: Vec<String> = writer.segments().unwrap().iter().map(|s| decode(&s.path)).collect();
+        assert_eq!(kept, ["a\nb\n", "c\n"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn age_survives_reopening() {
+        let dir = scratch("age");
+        let path = dir.join("events.jsonl");
+        let hourly = || policy(|p| p.max_age = Some(Duration::from_secs(3600)));
+        let mut writer = RotatingWriter::open(&path, hourly()).unwrap();
+        writer.write_lines(&lines(&["1"])).unwrap();
+        drop(writer);
+
+        // Reopening a recent file keeps appending to it
+        let mut writer = RotatingWriter::open(&path, hourly()).unwrap();
+        writer.write_lines(&lines(&["2"])).unwrap();
+        assert!(writer.segments().unwrap().is_empty());
+        drop(writer);
+
+        // The active file was started when the last segment was closed, two hours ago
+        old_segment(&path, Duration::from_secs(7200), "0\n");
+        let mut writer = RotatingWriter::open(&path, hourly()).unwrap();
+        writer.write_lines(&lines(&["3"])).unwrap();
+        let kept: Vec<String> = writer.segments().unwrap().iter().map(|s| decode(&s.path)).collect();
+        assert_eq!(kept, ["0\n", "1\n2\n"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "3\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
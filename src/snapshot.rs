@@ -0,0 +1,234 @@
+// ============================================================================
+// Snapshot Envelope
+// ============================================================================
+//
+// A `Snapshot` wraps one `SystemInfo` collection with what is needed to order,
+// attribute and migrate it later: host ID, collection times, the agent build,
+// the osquery version and a schema version.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::agent::{Agent, CollectOptions};
+use crate::models::SystemInfo;
+
+/// Version of the snapshot JSON layout. Bump it whenever a model change would
+/// break readers of older snapshots.
+pub const SNAPSHOT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub schema_version: u32,
+    pub host_id: String,
+    pub host_id_source: HostIdSource,
+    /// Unix time in milliseconds when collection started
+    pub started_at_ms: u64,
+    /// Unix time in milliseconds when collection finished
+    pub finished_at_ms: u64,
+    pub agent: AgentBuild,
+    pub osquery_version: Option<String>,
+    pub system_info: SystemInfo,
+}
+
+/// Which `SystemDetails` field the host ID was taken from
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostIdSource {
+    /// SMBIOS/hardware UUID
+    Uuid,
+    HardwareSerial,
+    Hostname,
+    /// Nothing usable was collected; the ID is `unknown`
+    Unknown,
+}
+
+/// Build information of the agent that produced a snapshot
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AgentBuild {
+    pub name: String,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    /// `debug` or `release`
+    pub profile: String,
+    /// Set at build time through the `AGENT_GIT_COMMIT` environment variable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+}
+
+impl AgentBuild {
+    /// Build information of this binary
+    pub fn current() -> Self {
+        AgentBuild {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            profile: if cfg!(debug_assertions) { "debug" } else { "release" }.to_string(),
+            git_commit: option_env!("AGENT_GIT_COMMIT").map(String::from),
+        }
+    }
+}
+
+impl Snapshot {
+    /// Runs one collection on `agent` and wraps the result
+    pub fn collect(agent: &dyn Agent, options: &CollectOptions) -> Snapshot {
+        let started_at_ms = now_ms();
+        let system_info = agent.collect_with(options);
+        let finished_at_ms = now_ms();
+        let osquery_version = agent.capabilities().and_then(|capabilities| capabilities.version);
+        Snapshot::new(system_info, started_at_ms, finished_at_ms, osquery_version)
+    }
+
+    /// Wraps an existing collection
+    pub fn new(
+        system_info: SystemInfo,
+        started_at_ms: u64,
+        finished_at_ms: u64,
+        osquery_version: Option<String>,
+    ) -> Snapshot {
+        let (host_id, host_id_source) = host_id(&system_info);
+        Snapshot {
+            schema_version: SNAPSHOT_SCHEMA_VERSION,
+            host_id,
+            host_id_source,
+            started_at_ms,
+            finished_at_ms,
+            agent: AgentBuild::current(),
+            osquery_version,
+            system_info,
+        }
+    }
+
+    /// Parses a snapshot, refusing ones written by a newer schema
+    pub fn from_json(json: &str) -> Result<Snapshot> {
+        #[derive(Deserialize)]
+        struct Version {
+            schema_version: u32,
+        }
+        let version: Version = serde_json::from_str(json).context("Not a snapshot: missing schema_version")?;
+        if version.schema_version > SNAPSHOT_SCHEMA_VERSION {
+            bail!(
+                "Snapshot schema version {} is newer than supported version {}",
+                version.schema_version,
+                SNAPSHOT_SCHEMA_VERSION
+            );
+        }
+        serde_json::from_str(json).context("Invalid snapshot")
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        self.finished_at_ms.saturating_sub(self.started_at_ms)
+    }
+}
+
+/// Hardware UUID when it looks real, otherwise the serial number, otherwise
+/// the hostname. IDs are lowercased so the same host always compares equal.
+pub fn host_id(system_info: &SystemInfo) -> (String, HostIdSource) {
+    let Some(details) = &system_info.system_info else {
+        return ("unknown".to_string(), HostIdSource::Unknown);
+    };
+    let usable = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty() && !is_placeholder_id(v))
+    };
+
+    if let Some(uuid) = usable(&details.uuid) {
+        (uuid, HostIdSource::Uuid)
+    } else if let Some(serial) = usable(&details.hardware_serial) {
+        (serial, HostIdSource::HardwareSerial)
+    } else if let Some(hostname) = usable(&details.hostname)
+        .or_else(|| usable(&details.computer_name))
+        .or_else(|| usable(&details.local_hostname))
+    {
+        (hostname, HostIdSource::Hostname)
+    } else {
+        ("unknown".to_string(), HostIdSource::Unknown)
+    }
+}
+
+/// Values firmware vendors ship instead of a real identifier
+fn is_placeholder_id(value: &str) -> bool {
+    let digits: String = value.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    digits.is_empty()
+        || digits.chars().all(|c| c == '0')
+        || digits.chars().all(|c| c == 'f')
+        || digits == "03000200040005000006000700080009"
+        || matches!(value, "none" | "default string" | "to be filled by o.e.m." | "system serial number" | "0123456789")
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn system_info(details: Value) -> SystemInfo {
+        SystemInfo {
+            system_info: Some(serde_json::from_value(details).unwrap()),
+            ..SystemInfo::default()
+        }
+    }
+
+    #[test]
+    fn placeholder_uuids_fall_through_to_the_next_source() {
+        let cases = [
+            (
+                json!({"uuid": "00000000-0000-0000-0000-000000000000", "hardware_serial": "C02XK1ABJG5H", "hostname": "web-1"}),
+                ("c02xk1abjg5h", HostIdSource::HardwareSerial),
+            ),
+            (
+                json!({"uuid": "03000200-0400-0500-0006-000700080009", "hardware_serial": "To Be Filled By O.E.M.", "hostname": "Web-1"}),
+                ("web-1", HostIdSource::Hostname),
+            ),
+            (
+                json!({"uuid": "FFFFFFFF-FFFF-FFFF-FFFF-FFFFFFFFFFFF", "hardware_serial": "0", "computer_name": "Build Box"}),
+                ("build box", HostIdSource::Hostname),
+            ),
+            (
+                json!({"uuid": " 4C4C4544-0042-3510-8051-B3C04F4E4B32 ", "hardware_serial": "C02XK1ABJG5H"}),
+                ("4c4c4544-0042-3510-8051-b3c04f4e4b32", HostIdSource::Uuid),
+            ),
+            (json!({"uuid": "00000000-0000-0000-0000-000000000000"}), ("unknown", HostIdSource::Unknown)),
+        ];
+        for (details, (id, source)) in cases {
+            assert_eq!(host_id(&system_info(details.clone())), (id.to_string(), source), "{}", details);
+        }
+        assert_eq!(host_id(&SystemInfo::default()), ("unknown".to_string(), HostIdSource::Unknown));
+    }
+
+    #[test]
+    fn from_json_reads_current_snapshots_and_refuses_newer_ones() {
+        let snapshot = Snapshot::new(system_info(json!({"hostname": "web-1"})), 1_000, 1_250, Some("5.12.1".to_string()));
+        let parsed = Snapshot::from_json(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+        assert_eq!(parsed.schema_version, SNAPSHOT_SCHEMA_VERSION);
+        assert_eq!(parsed.host_id, "web-1");
+        assert_eq!(parsed.host_id_source, HostIdSource::Hostname);
+        assert_eq!(parsed.duration_ms(), 250);
+
+        let mut newer = serde_json::to_value(&snapshot).unwrap();
+        newer["schema_version"] = json!(SNAPSHOT_SCHEMA_VERSION + 1);
+        let error = Snapshot::from_json(&newer.to_string()).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "Snapshot schema version {} is newer than supported version {}",
+                SNAPSHOT_SCHEMA_VERSION + 1,
+                SNAPSHOT_SCHEMA_VERSION
+            )
+        );
+
+        let error = Snapshot::from_json(r#"{"host_id": "web-1"}"#).unwrap_err().to_string();
+        assert_eq!(error, "Not a snapshot: missing schema_version");
+    }
+}
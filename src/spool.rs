@@ -0,0 +1,729 @@
+// ============================================================================
+// Outbound Spool
+// ============================================================================
+//
+// A bounded on-disk queue for records waiting to be shipped, so nothing is
+// lost while the collector is unreachable (e.g. a laptop that is offline).
+// Records are appended to numbered segment files (`00000000000000000001.seg`),
+// each framed with its length and a CRC-32 of its bytes. `cursor.json` keeps
+// the position of the first record not delivered yet, so a restarted daemon
+// continues where it stopped. Delivery is at-least-once: a crash between a
+// successful send and saving the cursor sends that batch again.
+//
+// When the spool grows past its cap, whole segments are dropped oldest first
+// and their undelivered records counted; the segment being appended to is
+// always kept.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::http::{HttpShipper, ShipError};
+use crate::state::{sync_dir, write_atomic};
+
+/// Default cap on the total size of the segment files
+pub const DEFAULT_MAX_SPOOL_BYTES: u64 = 256 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "seg";
+const CURSOR_FILE: &str = "cursor.json";
+const LOCK_FILE: &str = "lock";
+
+/// Length and CRC-32, both little-endian u32
+const FRAME_HEADER: u64 = 8;
+
+/// A record position: byte offset of a frame within a segment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Position {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+/// Contents of `cursor.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cursor {
+    #[serde(flatten)]
+    position: Position,
+    /// Undelivered records dropped to stay under the cap, over the spool's lifetime
+    #[serde(default)]
+    dropped: u64,
+}
+
+/// Records read from the spool, in order; `commit` them once delivered
+#[derive(Debug)]
+pub struct Batch {
+    pub lines: Vec<String>,
+    /// Position just after the last record of the batch
+    pub end: Position,
+}
+
+#[derive(Debug)]
+struct Segment {
+    seq: u64,
+    size: u64,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    /// Oldest first, never empty; the last one is appended to
+    segments: VecDeque<Segment>,
+    active: File,
+    cursor: Position,
+    pending: u64,
+    dropped: u64,
+    /// Held for the lifetime of the spool so no other spool uses the directory
+    _lock: File,
+}
+
+impl Spool {
+    /// Opens or creates the spool in `dir`. A record torn by a crash at the
+    /// end of the last segment is cut off. Fails if another spool, in this
+    /// or another process, has the directory open.
+    pub fn open(dir: impl AsRef<Path>, max_bytes: u64) -> Result<Spool> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create spool directory {}", dir.display()))?;
+
+        let lock_path = dir.join(LOCK_FILE);
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => bail!("Spool {} is already in use", dir.display()),
+            Err(fs::TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {}", lock_path.display()))
+            }
+        }
+
+        let mut segments = list_segments(&dir)?;
+        if segments.is_empty() {
+            create_segment(&dir, 1)?;
+            segments.push_back(Segment { seq: 1, size: 0 });
+        }
+        if let Some(last) = segments.back_mut() {
+            last.size = repair_tail(&segment_path(&dir, last.seq), last.size)?;
+        }
+        let last = segments.back().map(|segment| segment.seq).unwrap_or(1);
+        let active = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, last))
+            .with_context(|| format!("Failed to open spool segment {}", segment_path(&dir, last).display()))?;
+
+        let mut spool = Spool {
+            segment_bytes: (max_bytes / 8).clamp(64 * 1024, 16 * 1024 * 1024),
+            dir,
+            max_bytes,
+            segments,
+            active,
+            cursor: Position::default(),
+            pending: 0,
+            dropped: 0,
+            _lock: lock,
+        };
+        let cursor = spool.load_cursor();
+        spool.cursor = spool.clamp(cursor.position);
+        spool.dropped = cursor.dropped;
+        spool.pending = spool.count_from(spool.cursor)?;
+        Ok(spool)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Records not delivered yet
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    /// Undelivered records dropped to stay under the cap
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Total size of the segment files
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    /// Appends `lines` as one synced write, then drops the oldest segments
+    /// while the spool is over its cap
+    pub fn append(&mut self, lines: &[String]) -> Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let mut buffer = Vec::with_capacity(lines.iter().map(|line| line.len() + FRAME_HEADER as usize).sum());
+        for line in lines {
+            buffer.extend_from_slice(&(line.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&crc32fast::hash(line.as_bytes()).to_le_bytes());
+            buffer.extend_from_slice(line.as_bytes());
+        }
+
+        let active_size = self.segments.back().map(|segment| segment.size).unwrap_or(0);
+        if active_size > 0 && active_size + buffer.len() as u64 > self.segment_bytes {
+            self.start_segment()?;
+        }
+        let Some(active) = self.segments.back_mut() else {
+            bail!("Spool {} has no segment to append to", self.dir.display());
+        };
+        if let Err(e) = self.active.write_all(&buffer).and_then(|_| self.active.sync_data()) {
+            // Cut off whatever part of the write landed so the next append starts on a frame boundary
+            let _ = self.active.set_len(active.size);
+            return Err(e).with_context(|| format!("Failed to write spool segment {}", active.seq));
+        }
+        active.size += buffer.len() as u64;
+        self.pending += lines.len() as u64;
+        self.enforce_cap()
+    }
+
+    /// Reads up to `max` records from the cursor on, without moving it
+    pub fn read_batch(&mut self, max: usize) -> Result<Batch> {
+        let mut lines = Vec::new();
+        let mut position = self.cursor;
+        while lines.len() < max {
+            let Some(index) = self.segments.iter().position(|segment| segment.seq == position.segment) else {
+                break;
+            };
+            let size = self.segments[index].size;
+            if position.offset >= size {
+                match self.segments.get(index + 1) {
+                    Some(next) => {
+                        position = Position {
+                            segment: next.seq,
+                            offset: 0,
+                        };
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+
+            let path = segment_path(&self.dir, position.segment);
+            let mut file = File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+            file.seek(SeekFrom::Start(position.offset))
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let mut reader = BufReader::new(file);
+            while lines.len() < max && position.offset < size {
+                match read_frame(&mut reader, size - position.offset) {
+                    Ok(Some(line)) => {
+                        position.offset += FRAME_HEADER + line.len() as u64;
+                        lines.push(line);
+                    }
+                    Ok(None) | Err(_) => {
+                        log::warn!(
+                            "Spool segment {} is corrupt at offset {}; skipping the rest of it",
+                            path.display(),
+                            position.offset
+                        );
+                        position.offset = size;
+                    }
+                }
+            }
+        }
+        Ok(Batch { lines, end: position })
+    }
+
+    /// Marks the records of `batch` delivered and removes segments that are
+    /// fully delivered
+    pub fn commit(&mut self, batch: &Batch) -> Result<()> {
+        // Dropping segments may have moved the cursor past the batch meanwhile
+        if batch.end <= self.cursor {
+            return Ok(());
+        }
+        self.cursor = batch.end;
+        self.pending = self.pending.saturating_sub(batch.lines.len() as u64);
+        self.save_cursor()?;
+        while self.segments.len() > 1 && self.segments[0].seq < self.cursor.segment {
+            if let Some(segment) = self.segments.pop_front() {
+                self.remove_segment(segment.seq);
+            }
+        }
+        Ok(())
+    }
+
+    fn start_segment(&mut self) -> Result<()> {
+        let seq = self.segments.back().map(|segment| segment.seq + 1).unwrap_or(1);
+        self.active = create_segment(&self.dir, seq)?;
+        self.segments.push_back(Segment { seq, size: 0 });
+        Ok(())
+    }
+
+    fn enforce_cap(&mut self) -> Result<()> {
+        let mut dropped = 0;
+        while self.size() > self.max_bytes && self.segments.len() > 1 {
+            let Some(oldest) = self.segments.pop_front() else { break };
+            if self.cursor.segment <= oldest.seq {
+                let from = Position {
+                    segment: oldest.seq,
+                    offset: if self.cursor.segment == oldest.seq { self.cursor.offset } else { 0 },
+                };
+                dropped += count_frames(&segment_path(&self.dir, oldest.seq), from.offset, oldest.size)?;
+                self.cursor = Position {
+                    segment: self.segments[0].seq,
+                    offset: 0,
+                };
+            }
+            self.remove_segment(oldest.seq);
+        }
+        if dropped == 0 {
+            return Ok(());
+        }
+        self.pending = self.pending.saturating_sub(dropped);
+        self.dropped += dropped;
+        log::warn!(
+            "Spool {} over its {} byte cap: dropped {} oldest undelivered record(s), {} in total",
+            self.dir.display(),
+            self.max_bytes,
+            dropped,
+            self.dropped
+        );
+        self.save_cursor()
+    }
+
+    fn remove_segment(&self, seq: u64) {
+        let path = segment_path(&self.dir, seq);
+        if let Err(e) = fs::remove_file(&path) {
+            log::warn!("Failed to remove spool segment {}: {}", path.display(), e);
+        }
+    }
+
+    fn load_cursor(&self) -> Cursor {
+        let path = self.dir.join(CURSOR_FILE);
+        match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|e| {
+                log::warn!("Spool cursor {} is unreadable ({}); resending from the oldest record", path.display(), e);
+                Cursor::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Cursor::default(),
+            Err(e) => {
+                log::warn!("Failed to read spool cursor {} ({}); resending from the oldest record", path.display(), e);
+                Cursor::default()
+            }
+        }
+    }
+
+    fn save_cursor(&self) -> Result<()> {
+        let cursor = Cursor {
+            position: self.cursor,
+            dropped: self.dropped,
+        };
+        let json = serde_json::to_vec(&cursor).context("Failed to serialize spool cursor")?;
+        write_atomic(&self.dir.join(CURSOR_FILE), &json)
+    }
+
+    /// A saved position that no longer matches the segments on disk restarts
+    /// from the oldest record
+    fn clamp(&self, position: Position) -> Position {
+        let oldest = Position {
+            segment: self.segments[0].seq,
+            offset: 0,
+        };
+        match self.segments.iter().find(|segment| segment.seq == position.segment) {
+            Some(segment) => Position {
+                segment: segment.seq,
+                offset: position.offset.min(segment.size),
+            },
+            None if position < oldest => oldest,
+            None => {
+                log::warn!(
+                    "Spool cursor points at missing segment {}; resending from the oldest record",
+                    position.segment
+                );
+                oldest
+            }
+        }
+    }
+
+    fn count_from(&self, position: Position) -> Result<u64> {
+        let mut count = 0;
+        for segment in self.segments.iter().filter(|segment| segment.seq >= position.segment) {
+            let offset = if segment.seq == position.segment { position.offset } else { 0 };
+            count += count_frames(&segment_path(&self.dir, segment.seq), offset, segment.size)?;
+        }
+        Ok(count)
+    }
+}
+
+/// Drains a spool to an HTTP collector on a background thread. Batches that
+/// cannot be delivered stay in the spool and are retried with the shipper's
+/// backoff until the collector is reachable; batches the collector rejects
+/// are logged and skipped. Dropping it stops the thread.
+pub struct SpoolSender {
+    spool: Arc<Mutex<Spool>>,
+    /// Wakes the thread after an append; dropping it stops the thread
+    wake: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SpoolSender {
+    pub fn start(spool: Spool, shipper: HttpShipper) -> Result<SpoolSender> {
+        let spool = Arc::new(Mutex::new(spool));
+        let (wake, woken) = mpsc::channel();
+        let drained = spool.clone();
+        let thread = std::thread::Builder::new()
+            .name("spool-sender".to_string())
+            .spawn(move || drain(&drained, &shipper, &woken))
+            .context("Failed to start spool sender thread")?;
+        Ok(SpoolSender {
+            spool,
+            wake: Some(wake),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn spool(&self) -> &Arc<Mutex<Spool>> {
+        &self.spool
+    }
+
+    /// Appends `lines` to the spool; they are durable once this returns `Ok`
+    pub fn enqueue(&self, lines: &[String]) -> Result<()> {
+        self.spool.lock().unwrap().append(lines)?;
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpoolSender {
+    /// Stops the thread and waits for it. The thread sends one request at a
+    /// time and backs off between them without holding anything up, so this
+    /// blocks for at most one request (`timeout_secs`), e.g. during a reload.
+    /// Anything not delivered stays in the spool for the next start.
+    fn drop(&mut self) {
+        self.wake.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn drain(spool: &Mutex<Spool>, shipper: &HttpShipper, woken: &mpsc::Receiver<()>) {
+    let mut failures = 0u32;
+    loop {
+        let batch = spool.lock().unwrap().read_batch(shipper.batch_size());
+        let batch = match batch {
+            Ok(batch) if batch.lines.is_empty() => {
+                // Nothing to send until the next append
+                if woken.recv().is_err() {
+                    return;
+                }
+                continue;
+            }
+            Ok(batch) => batch,
+            Err(e) => {
+                log::error!("Failed to read spool: {:#}", e);
+                if !wait(woken, shipper.backoff(failures.max(1))) {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        // One attempt at a time, so stopping never waits for the shipper's own retries
+        match shipper.send_once(&batch.lines) {
+            Ok(()) => {
+                if failures > 0 {
+                    log::info!("Collector {} reachable again, sending spooled records", shipper.url());
+                    failures = 0;
+                }
+            }
+            Err(e @ ShipError::Rejected { .. }) => log::error!("{}; dropping the batch from the spool", e),
+            Err(ShipError::Unavailable { reason, retry_after, .. }) => {
+                failures = failures.saturating_add(1);
+                let delay = retry_after.unwrap_or_else(|| shipper.backoff(failures));
+                let pending = spool.lock().unwrap().pending();
+                if failures == 1 {
+                    log::warn!(
+                        "Collector {} unavailable ({}); keeping {} record(s) in the spool until it is reachable",
+                        shipper.url(),
+                        reason,
+                        pending
+                    );
+                } else {
+                    log::debug!("Collector {} still unavailable ({}), {} record(s) spooled", shipper.url(), reason, pending);
+                }
+                if !wait(woken, delay) {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        if let Err(e) = spool.lock().unwrap().commit(&batch) {
+            log::error!("Failed to save spool position, records may be sent again: {:#}", e);
+        }
+    }
+}
+
+/// Sleeps for `delay`, ignoring wake-ups; false once the sender is stopped
+fn wait(woken: &mpsc::Receiver<()>, delay: Duration) -> bool {
+    let deadline = Instant::now() + delay;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match woken.recv_timeout(remaining) {
+            Ok(()) if !remaining.is_zero() => continue,
+            Ok(()) | Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", seq, SEGMENT_EXTENSION))
+}
+
+fn list_segments(dir: &Path) -> Result<VecDeque<Segment>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))? {
+        let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        let Some(seq) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) else {
+            continue;
+        };
+        let size = entry.metadata().with_context(|| format!("Failed to read {}", path.display()))?.len();
+        segments.push(Segment { seq, size });
+    }
+    segments.sort_by_key(|segment| segment.seq);
+    Ok(segments.into())
+}
+
+fn create_segment(dir: &Path, seq: u64) -> Result<File> {
+    let path = segment_path(dir, seq);
+    let file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to create spool segment {}", path.display()))?;
+    sync_dir(&path);
+    Ok(file)
+}
+
+/// Truncates `path` after its last intact frame and returns the new size
+fn repair_tail(path: &Path, size: u64) -> Result<u64> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut valid = 0;
+    while valid < size {
+        match read_frame(&mut reader, size - valid) {
+            Ok(Some(line)) => valid += FRAME_HEADER + line.len() as u64,
+            Ok(None) | Err(_) => break,
+        }
+    }
+    if valid < size {
+        log::warn!(
+            "Spool segment {} ends with {} byte(s) of a torn record; cutting them off",
+            path.display(),
+            size - valid
+        );
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        file.set_len(valid)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed to repair {}", path.display()))?;
+    }
+    Ok(valid)
+}
+
+/// Intact records in `path` from `offset` up to `size`
+fn count_frames(path: &Path, offset: u64, size: u64) -> Result<u64> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    file.seek(SeekFrom::Start(offset))
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut position = offset;
+    let mut count = 0;
+    while position < size {
+        match read_frame(&mut reader, size - position) {
+            Ok(Some(line)) => {
+                position += FRAME_HEADER + line.len() as u64;
+                count += 1;
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+    Ok(count)
+}
+
+/// Reads one frame of at most `available` bytes. `None` when the frame is
+/// cut short, fails its checksum or is not UTF-8.
+fn read_frame(reader: &mut impl Read, available: u64) -> std::io::Result<Option<String>> {
+    if available < FRAME_HEADER {
+        return Ok(None);
+    }
+    let mut header = [0u8; FRAME_HEADER as usize];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if len > available - FRAME_HEADER {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    if crc32fast::hash(&payload) != crc {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(payload).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpOutput;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-spool-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_and_commits_appended_records_in_order() {
+        let dir = scratch("round-trip");
+        let mut spool = Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).unwrap();
+        spool.append(&lines(&["a", "b", "c"])).unwrap();
+        assert_eq!(spool.pending(), 3);
+
+        let batch = spool.read_batch(2).unwrap();
+        assert_eq!(batch.lines, lines(&["a", "b"]));
+        // Reading does not move the cursor
+        assert_eq!(spool.read_batch(2).unwrap().lines, lines(&["a", "b"]));
+        spool.commit(&batch).unwrap();
+        assert_eq!(spool.pending(), 1);
+
+        spool.append(&lines(&["d"])).unwrap();
+        let batch = spool.read_batch(10).unwrap();
+        assert_eq!(batch.lines, lines(&["c", "d"]));
+        spool.commit(&batch).unwrap();
+        assert_eq!(spool.pending(), 0);
+        assert!(spool.read_batch(10).unwrap().lines.is_empty());
+        drop(spool);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn resumes_at_the_cursor_after_reopening() {
+        let dir = scratch("resume");
+        let mut spool = Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).unwrap();
+        spool.append(&lines(&["a", "b", "c"])).unwrap();
+        let batch = spool.read_batch(2).unwrap();
+        spool.commit(&batch).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).unwrap();
+        assert_eq!(spool.pending(), 1);
+        assert_eq!(spool.read_batch(10).unwrap().lines, lines(&["c"]));
+        drop(spool);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cuts_off_a_torn_or_corrupt_tail_on_reopen() {
+        let mut bad_crc = Vec::new();
+        bad_crc.extend_from_slice(&3u32.to_le_bytes());
+        bad_crc.extend_from_slice(&(crc32fast::hash(b"xyz") ^ 1).to_le_bytes());
+        bad_crc.extend_from_slice(b"xyz");
+        let mut torn = Vec::new();
+        torn.extend_from_slice(&100u32.to_le_bytes());
+        torn.extend_from_slice(&crc32fast::hash(b"x").to_le_bytes());
+        torn.extend_from_slice(b"partial");
+
+        for (name, tail) in [("torn", torn), ("crc", bad_crc)] {
+            let dir = scratch(name);
+            let mut spool = Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).unwrap();
+            spool.append(&lines(&["a", "b"])).unwrap();
+            let size = spool.size();
+            drop(spool);
+            let segment = segment_path(&dir, 1);
+            OpenOptions::new().append(true).open(&segment).unwrap().write_all(&tail).unwrap();
+
+            let mut spool = Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).unwrap();
+            assert_eq!(fs::metadata(&segment).unwrap().len(), size, "{}", name);
+            assert_eq!(spool.pending(), 2, "{}", name);
+            // Appends continue on a frame boundary
+            spool.append(&lines(&["c"])).unwrap();
+            assert_eq!(spool.read_batch(10).unwrap().lines, lines(&["a", "b", "c"]), "{}", name);
+            drop(spool);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn drops_the_oldest_segment_over_the_cap() {
+        let dir = scratch("cap");
+        // Segments roll over at 64 KiB, so six 10 kB records fill one
+        let record = "x".repeat(10_000);
+        let mut spool = Spool::open(&dir, 100_000).unwrap();
+        for i in 0..9 {
+            spool.append(&[format!("{}{}", i, record)]).unwrap();
+        }
+        assert_eq!((spool.pending(), spool.dropped()), (9, 0));
+
+        spool.append(&[format!("9{}", record)]).unwrap();
+        assert_eq!((spool.pending(), spool.dropped()), (4, 6));
+        assert!(!segment_path(&dir, 1).exists());
+        assert!(spool.read_batch(1).unwrap().lines[0].starts_with('6'));
+        drop(spool);
+
+        let spool = Spool::open(&dir, 100_000).unwrap();
+        assert_eq!((spool.pending(), spool.dropped()), (4, 6));
+        drop(spool);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refuses_a_directory_that_is_already_open() {
+        let dir = scratch("lock");
+        let spool = Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).unwrap();
+        let error = Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).err().unwrap();
+        assert!(error.to_string().contains("already in use"), "{}", error);
+
+        drop(spool);
+        assert!(Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stopping_the_sender_does_not_wait_for_retries() {
+        let dir = scratch("sender");
+        let config: HttpOutput = serde_json::from_value(serde_json::json!({
+            "url": "http://127.0.0.1:9/ingest",
+            "timeout_secs": 1,
+            "max_retries": 10,
+            "backoff_initial_ms": 5000,
+            "backoff_max_ms": 5000,
+        }))
+        .unwrap();
+        let sender = SpoolSender::start(Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).unwrap(), HttpShipper::new(config)).unwrap();
+        sender.enqueue(&lines(&["a"])).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+
+        let started = Instant::now();
+        drop(sender);
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+        // The undelivered record is still spooled
+        assert_eq!(Spool::open(&dir, DEFAULT_MAX_SPOOL_BYTES).unwrap().pending(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,280 @@
+// ============================================================================
+// Daemon State Store
+// ============================================================================
+//
+// Keeps what `agent-daemon` needs across restarts in one JSON file under the
+// state directory: the cycle counter, the last snapshot, the differential
+// cursor of every query, outbound records that were not delivered yet, and
+// the remote server's node key and last scheduled query results. Saves write
+// a temporary file, sync it and rename it over the old one, so a crash leaves
+// either the previous or the new state on disk, never a torn file.
+//
+// A cursor's rows are usually the last snapshot's rows of its section. Those
+// are written once, in the snapshot, and the cursor only records that they
+// live there.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::diff::keyed_rows;
+use crate::events::QueryCursor;
+use crate::models::Section;
+use crate::remote::QueryResults;
+use crate::snapshot::Snapshot;
+
+/// Version of the state file layout
+pub const STATE_SCHEMA_VERSION: u32 = 1;
+
+/// Default cap on the size of the state file
+pub const DEFAULT_MAX_STATE_BYTES: u64 = 64 * 1024 * 1024;
+
+const STATE_FILE: &str = "state.json";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AgentState {
+    pub schema_version: u32,
+    /// Collection cycles run so far
+    pub cycle: u64,
+    /// Unix time in milliseconds of the last save
+    pub saved_at_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_snapshot: Option<Snapshot>,
+    /// Differential position of each query, keyed by query name
+    #[serde(default)]
+    pub cursors: BTreeMap<String, QueryCursor>,
+    /// Outbound records not delivered yet, oldest first
+    #[serde(default)]
+    pub pending: VecDeque<Value>,
+    /// Pending records dropped to keep the state under its size cap
+    #[serde(default)]
+    pub pending_dropped: u64,
+    /// Node key from enrolling with the remote server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_key: Option<String>,
+    /// Last run of each of the remote server's scheduled queries, by query name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub schedule_results: BTreeMap<String, QueryResults>,
+}
+
+impl Default for AgentState {
+    fn default() -> Self {
+        AgentState {
+            schema_version: STATE_SCHEMA_VERSION,
+            cycle: 0,
+            saved_at_ms: 0,
+            last_snapshot: None,
+            cursors: BTreeMap::new(),
+            pending: VecDeque::new(),
+            pending_dropped: 0,
+            node_key: None,
+            schedule_results: BTreeMap::new(),
+        }
+    }
+}
+
+impl AgentState {
+    /// Takes out the cursor rows that the last snapshot already holds, marking
+    /// their cursors; `restore_cursor_rows()` puts them back after saving
+    fn share_cursor_rows(&mut self) -> Vec<(String, BTreeMap<String, Value>)> {
+        let Some(snapshot) = &self.last_snapshot else { return Vec::new() };
+        let mut shared = Vec::new();
+        for (name, cursor) in self.cursors.iter_mut() {
+            let Ok(section) = name.parse::<Section>() else { continue };
+            if !cursor.rows.is_empty() && cursor.rows == keyed_rows(&snapshot.system_info, section) {
+                cursor.rows_in_snapshot = true;
+                shared.push((name.clone(), std::mem::take(&mut cursor.rows)));
+            }
+        }
+        shared
+    }
+
+    fn restore_cursor_rows(&mut self, shared: Vec<(String, BTreeMap<String, Value>)>) {
+        for (name, rows) in shared {
+            if let Some(cursor) = self.cursors.get_mut(&name) {
+                cursor.rows = rows;
+                cursor.rows_in_snapshot = false;
+            }
+        }
+    }
+
+    /// Fills the rows of cursors saved with `rows_in_snapshot` from the last snapshot
+    fn load_cursor_rows(&mut self) {
+        for (name, cursor) in self.cursors.iter_mut().filter(|(_, cursor)| cursor.rows_in_snapshot) {
+            cursor.rows_in_snapshot = false;
+            match (&self.last_snapshot, name.parse::<Section>()) {
+                (Some(snapshot), Ok(section)) => cursor.rows = keyed_rows(&snapshot.system_info, section),
+                _ => log::warn!("Saved cursor {} refers to rows of a snapshot that was not kept", name),
+            }
+        }
+    }
+
+    /// The state as written to disk, with cursor rows stored once
+    fn serialize_for_disk(&mut self) -> Result<Vec<u8>> {
+        let shared = self.share_cursor_rows();
+        let json = serde_json::to_vec(self).context("Failed to serialize state");
+        self.restore_cursor_rows(shared);
+        json
+    }
+}
+
+/// The state file of one daemon instance
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl StateStore {
+    /// Uses `dir/state.json`, creating `dir` if needed. Saves larger than
+    /// `max_bytes` shed pending records, then the last snapshot.
+    pub fn open(dir: impl AsRef<Path>, max_bytes: u64) -> Result<StateStore> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create state directory {}", dir.display()))?;
+        Ok(StateStore {
+            path: dir.join(STATE_FILE),
+            max_bytes,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Reads the saved state. A missing file is a fresh start; an unreadable
+    /// one is moved aside to `state.json.corrupt` so the daemon can still run.
+    pub fn load(&self) -> Result<AgentState> {
+        let json = match fs::read_to_string(&self.path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(AgentState::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.path.display())),
+        };
+
+        #[derive(Deserialize)]
+        struct Version {
+            schema_version: u32,
+        }
+        if let Ok(version) = serde_json::from_str::<Version>(&json) {
+            if version.schema_version > STATE_SCHEMA_VERSION {
+                bail!(
+                    "State file {} has schema version {}, newer than supported version {}",
+                    self.path.display(),
+                    version.schema_version,
+                    STATE_SCHEMA_VERSION
+                );
+            }
+        }
+
+        match serde_json::from_str::<AgentState>(&json) {
+            Ok(mut state) => {
+                state.load_cursor_rows();
+                Ok(state)
+            }
+            Err(e) => {
+                let corrupt = self.path.with_extension("json.corrupt");
+                log::warn!(
+                    "State file {} is unreadable ({}); moving it to {} and starting fresh",
+                    self.path.display(),
+                    e,
+                    corrupt.display()
+                );
+                fs::rename(&self.path, &corrupt)
+                    .with_context(|| format!("Failed to move aside {}", self.path.display()))?;
+                Ok(AgentState::default())
+            }
+        }
+    }
+
+    /// Writes `state` atomically and returns the size written. Oldest pending
+    /// records, then the last snapshot, are dropped from `state` when it does
+    /// not fit under the cap; a state that still does not fit is not written.
+    pub fn save(&self, state: &mut AgentState) -> Result<u64> {
+        state.schema_version = STATE_SCHEMA_VERSION;
+        state.saved_at_ms = now_ms();
+        let mut json = state.serialize_for_disk()?;
+
+        if json.len() as u64 > self.max_bytes && !state.pending.is_empty() {
+            let mut excess = json.len() as u64 - self.max_bytes;
+            let mut dropped = 0u64;
+            while excess > 0 {
+                let Some(record) = state.pending.pop_front() else { break };
+                // The record plus its separating comma
+                let size = serde_json::to_vec(&record).map(|r| r.len() as u64 + 1).unwrap_or(1);
+                excess = excess.saturating_sub(size);
+                dropped += 1;
+            }
+            state.pending_dropped += dropped;
+            log::warn!(
+                "State over its {} byte cap: dropped {} oldest pending record(s), {} in total",
+                self.max_bytes,
+                dropped,
+                state.pending_dropped
+            );
+            json = state.serialize_for_disk()?;
+        }
+
+        if json.len() as u64 > self.max_bytes && state.last_snapshot.is_some() {
+            log::warn!("State over its {} byte cap: not keeping the last snapshot", self.max_bytes);
+            state.last_snapshot = None;
+            json = state.serialize_for_disk()?;
+        }
+
+        if json.len() as u64 > self.max_bytes {
+            bail!(
+                "State is {} bytes, over its {} byte cap; not saved",
+                json.len(),
+                self.max_bytes
+            );
+        }
+
+        write_atomic(&self.path, &json)?;
+        Ok(json.len() as u64)
+    }
+}
+
+/// Replaces `path` with `data` through a synced temporary file and a rename
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+
+    let mut file = File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(data)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    drop(file);
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    sync_dir(path);
+    Ok(())
+}
+
+/// Makes a rename or removal in `path`'s directory durable. Directories
+/// cannot be opened for syncing on Windows, where this does nothing.
+pub fn sync_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Err(e) = File::open(dir).and_then(|d| d.sync_all()) {
+            log::debug!("Failed to sync directory {}: {}", dir.display(), e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,159 @@
+// Replays the recorded per-platform fixtures through the agents, so the
+// parsing of every section is covered without osquery installed.
+
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use security_agent::agent::{agent_for_platform, Platform};
+use security_agent::fixture::ReplayBackend;
+use security_agent::models::{ScheduledTaskDetails, Section, SectionState, SystemInfo};
+use security_agent::osquery::{OsqueryError, QueryBackend};
+
+/// The Linux fixtures as seen on a host without systemd
+struct WithoutSystemd(ReplayBackend);
+
+impl QueryBackend for WithoutSystemd {
+    fn execute(&self, query: &str) -> Result<Vec<Value>, OsqueryError> {
+        if query.contains("FROM startup_items") {
+            return Ok(vec![
+                json!({"name": "cron", "path": "/etc/init.d/cron", "status": "enabled"}),
+                json!({"name": "sshd", "path": "/etc/init.d/sshd", "status": "enabled"}),
+            ]);
+        }
+        let mut rows = self.0.execute(query)?;
+        if query.contains("FROM osquery_registry") {
+            rows.retain(|row| row["name"] != "systemd_units");
+        }
+        Ok(rows)
+    }
+}
+
+fn collect(platform: Platform, dir: &str) -> SystemInfo {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(dir);
+    let backend = ReplayBackend::load(&dir).unwrap();
+    agent_for_platform(platform, Box::new(backend)).collect_system_info()
+}
+
+/// Asserts each section's state and row count, and that nothing was dropped
+fn assert_sections(info: &SystemInfo, expected: &[(Section, SectionState, usize)]) {
+    assert_eq!(info.collection_status.len(), Section::ALL.len());
+    for &(section, state, rows) in expected {
+        let status = info.status(section).unwrap();
+        assert_eq!(status.state, state, "{}: {:?}", section, status);
+        assert_eq!(status.rows, rows, "{}: {:?}", section, status);
+        assert_eq!(status.rows_skipped, 0, "{}: {:?}", section, status);
+        assert_eq!(status.error, None, "{}", section);
+    }
+}
+
+#[test]
+fn linux_fixtures() {
+    let info = collect(Platform::Linux, "linux");
+    assert_sections(
+        &info,
+        &[
+            (Section::OsVersion, SectionState::Ok, 1),
+            (Section::SystemInfo, SectionState::Ok, 1),
+            (Section::Processes, SectionState::Ok, 5),
+            (Section::NetworkConnections, SectionState::Ok, 3),
+            (Section::ListeningPorts, SectionState::Ok, 4),
+            (Section::Users, SectionState::Ok, 3),
+            (Section::Services, SectionState::Ok, 4),
+            (Section::ScheduledTasks, SectionState::Ok, 6),
+            (Section::InstalledPackages, SectionState::Ok, 4),
+            (Section::InterfaceAddresses, SectionState::Ok, 4),
+        ],
+    );
+
+    // The recorded osquery build has neither portage_packages nor pkg_packages
+    let packages = info.status(Section::InstalledPackages).unwrap();
+    assert_eq!(packages.unsupported_tables, vec!["portage_packages", "pkg_packages"]);
+    assert_eq!(info.installed_packages.len(), 4);
+    assert_eq!(info.scheduled_tasks.len(), 6);
+}
+
+#[test]
+fn windows_fixtures() {
+    let info = collect(Platform::Windows, "windows");
+    assert_sections(
+        &info,
+        &[
+            (Section::OsVersion, SectionState::Ok, 1),
+            (Section::SystemInfo, SectionState::Ok, 1),
+            (Section::Processes, SectionState::Ok, 4),
+            (Section::NetworkConnections, SectionState::Ok, 2),
+            (Section::ListeningPorts, SectionState::Ok, 3),
+            (Section::Users, SectionState::Ok, 3),
+            (Section::Services, SectionState::Ok, 3),
+            (Section::ScheduledTasks, SectionState::Ok, 3),
+            (Section::InstalledPackages, SectionState::Ok, 3),
+            (Section::InterfaceAddresses, SectionState::Ok, 2),
+        ],
+    );
+    assert!(info.collection_status.values().all(|status| status.unsupported_tables.is_empty()));
+}
+
+#[test]
+fn macos_fixtures() {
+    let info = collect(Platform::MacOs, "macos");
+    assert_sections(
+        &info,
+        &[
+            (Section::OsVersion, SectionState::Ok, 1),
+            (Section::SystemInfo, SectionState::Ok, 1),
+            (Section::Processes, SectionState::Ok, 3),
+            (Section::NetworkConnections, SectionState::Ok, 1),
+            (Section::ListeningPorts, SectionState::Ok, 2),
+            (Section::Users, SectionState::Ok, 2),
+            (Section::Services, SectionState::Ok, 4),
+            (Section::ScheduledTasks, SectionState::Ok, 3),
+            (Section::InstalledPackages, SectionState::Ok, 3),
+            (Section::InterfaceAddresses, SectionState::Ok, 2),
+        ],
+    );
+}
+
+#[test]
+fn missing_fixture_fails_its_section() {
+    let dir = std::env::temp_dir().join(format!("agent-fixtures-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/linux");
+    for entry in std::fs::read_dir(&source).unwrap() {
+        let path = entry.unwrap().path();
+        if path.file_name().unwrap() != "select_from_users.json" {
+            std::fs::copy(&path, dir.join(path.file_name().unwrap())).unwrap();
+        }
+    }
+
+    let backend = ReplayBackend::load(&dir).unwrap();
+    let info = agent_for_platform(Platform::Linux, Box::new(backend)).collect_system_info();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let users = info.status(Section::Users).unwrap();
+    assert_eq!(users.state, SectionState::Failed);
+    assert!(users.error.as_deref().unwrap().contains("users"), "{:?}", users);
+    assert!(info.users.is_empty());
+    assert_eq!(info.status(Section::Processes).unwrap().state, SectionState::Ok);
+}
+
+#[test]
+fn substitutes_only_the_services_query_without_systemd() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/linux");
+    let backend = WithoutSystemd(ReplayBackend::load(&dir).unwrap());
+    let info = agent_for_platform(Platform::Linux, Box::new(backend)).collect_system_info();
+
+    // Services come from startup_items instead
+    let services = info.status(Section::Services).unwrap();
+    assert_eq!((services.state, services.rows), (SectionState::Ok, 2));
+    assert!(services.unsupported_tables.is_empty());
+
+    // Timers are skipped, not filled with startup items
+    let tasks = info.status(Section::ScheduledTasks).unwrap();
+    assert_eq!((tasks.state, tasks.rows), (SectionState::Ok, 4));
+    assert_eq!(tasks.unsupported_tables, vec!["systemd_units"]);
+    assert!(info
+        .scheduled_tasks
+        .iter()
+        .all(|task| matches!(task.details, ScheduledTaskDetails::Cron(_))));
+}
@@ -0,0 +1,65 @@
+// Saves and reloads daemon state built from the Linux fixtures.
+
+use std::path::Path;
+
+use security_agent::agent::{agent_for_platform, CollectOptions, Platform};
+use security_agent::events::EventStream;
+use security_agent::fixture::ReplayBackend;
+use security_agent::snapshot::Snapshot;
+use security_agent::state::{AgentState, StateStore, DEFAULT_MAX_STATE_BYTES};
+
+fn snapshot() -> Snapshot {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/linux");
+    let agent = agent_for_platform(Platform::Linux, Box::new(ReplayBackend::load(&dir).unwrap()));
+    Snapshot::collect(agent.as_ref(), &CollectOptions::default())
+}
+
+#[test]
+fn cursor_rows_are_saved_once_and_restored() {
+    let snapshot = snapshot();
+    let mut stream = EventStream::new(10);
+    stream.process(&snapshot);
+
+    let mut state = AgentState {
+        cursors: stream.cursors(),
+        last_snapshot: Some(snapshot.clone()),
+        ..AgentState::default()
+    };
+    let expected = state.cursors.clone();
+
+    let dir = std::env::temp_dir().join(format!("agent-state-{}", std::process::id()));
+    let store = StateStore::open(&dir, DEFAULT_MAX_STATE_BYTES).unwrap();
+    store.save(&mut state).unwrap();
+    assert_eq!(state.cursors, expected, "saving must leave the in-memory cursors alone");
+
+    let json = std::fs::read_to_string(store.path()).unwrap();
+    assert_eq!(json.matches("\"/usr/sbin/sshd\"").count(), 1, "{}", json);
+
+    let loaded = store.load().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(loaded.cursors, expected);
+}
+
+#[test]
+fn cursor_rows_stay_inline_without_a_matching_snapshot() {
+    let snapshot = snapshot();
+    let mut stream = EventStream::new(10);
+    stream.process(&snapshot);
+
+    // A cursor that no longer matches the snapshot keeps its own rows
+    let mut cursors = stream.cursors();
+    cursors.get_mut("users").unwrap().rows.clear();
+    let mut state = AgentState {
+        cursors,
+        last_snapshot: None,
+        ..AgentState::default()
+    };
+    let expected = state.cursors.clone();
+
+    let dir = std::env::temp_dir().join(format!("agent-state-inline-{}", std::process::id()));
+    let store = StateStore::open(&dir, DEFAULT_MAX_STATE_BYTES).unwrap();
+    store.save(&mut state).unwrap();
+    let loaded = store.load().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(loaded.cursors, expected);
+}